@@ -0,0 +1,52 @@
+//! Benchmark of a long editing session on a large file, which only reports how long
+//! it took. Run with `cargo bench --bench large_file`
+// Only the piece table is used, and the modules' tests are left out of benchmarks
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src/modules/mod.rs"]
+mod modules;
+
+use std::cmp::{max, min};
+use std::time::{Duration, Instant};
+
+use modules::piece_table::PieceTable;
+
+fn main() {
+    let mut piece_table = PieceTable::new();
+    let line = "All work and no play makes Jack a dull boy. All work and no play makes Jack a dull boy!\n";
+    let start = Instant::now();
+    let mut chunk = String::new();
+    while chunk.len() < 1024 * 1024 {
+        chunk.push_str(line);
+    }
+    while piece_table.text_len() < 100 * 1024 * 1024 {
+        piece_table.update_original_buffer(chunk.clone());
+    }
+    println!("Loaded {} bytes in {:?}", piece_table.text_len(), start.elapsed());
+
+    let num_edits = 100_000;
+    let mut seed: usize = 1;
+    let start = Instant::now();
+    let mut slowest = Duration::default();
+    for i in 0..num_edits {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        let index = (seed >> 16) % piece_table.text_len();
+        let edit_start = Instant::now();
+        if i % 2 == 0 {
+            piece_table.add_text("x\n".to_string(), index);
+        } else {
+            let end = min(index + 1 + (seed >> 8) % 16, piece_table.text_len());
+            piece_table.delete_text(index, end);
+        }
+        slowest = max(slowest, edit_start.elapsed());
+    }
+    let elapsed = start.elapsed();
+    println!("{} edits in {:?} ({:?} per edit, slowest {:?}), {} lines",
+        num_edits, elapsed, elapsed / num_edits as u32, slowest, piece_table.line_count());
+
+    let start = Instant::now();
+    for _ in 0..500 {
+        piece_table.undo().unwrap();
+    }
+    println!("500 undos in {:?}", start.elapsed());
+}
@@ -3,13 +3,20 @@
 // #![warn(unused_mut)]
 
 use std::env;
+use std::process;
 
 mod modules;
 
 use modules::via::Via;
 
 fn main() {
-    let (mut file_paths, options) = Via::process_args(env::args().collect());
+    let (mut file_paths, options) = match Via::process_args(env::args().collect()) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("via: {}", err);
+            process::exit(1);
+        },
+    };
     if file_paths.is_empty() {
         file_paths.push(String::new());
     }
@@ -1,9 +1,26 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
 use std::cmp::{min, max};
-use std::path::Path;
-
-use super::piece_table::PieceTable;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use super::original_buffer::OriginalBuffer;
+use super::piece_table::{Chunks, PieceTable};
+use super::regex::{Captures, Regex};
+use super::save;
+use super::swap_file::{self, SwapFile};
+use super::undo_file;
+
+/// Number of lines to read from the file at least, when searching forward past the
+/// lines read so far
+const SEARCH_LINES: usize = 1000;
+/// Number of bytes to search at a time (rounded to whole lines) when searching backward
+const SEARCH_BYTES: usize = 64 * 1024;
 
 /// An editor window
 pub(crate) struct Editor {
@@ -14,59 +31,267 @@ pub(crate) struct Editor {
     /// Path of file being editing (may not yet exist). 
     /// Empty string if no file specified
     file_path: String,
-    /// Reader of the file (Error if is an nonexistent file)
-    reader: Result<BufReader<File>, String>,
-    /// Whether we have read all of `self.reader`
+    /// Whether all of the file has been loaded into `self.piece_table`
     eof_reached: bool,
-    /// Represents each line of the editor, and how many characters are in that line
-    lines: Vec<usize>,
     /// Row cursor is at (1-indexed)
     row: usize,
-    /// Column cursor is at (1-indexed)
+    /// Column cursor is at (1-indexed), counted in grapheme clusters
     col: usize,
     /// Column cursor we want (1-indexed). When we move vertically, from a long 
     /// line to short one, we want to try to get to a specific column
     col_want: usize,
+    /// Positions (row, col) marked by name, such as `<` and `>` for the last visual selection
+    marks: HashMap<char, (usize, usize)>,
+    /// Why the file couldn't be (completely) read. The text isn't written back then,
+    /// since that would lose what wasn't read
+    error: Option<String>,
+    /// Whether to keep a copy of the file as it was before it is written, as `file~`
+    backup: bool,
+    /// Swap file the changes to the text are recorded in, if there is one
+    swap: Option<SwapFile>,
+    /// Directory the undo and swap files are kept in, if there is one
+    state_dir: Option<PathBuf>,
 }
 
 impl Editor {
     /// Initialize a new editor from a file path (read a single line)
     pub(crate) fn new(file_path: String) -> Editor {
-        // let (reader, eof_reached) = create_reader(file_path);
-        let reader;
-        let eof_reached;
-        if file_path == "" {
-            reader = Err("No file specified".to_string());
-            eof_reached = true;
-        } else if Path::new(&file_path).is_file() {
-            reader = Ok(BufReader::new(File::open(file_path.clone()).unwrap()));
-            eof_reached = false;
-        } else if Path::new(&file_path).is_dir() || file_path.ends_with("/") {
-            panic!("No support (yet) for writing to directories");
-        } else {
-            reader = Err("File doesn't exist".to_string());
-            eof_reached = true;
-        }
+        let is_file = !file_path.is_empty() && Path::new(&file_path).is_file();
+        let is_dir = !is_file && (Path::new(&file_path).is_dir() || file_path.ends_with('/'));
         let mut editor = Editor {piece_table: PieceTable::new(),
             pt_index: 0,
-            file_path: file_path,
-            reader: reader,
-            eof_reached: eof_reached,
-            lines: Vec::new(),
+            file_path,
+            eof_reached: true,
             row: 1,
             col: 1,
             col_want: 1,
+            marks: HashMap::new(),
+            error: None,
+            backup: false,
+            swap: None,
+            state_dir: undo_file::state_dir(),
         };
-        if editor.read_lines(1) == 0 {
-            editor.lines.push(0);
-        } else {
-            editor.lines.push(editor.piece_table.text_len());
+        if is_dir {
+            editor.error = Some(format!("\"{}\" is a directory", editor.file_path));
+        } else if is_file && editor.read_undo_file().is_err() {
+            match OriginalBuffer::open(&editor.file_path) {
+                Ok(original_buffer) => {
+                    editor.piece_table = PieceTable::from_original(original_buffer);
+                    editor.eof_reached = false;
+                },
+                Err(e) => editor.error = Some(format!("Error reading file: {}", e)),
+            }
         }
+        editor.read_lines(1);
         editor
     }
 
-    /// Returns visible text
-    pub(crate) fn text(&mut self) -> &str {
+    /// Returns where the undo file of `self.file_path` is stored
+    fn undo_path(&self) -> Option<PathBuf> {
+        undo_file::path(self.state_dir.as_ref()?, &self.file_path)
+    }
+
+    /// Returns where the swap file of `self.file_path` is stored
+    fn swap_path(&self) -> Option<PathBuf> {
+        swap_file::path(self.state_dir.as_ref()?, &self.file_path)
+    }
+
+    /// Restore the piece table and its undo history from the undo file of `self.file_path`
+    fn read_undo_file(&mut self) -> Result<(), String> {
+        let path = self.undo_path().ok_or("Unable to find undo file")?;
+        if !path.is_file() {
+            return Err("No undo file".to_string());
+        }
+        let original_buffer = OriginalBuffer::open(&self.file_path)?;
+        self.piece_table = undo_file::read(&path, swap_file::stamp(&self.file_path), original_buffer)?;
+        // The undo file is written with the file, so the text it was at is in the file
+        self.piece_table.mark_saved();
+        self.eof_reached = true;
+        Ok(())
+    }
+
+    /// Save the undo history to the undo file of `self.file_path`.
+    /// Should be called right after the text has been written to the file
+    pub(crate) fn write_undo_file(&self) -> Result<(), String> {
+        if self.file_path.is_empty() {
+            return Err("No file name".to_string());
+        }
+        let path = self.undo_path().ok_or("Unable to find undo file")?;
+        undo_file::write(&path, swap_file::stamp(&self.file_path), &self.piece_table)
+    }
+
+    /// Write the text to `self.file_path`, returning the number of bytes written
+    pub(crate) fn write_file(&mut self) -> Result<usize, String> {
+        if self.file_path.is_empty() {
+            return Err("No file name".to_string());
+        }
+        let path = self.file_path.clone();
+        let len = self.write_text(&path, None, false)?;
+        self.piece_table.mark_saved();
+        // The swap file only has to have the changes made after this
+        let _ = self.reset_swap();
+        Ok(len)
+    }
+
+    /// Write the `rows` (first and last), or all of the text if `None`, to `path`, after
+    /// what is in it already if `append`, returning the number of bytes written. Otherwise
+    /// the file is replaced rather than overwritten (see `save::save`), which also keeps
+    /// the original buffer valid if it is a memory map of it
+    pub(crate) fn write_rows(&mut self, path: &str, rows: Option<(usize, usize)>, append: bool) -> Result<usize, String> {
+        self.write_text(path, rows, append)
+    }
+
+    /// Write the `rows` to `path`, like `write_rows`. All of the text is written a piece
+    /// at a time rather than copied first, with the rest of the file which hasn't been
+    /// loaded yet after it
+    fn write_text(&mut self, path: &str, rows: Option<(usize, usize)>, append: bool) -> Result<usize, String> {
+        if let Some(error) = &self.error {
+            return Err(format!("Not writing, since the file wasn't read: {}", error));
+        }
+        let text = rows.map(|(first, last)| self.rows_text(first, last));
+        let piece_table = &self.piece_table;
+        let mut len = 0;
+        let mut write = |file: &mut File| {
+            let mut writer = BufWriter::new(file);
+            len = match &text {
+                Some(text) => writer.write_all(text.as_bytes()).map(|_| text.len())?,
+                None => piece_table.write_to(&mut writer)?,
+            };
+            writer.flush()
+        };
+        if append {
+            let mut file = OpenOptions::new().append(true).create(true).open(path).map_err(|e| e.to_string())?;
+            write(&mut file).map_err(|e| e.to_string())?;
+        } else {
+            save::save(path, self.backup, None, write)?;
+        }
+        Ok(len)
+    }
+
+    /// Returns the number of lines, like `last_line`, but without loading the rest of the file
+    pub(crate) fn count_lines(&self) -> usize {
+        let unloaded = self.piece_table.unloaded_original();
+        let newlines = self.num_lines() - 1 + unloaded.iter().filter(|&&byte| byte == b'\n').count();
+        let ends_with_newline = match unloaded.last() {
+            Some(byte) => *byte == b'\n',
+            None => self.line_start(self.num_lines()) == self.piece_table.text_len(),
+        };
+        max(if ends_with_newline {newlines} else {newlines + 1}, 1)
+    }
+
+    /// Returns the pid of the via which the swap file of `self.file_path` is from,
+    /// if there is one
+    pub(crate) fn find_swap(&self) -> Option<u32> {
+        if self.file_path.is_empty() {
+            return None
+        }
+        let path = self.swap_path().filter(|path| path.is_file())?;
+        // A swap file that can't be read is still in the way
+        Some(swap_file::read(&path).map_or(0, |swap| swap.pid))
+    }
+
+    /// Start recording the changes to the text in the swap file of `self.file_path`,
+    /// replacing any swap file there is. The text has to be what is in the file
+    pub(crate) fn open_swap(&mut self) -> Result<(), String> {
+        if self.file_path.is_empty() || self.error.is_some() {
+            return Ok(())
+        }
+        let path = self.swap_path().ok_or("Unable to find swap file")?;
+        let mut swap = SwapFile::create(path)?;
+        swap.reset(swap_file::stamp(&self.file_path))?;
+        self.swap = Some(swap);
+        self.piece_table.start_journal();
+        Ok(())
+    }
+
+    /// Start the swap file over from the file as it is now, if the text was just written
+    /// to it, moving the swap file along if the text was written to another file. Until
+    /// then, the changes keep being recorded for the file the text was last read from or
+    /// written to, since they can't be recovered from any other
+    fn reset_swap(&mut self) -> Result<(), String> {
+        if self.swap.is_none() || !self.piece_table.is_saved() {
+            return Ok(())
+        }
+        self.piece_table.take_journal();
+        let path = self.swap_path().ok_or("Unable to find swap file")?;
+        if self.swap.as_ref().unwrap().path() != path {
+            let old_swap = self.swap.replace(SwapFile::create(path)?);
+            let _ = old_swap.unwrap().remove();
+        }
+        self.swap.as_mut().unwrap().reset(swap_file::stamp(&self.file_path))
+    }
+
+    /// Write the changes made since the last call to the swap file, if there is one
+    pub(crate) fn sync_swap(&mut self) -> Result<(), String> {
+        match &mut self.swap {
+            Some(swap) => swap.append(&self.piece_table.take_journal()),
+            None => Ok(()),
+        }
+    }
+
+    /// Stop recording the changes to the text, removing the swap file
+    pub(crate) fn close_swap(&mut self) {
+        if let Some(swap) = self.swap.take() {
+            let _ = swap.remove();
+        }
+        self.piece_table.stop_journal();
+    }
+
+    /// Make the changes in the swap file of `self.file_path` to the text, as one undoable
+    /// action, and if `open_swap`, record them in a new swap file replacing it. That
+    /// isn't done while the via which wrote it is still running, since it still writes
+    /// to it. Returns the number of changes
+    pub(crate) fn recover(&mut self, open_swap: bool) -> Result<usize, String> {
+        if let Some(error) = &self.error {
+            return Err(error.clone())
+        }
+        let path = self.swap_path().filter(|path| !self.file_path.is_empty() && path.is_file())
+            .ok_or_else(|| format!("E305: No swap file found for {}", self.file_path))?;
+        let swap = swap_file::read(&path)?;
+        if swap.stamp != swap_file::stamp(&self.file_path) {
+            return Err(format!("\"{}\" changed after the swap file was written, so it can't be recovered", self.file_path))
+        }
+        self.begin_group();
+        let recovered = swap.changes.iter().try_for_each(|change| self.recover_change(change));
+        // Changes made before one which doesn't fit are taken back
+        if self.piece_table.commit_group() > 0 && recovered.is_err() {
+            self.undo()?;
+        }
+        recovered?;
+        if open_swap {
+            self.open_swap()?;
+            // The new swap file is for the file as it is, so it has the changes made to it
+            if let Some(swap_file) = &mut self.swap {
+                for change in &swap.changes {
+                    swap_file.append_change(change.index, change.removed, &change.inserted)?;
+                }
+            }
+        }
+        self.sync_swap()?;
+        Ok(swap.changes.len())
+    }
+
+    /// Make a change read from a swap file to the text, loading as much of the file
+    /// as it needs. Errors if it doesn't fit in the text
+    fn recover_change(&mut self, change: &swap_file::Change) -> Result<(), String> {
+        let end = change.index.checked_add(change.removed).ok_or(swap_file::DAMAGED)?;
+        while self.piece_table.text_len() < end && self.read_line() {}
+        if end > self.piece_table.text_len() || !self.piece_table.is_char_boundary(change.index) || !self.piece_table.is_char_boundary(end) {
+            return Err(swap_file::DAMAGED.to_string())
+        }
+        self.replace_text(change.index..end, change.inserted.clone());
+        Ok(())
+    }
+
+    /// Returns the text of the rows `first` to `last` (inclusive), with the newline
+    /// ending the last one, if there is one
+    pub(crate) fn rows_text(&mut self, first: usize, last: usize) -> String {
+        let end = if self.has_line(last + 1) {self.line_start(last + 1)} else {self.piece_table.text_len()};
+        self.text_between(self.line_start(first)..end)
+    }
+
+    /// Returns a copy of the visible text
+    pub(crate) fn text(&self) -> String {
         self.piece_table.text()
     }
 
@@ -75,7 +300,22 @@ impl Editor {
         self.file_path.as_str()
     }
 
-    /// Update the file path
+    /// Set whether to keep a copy of the file as it was before it is written
+    pub(crate) fn set_backup(&mut self, backup: bool) {
+        self.backup = backup;
+    }
+
+    pub(crate) fn backup(&self) -> bool {
+        self.backup
+    }
+
+    /// Returns why the file couldn't be (completely) read, if it couldn't
+    pub(crate) fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// Update the file path. The swap file, if there is one, moves along once the
+    /// text is written to the file (see `reset_swap`)
     pub(crate) fn update_file_path(&mut self, file_path: String) {
         self.file_path = file_path;
     }
@@ -90,153 +330,133 @@ impl Editor {
         self.col
     }
 
+    /// Returns the index of the cursor in the text
+    pub(crate) fn index(&self) -> usize {
+        self.pt_index
+    }
+
+    /// Returns the column on screen the cursor is at (1-indexed), taking into
+    /// account characters which are wider or narrower than one column
+    pub(crate) fn display_col(&self) -> usize {
+        self.piece_table.slice(self.line_start(self.row)..self.pt_index).to_string().width() + 1
+    }
+
+    /// Returns the column in `row` (1-indexed) which is shown at column
+    /// `display_col` on screen (1-indexed)
+    pub(crate) fn display_col_to_col(&self, row: usize, display_col: usize) -> usize {
+        let mut width = 0;
+        let graphemes_before = self.line_text(row).graphemes(true).take_while(|grapheme| {
+            width += grapheme.width();
+            width < display_col
+        }).count();
+        graphemes_before + 1
+    }
+
     /// Returns the number of columns in the specified `row` (1-indexed)
     pub(crate) fn num_cols(&self, row: usize) -> usize {
-        *self.lines.get(row - 1).unwrap()
+        self.line_len(row)
     }
 
     /// Returns the number of lines
     pub(crate) fn num_lines(&self) -> usize {
-        self.lines.len()
+        self.piece_table.line_count()
     }
 
-    /// Returns the length of the line (1-indexed)
+    /// Returns the length of the line (1-indexed) in grapheme clusters
     pub(crate) fn line_len(&self, line: usize) -> usize {
-        *self.lines.get(line - 1).unwrap()
+        self.line_text(line).graphemes(true).count()
+    }
+
+    /// Returns the text of the line (1-indexed), not including the newline
+    pub(crate) fn line_text(&self, line: usize) -> String {
+        self.text_line(line).to_string()
+    }
+
+    /// Returns the index in `self.piece_table` the line (1-indexed) starts at
+    pub(crate) fn line_start(&self, line: usize) -> usize {
+        self.piece_table.line_to_offset(line - 1).unwrap()
+    }
+
+    /// Returns the index in `self.piece_table` of the column `col` in `line` (both 1-indexed).
+    /// Columns past the end of the line are at the end of the line
+    fn col_to_index(&self, line: usize, col: usize) -> usize {
+        let text = self.line_text(line);
+        let offset = text.grapheme_indices(true).nth(col - 1).map_or(text.len(), |(offset, _)| offset);
+        self.line_start(line) + offset
     }
 
     /// Returns whether the text of the file matches the text of `self.piece_table`
     pub(crate) fn text_matches(&self) -> bool {
-        !self.piece_table.actions_taken()
+        self.piece_table.is_saved()
     }
 
     /// Returns visible text from line `first` (inclusive) to `last` (exclusive)
-    pub(crate) fn text_lines(&mut self, first: usize, last: usize) -> &str {
+    pub(crate) fn text_lines(&self, first: usize, last: usize) -> Chunks<'_> {
         if first >= last {
             panic!("First row ({}) must be less last ({})", first, last);
         }
-        let mut start_index = 0;
-        let mut end_index = 0;
-        for (i, line) in self.lines.iter().enumerate() {
-            if i < first - 1 {
-                start_index += line + 1;
-                end_index = start_index;
-            } else if i >= last - 1 {
-                break
-            } else {
-                end_index += line + 1;
-            }
-        }
-        self.piece_table.text().get(start_index..end_index - 1).unwrap()
+        let start_index = self.line_start(first);
+        let end_index = match self.piece_table.line_to_offset(last - 1) {
+            Some(index) => index - 1,
+            None => self.piece_table.text_len(),
+        };
+        self.piece_table.slice(start_index..end_index)
     }
 
     /// Returns the visible text for a single row
-    pub(crate) fn text_line(&mut self, line: usize) -> &str {
+    pub(crate) fn text_line(&self, line: usize) -> Chunks<'_> {
         self.text_lines(line, line + 1)
     }
 
+    /// Returns the text from `start` (inclusive) to `end` (exclusive), which are (row, col)
+    pub(crate) fn text_range(&self, start: (usize, usize), end: (usize, usize)) -> String {
+        let start_index = self.col_to_index(start.0, start.1);
+        let end_index = self.col_to_index(end.0, end.1);
+        self.piece_table.slice(start_index..end_index).to_string()
+    }
+
+    /// Set the mark `name` to (`row`, `col`)
+    pub(crate) fn set_mark(&mut self, name: char, row: usize, col: usize) {
+        self.marks.insert(name, (row, col));
+    }
+
+    /// Returns the position (row, col) of the mark `name`
+    pub(crate) fn mark(&self, name: char) -> Option<(usize, usize)> {
+        self.marks.get(&name).copied()
+    }
+
     /// Adds `text` at the current cursor position
     pub(crate) fn add_text(&mut self, text: String) {
-        let mut from_end = 0;
-        let mut num_lines = 0;
-        let text_len = text.len();
-        let mut last_line_len = 0;
-        for (i, line) in text.split('\n').enumerate() {
-            if i == 0 {
-                let curr_line_len = self.lines.get_mut(self.row - 1).unwrap();
-                from_end = *curr_line_len + 1 - self.col;
-                if text.contains('\n') {
-                    *curr_line_len -= from_end;
-                }
-                *curr_line_len += line.len();
-            } else if self.row + i >= self.lines.len() {
-                self.lines.push(line.len() + from_end);
-                from_end = 0;
-            } else {
-                self.lines.insert(self.row + i, line.len() + from_end);
-                from_end = 0;
-            }
-            num_lines += 1;
-            last_line_len = line.len();
-        }
+        let index = self.pt_index + text.len();
         self.piece_table.add_text(text, self.pt_index);
-        if num_lines == 1 {
-            self.right(text_len);
-        } else {
-            self.down(num_lines - 1);
-            self.goto_col(last_line_len + 1);
-        }
+        self.goto_index(index);
     }
 
     /// Deletes from current cursor position to (row, col) which are 1-indexed
     pub(crate) fn delete_text(&mut self, row: usize, col: usize) -> Result<(), String> {
-        if row == self.row {
-            if row == self.row && col == self.col {
-                return Ok(())
-                // return Err("No text to delete".to_string());
-            }
-            let line_len = self.lines.get_mut(row - 1).unwrap();
-            let first_col = min(self.col, col);
-            let last_col = if first_col == self.col {col} else {self.col};
-            if last_col > *line_len + 1 {
-                // panic!("Can't delete from {} to {} of line length {}", first_col, last_col, *line_len);
-                return Err(format!("Can't delete from {} to {} of line length {}", first_col, last_col, *line_len))
-            }
-            let len = last_col - first_col;
-            *(line_len) -= len;
-            if first_col == self.col {
-                self.piece_table.delete_text(self.pt_index, self.pt_index + len);
-            } else {
-                self.piece_table.delete_text(self.pt_index - len, self.pt_index);
-                self.col -= len;
-                self.col_want = self.col;
-            }
+        if row == self.row && col == self.col {
             return Ok(())
+            // return Err("No text to delete".to_string());
         }
-
-        let mut size = 0;
-        let first_row = min(self.row, row);
-        let first_col = if first_row == self.row {self.col} else {col};
-        let last_row = if first_row == self.row {row} else {self.row};
-        let last_col = if first_row == self.row {col} else {self.col};
-
-        if last_row == usize::MAX {
+        let index = if row == usize::MAX {
             // TODO: Don't actually read to end of file. Just pretend you did
             // If you do this, you have to update undo and redo to update self.eof_reached
-            self.read_to_eof()
+            self.read_to_eof();
+            self.piece_table.text_len()
         } else {
-            self.read_lines(max(self.lines.len(), row) - min(self.lines.len(), row));
-        }
-        
-        let first_line_len = self.lines.get_mut(first_row - 1).unwrap();
-        if first_col > *first_line_len + 1 {
-            panic!("Invalid beginning column {} for row {}", first_col, first_row);
-        }
-        size += *first_line_len + 1 - (first_col - 1);
-        *first_line_len -= *first_line_len - (first_col - 1);
-        let first_line_len_copy = *first_line_len;
-        let to_last_row = last_row == self.lines.len();
-        for _ in first_row + 1..last_row {
-            let line_len = self.lines.remove(first_row);
-            size += line_len + 1;
-        }
-        let last_line_len = self.lines.get_mut(last_row - (last_row - first_row) - 1).unwrap();
-        if last_col - 1 > *last_line_len {
-            panic!("Invalid ending column {} for row {}", last_col, last_row);
-        }
-        *(last_line_len) -= last_col - 1;
-        size += last_col - 1;
-        if to_last_row {
-            self.lines.pop();
-        }
-        if first_row == self.row {
-            self.piece_table.delete_text(self.pt_index, self.pt_index + size);
-        } else {
-            self.piece_table.delete_text(self.pt_index - size, self.pt_index);
-            self.row = first_row;
-            self.col = first_line_len_copy;
-            self.col_want = self.col;
+            if row >= self.num_lines() {
+                self.read_lines(row + 1 - self.num_lines());
+            }
+            if row > self.num_lines() || col > self.line_len(row) + 1 {
+                return Err(format!("Can't delete from ({}, {}) to ({}, {})", self.row, self.col, row, col))
+            }
+            self.col_to_index(row, col)
+        };
+        let (start, end) = (min(self.pt_index, index), max(self.pt_index, index));
+        if start != end {
+            self.piece_table.delete_text(start, end);
         }
+        self.goto_index(start);
         Ok(())
     }
 
@@ -256,96 +476,161 @@ impl Editor {
         self.delete_text(usize::MAX, usize::MAX).unwrap();
     }
 
-    /// Read `num_lines` from `reader`, updating `self.piece_table` & `self.lines`
+    /// Load `num_lines` from the file into `self.piece_table`
     /// Returns number of lines actually read
     fn read_lines(&mut self, num_lines: usize) -> usize {
-        if num_lines == 0 || self.eof_reached {
-            return 0;
-        }
         let mut lines_read = 0;
-        let reader = self.reader.as_mut().unwrap();
-        for _ in 0..num_lines {
-            let mut temp_str = String::new();
-            match reader.read_line(&mut temp_str) {
-                Ok(0) => {
-                    self.eof_reached = true;
-                    break
-                },
-                Ok(len) => {
-                    lines_read += 1;
-                    self.lines.push(len);
-                    self.piece_table.update_original_buffer(temp_str);
-                },
-                Err(e) => panic!("Error reading file: {:?}", e),
-            }
+        while lines_read < num_lines && self.read_line() {
+            lines_read += 1;
         }
         lines_read
     }
 
-    /// Read to EOF, updating `self.piece_table` & `self.lines`
+    /// Load the rest of the file into `self.piece_table`
     fn read_to_eof(&mut self) {
         if self.eof_reached {
             return;
         }
-        let reader = self.reader.as_mut().unwrap();
-        loop {
-            let mut temp_str = String::new();
-            match reader.read_line(&mut temp_str) {
-                Ok(0) => {
-                    self.eof_reached = true;
-                    break
-                },
-                Ok(len) => {
-                    self.lines.push(len);
-                    self.piece_table.update_original_buffer(temp_str);
-                },
-                Err(e) => panic!("Error reading file: {:?}", e),
-            }
+        let len = self.piece_table.unloaded_original().len();
+        if self.piece_table.load_original(len).is_err() {
+            // Keep the lines before the error
+            while self.read_line() {}
+        }
+        self.eof_reached = true;
+    }
+
+    /// Load a single line from the file into `self.piece_table`.
+    /// The last line is only complete once `self.eof_reached`.
+    /// Returns whether a line was read
+    fn read_line(&mut self) -> bool {
+        if self.eof_reached {
+            return false;
+        }
+        let unloaded = self.piece_table.unloaded_original();
+        let len = match unloaded.iter().position(|byte| *byte == b'\n') {
+            Some(newline) => newline + 1,
+            None => {
+                self.eof_reached = true;
+                unloaded.len()
+            },
+        };
+        if len == 0 {
+            return false;
         }
+        if let Err(e) = self.piece_table.load_original(len) {
+            // Stop reading, keeping the lines read so far
+            self.error = Some(format!("Error reading file: {}", e));
+            self.eof_reached = true;
+            return false;
+        }
+        true
+    }
+
+    /// Undo the last action, moving the cursor to where it took place
+    pub(crate) fn undo(&mut self) -> Result<(), String> {
+        let index = self.piece_table.undo()?;
+        self.goto_index(index);
+        Ok(())
+    }
+
+    /// Redo the last undone action, moving the cursor to where it took place
+    pub(crate) fn redo(&mut self) -> Result<(), String> {
+        let index = self.piece_table.redo()?;
+        self.goto_index(index);
+        Ok(())
+    }
+
+    /// Go back `steps` actions in time, across undo branches
+    pub(crate) fn earlier(&mut self, steps: usize) -> Result<(), String> {
+        let index = self.piece_table.earlier(steps)?;
+        self.goto_index(index);
+        Ok(())
+    }
+
+    /// Go forward `steps` actions in time, across undo branches
+    pub(crate) fn later(&mut self, steps: usize) -> Result<(), String> {
+        let index = self.piece_table.later(steps)?;
+        self.goto_index(index);
+        Ok(())
+    }
+
+    /// Go back to the text as it was `duration` earlier
+    pub(crate) fn earlier_time(&mut self, duration: Duration) -> Result<(), String> {
+        let index = self.piece_table.earlier_time(duration)?;
+        self.goto_index(index);
+        Ok(())
+    }
+
+    /// Go forward to the text as it was `duration` later
+    pub(crate) fn later_time(&mut self, duration: Duration) -> Result<(), String> {
+        let index = self.piece_table.later_time(duration)?;
+        self.goto_index(index);
+        Ok(())
+    }
+
+    /// Start grouping all following changes into a single undo step
+    pub(crate) fn begin_group(&mut self) {
+        self.piece_table.begin_group();
+    }
+
+    /// Finish the current undo step started by `begin_group`
+    pub(crate) fn commit_group(&mut self) {
+        self.piece_table.commit_group();
+    }
+
+    /// Free memory used by text that is no longer needed
+    pub(crate) fn compact(&mut self) {
+        self.piece_table.compact();
+    }
+
+    /// Move the cursor to `index` of `self.piece_table`
+    pub(crate) fn goto_index(&mut self, index: usize) {
+        (self.row, self.col) = self.index_to_position(index);
+        self.col_want = self.col;
+        self.pt_index = index;
+    }
+
+    /// Returns the position (row, col) of `index` of `self.piece_table`
+    pub(crate) fn index_to_position(&self, index: usize) -> (usize, usize) {
+        let row = self.piece_table.offset_to_line(index) + 1;
+        let col = self.piece_table.slice(self.line_start(row)..index).to_string().graphemes(true).count() + 1;
+        (row, col)
+    }
+
+    /// Move the cursor to `row` and `col` without changing `self.col_want`
+    fn set_cursor(&mut self, row: usize, col: usize) {
+        self.row = row;
+        self.col = col;
+        self.pt_index = self.col_to_index(row, col);
     }
 
     /// Move the cursor up `num` places
     /// If unable to go up all the way, go to first row
     pub(crate) fn up(&mut self, num: usize) {
-        if num == 0 || self.row == 1 {
-            return
-        } else if num >= self.row {
-            self.up(self.row - 1);
+        let row = self.row - min(num, self.row - 1);
+        if row == self.row {
             return
         }
-        self.pt_index -= self.col;
-        for i in 1..num {
-            self.pt_index -= self.lines.get(self.row - i - 1).unwrap() + 1;
-        }
-        self.row -= num;
-        let line_cols = self.lines.get(self.row - 1).unwrap();
-        self.col = min(self.col_want, line_cols + 1);
-        self.pt_index -= line_cols + 1 - self.col;
+        self.set_cursor(row, min(self.col_want, self.line_len(row) + 1));
     }
 
     /// Move the cursor down `num` places.
     /// If unable to go all the way down, go to last row
     pub(crate) fn down(&mut self, num: usize) {
-        if num == 0 {
-            return
-        } else if self.row + num > self.lines.len() {
-            let lines_read = self.read_lines(self.row + num - self.lines.len());
-            self.down(lines_read);
-            return
+        if self.row + num >= self.num_lines() {
+            self.read_lines(self.row + num + 1 - self.num_lines());
         }
-        self.pt_index += self.lines.get(self.row - 1).unwrap() + 1 - self.col + 1;
-        for i in 1..num {
-            self.pt_index += self.lines.get(self.row + i - 1).unwrap() + 1;
+        let row = self.row + min(num, self.num_lines() - self.row);
+        if row == self.row {
+            return
         }
-        self.row += num;
-        self.col = min(self.col_want, self.lines.get(self.row - 1).unwrap() + 1);
-        self.pt_index += self.col - 1;
+        self.set_cursor(row, min(self.col_want, self.line_len(row) + 1));
     }
 
     /// Move the cursor right `num` places.
     /// If unable to go all the way right, go to last column
     pub(crate) fn right(&mut self, num: usize) {
-        let line_len = self.lines.get(self.row - 1).unwrap();
+        let line_len = self.line_len(self.row);
         if num == 0 || self.col == line_len + 1 {
             return
         } else if self.col + num > line_len + 1 {
@@ -353,7 +638,7 @@ impl Editor {
             return
         }
         self.col += num;
-        self.pt_index += num;
+        self.pt_index = self.col_to_index(self.row, self.col);
         self.col_want = self.col;
     }
 
@@ -367,13 +652,13 @@ impl Editor {
             return
         }
         self.col -= num;
-        self.pt_index -= num;
+        self.pt_index = self.col_to_index(self.row, self.col);
         self.col_want = self.col;
     }
 
     /// Move to a certain column in the current row
     pub(crate) fn goto_col(&mut self, col: usize) {
-        if col > *self.lines.get(self.row - 1).unwrap() + 1 {
+        if col > self.line_len(self.row) + 1 {
             self.goto_last_col();
         } else if self.col == col {
             self.col_want = col;
@@ -386,11 +671,9 @@ impl Editor {
 
     /// Move to a certain row
     pub(crate) fn goto_row(&mut self, row: usize) {
-        if self.row == row {
-            return
-        } else if row < self.row {
+        if row < self.row {
             self.up(self.row - row)
-        } else {
+        } else if row > self.row {
             self.down(row - self.row)
         }
     }
@@ -403,19 +686,656 @@ impl Editor {
 
     /// Move to the last column in the current row
     pub(crate) fn goto_last_col(&mut self) {
-        self.goto_col(*self.lines.get(self.row - 1).unwrap() + 1)
+        self.goto_col(self.line_len(self.row) + 1)
     }
 
     /// Move to the last row
     pub(crate) fn goto_last_row(&mut self) {
         self.read_to_eof();
-        self.goto(self.lines.len(), 1)
+        self.goto(self.num_lines(), 1)
+    }
+
+    /// Returns whether `line` (1-indexed) exists, loading it from the file if needed
+    pub(crate) fn has_line(&mut self, line: usize) -> bool {
+        if line >= self.num_lines() {
+            self.read_lines(line + 1 - self.num_lines());
+        }
+        line <= self.num_lines()
+    }
+
+    /// Move the cursor to the start of the `num`-th next word, or WORD if `bigword` (`w`).
+    /// Like Vim, an empty line counts as a word
+    pub(crate) fn word_forward(&mut self, num: usize, bigword: bool) {
+        let mut scan = Scan::new(self);
+        for _ in 0..num {
+            let class = scan.class(bigword);
+            if !scan.next(self) || (class != Class::Blank && !scan.skip(self, class, bigword, true)) {
+                break
+            }
+            while scan.class(bigword) == Class::Blank && !scan.is_empty_line() {
+                if !scan.next(self) {
+                    break
+                }
+            }
+        }
+        self.goto(scan.row, scan.col);
+    }
+
+    /// Move the cursor to the start of the `num`-th previous word, or WORD if `bigword` (`b`)
+    pub(crate) fn word_backward(&mut self, num: usize, bigword: bool) {
+        let mut scan = Scan::new(self);
+        for _ in 0..num {
+            if !scan.prev(self) {
+                break
+            }
+            while scan.class(bigword) == Class::Blank && !scan.is_empty_line() {
+                if !scan.prev(self) {
+                    break
+                }
+            }
+            if scan.class(bigword) != Class::Blank && scan.skip(self, scan.class(bigword), bigword, false) {
+                scan.next(self);
+            }
+        }
+        self.goto(scan.row, scan.col);
+    }
+
+    /// Move the cursor to the end of the `num`-th next word, or WORD if `bigword` (`e`)
+    pub(crate) fn word_end(&mut self, num: usize, bigword: bool) {
+        let mut scan = Scan::new(self);
+        for _ in 0..num {
+            if !scan.next(self) {
+                break
+            }
+            while scan.class(bigword) == Class::Blank {
+                if !scan.next(self) {
+                    break
+                }
+            }
+            if scan.class(bigword) != Class::Blank && scan.skip(self, scan.class(bigword), bigword, true) {
+                scan.prev(self);
+            }
+        }
+        self.goto(scan.row, scan.col);
+    }
+
+    /// Move the cursor to the end of the `num`-th previous word, or WORD if `bigword` (`ge`)
+    pub(crate) fn word_end_backward(&mut self, num: usize, bigword: bool) {
+        let mut scan = Scan::new(self);
+        for _ in 0..num {
+            let class = scan.class(bigword);
+            if !scan.prev(self) || (class != Class::Blank && !scan.skip(self, class, bigword, false)) {
+                break
+            }
+            while scan.class(bigword) == Class::Blank && !scan.is_empty_line() {
+                if !scan.prev(self) {
+                    break
+                }
+            }
+        }
+        self.goto(scan.row, scan.col);
+    }
+
+    /// Move the cursor to the start of the `num`-th next sentence (`)`). Like Vim, a
+    /// sentence ends at a `.`, `!` or `?` followed by a blank, and an empty line after
+    /// a paragraph counts as a sentence
+    pub(crate) fn sentence_forward(&mut self, num: usize) {
+        let mut scan = Scan::new(self);
+        for _ in 0..num {
+            while scan.next(self) && !scan.at_sentence_start(self) {}
+        }
+        self.goto(scan.row, scan.col);
+    }
+
+    /// Move the cursor to the start of the sentence, or of the one before it if already
+    /// at the start, `num` times (`(`)
+    pub(crate) fn sentence_backward(&mut self, num: usize) {
+        let mut scan = Scan::new(self);
+        for _ in 0..num {
+            while scan.prev(self) && !scan.at_sentence_start(self) {}
+        }
+        self.goto(scan.row, scan.col);
+    }
+
+    /// Move the cursor to the `num`-th next empty line after a paragraph, or the
+    /// end of the last line if there is none (`}`)
+    pub(crate) fn paragraph_forward(&mut self, num: usize) {
+        let mut row = self.row;
+        for _ in 0..num {
+            while self.line_len(row) == 0 && self.has_line(row + 1) {
+                row += 1;
+            }
+            while self.line_len(row) != 0 && self.has_line(row + 1) {
+                row += 1;
+            }
+        }
+        self.goto(row, self.line_len(row) + 1);
+    }
+
+    /// Move the cursor to the `num`-th previous empty line before a paragraph, or the
+    /// start of the first line if there is none (`{`)
+    pub(crate) fn paragraph_backward(&mut self, num: usize) {
+        let mut row = self.row;
+        for _ in 0..num {
+            while self.line_len(row) == 0 && row > 1 {
+                row -= 1;
+            }
+            while self.line_len(row) != 0 && row > 1 {
+                row -= 1;
+            }
+        }
+        self.goto(row, 1);
+    }
+
+    /// Move the cursor to the `num`-th `target` after the cursor in its line, or before it
+    /// if not `forward` (`f` and `F`). If `till`, move next to it instead (`t` and `T`), in
+    /// which case a `target` right next to the cursor is skipped if `repeat` (`;` and `,`).
+    /// Returns false without moving if there aren't enough of `target`
+    pub(crate) fn find_char(&mut self, num: usize, target: char, forward: bool, till: bool, repeat: bool) -> bool {
+        let target = target.to_string();
+        let line = self.line_text(self.row);
+        let cols: Vec<usize> = line.graphemes(true).enumerate()
+            .filter(|(_, grapheme)| *grapheme == target)
+            .map(|(i, _)| i + 1).collect();
+        let skip = if till && repeat {1} else {0};
+        let found = if forward {
+            cols.into_iter().filter(|col| *col > self.col + skip).nth(num - 1)
+        } else {
+            cols.into_iter().rev().filter(|col| *col + skip < self.col).nth(num - 1)
+        };
+        match found {
+            Some(col) => {
+                let col = match (till, forward) {
+                    (false, _) => col,
+                    (true, true) => col - 1,
+                    (true, false) => col + 1,
+                };
+                self.goto_col(col);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Returns the position (row, col) of the `num`-th match of `regex` after the cursor,
+    /// or before it if not `forward`, and whether the search wrapped around the end (or
+    /// start) of the text to find it. Returns `None` if there is no match at all. The file
+    /// is only read as far as needed to find a match
+    pub(crate) fn search(&mut self, regex: &Regex, forward: bool, num: usize) -> Option<((usize, usize), bool)> {
+        let mut index = self.pt_index;
+        let mut wrapped = false;
+        for _ in 0..num {
+            let (found, wrap) = if forward {self.find_forward(regex, index)?} else {self.find_backward(regex, index)?};
+            index = found;
+            wrapped |= wrap;
+        }
+        Some((self.index_to_position(index), wrapped))
+    }
+
+    /// Returns the index of the first match of `regex` after `index`, and whether the
+    /// search wrapped around the end of the text to find it
+    fn find_forward(&mut self, regex: &Regex, index: usize) -> Option<(usize, bool)> {
+        let from = index + self.piece_table.chars_at(index).next().map_or(0, char::len_utf8);
+        loop {
+            if let Some(found) = self.piece_table.find(regex, from..self.piece_table.text_len()) {
+                return Some((found.range().start, false))
+            } else if self.eof_reached {
+                break
+            }
+            // Read as many lines again as there are, so the text is only searched a few times
+            self.read_lines(max(self.num_lines(), SEARCH_LINES));
+        }
+        self.piece_table.find(regex, 0..from).map(|found| (found.range().start, true))
+    }
+
+    /// Returns the index of the last match of `regex` before `index`, and whether the
+    /// search wrapped around the start of the text to find it
+    fn find_backward(&mut self, regex: &Regex, index: usize) -> Option<(usize, bool)> {
+        let mut end = index;
+        while end > 0 {
+            // Search a few lines at a time, starting with those closest to `index`
+            let start = self.line_start(self.piece_table.offset_to_line(end.saturating_sub(SEARCH_BYTES)) + 1);
+            if let Some(found) = self.piece_table.rfind(regex, start..end) {
+                return Some((found.range().start, false))
+            }
+            end = start;
+        }
+        self.read_to_eof();
+        self.piece_table.rfind(regex, index..self.piece_table.text_len()).map(|found| (found.range().start, true))
+    }
+
+    /// Returns the start (inclusive) and end (exclusive) of each match of `regex` which
+    /// starts in the rows `first` to `last` (inclusive), as (row, col)
+    pub(crate) fn find_all(&self, regex: &Regex, first: usize, last: usize) -> Vec<((usize, usize), (usize, usize))> {
+        let end = self.piece_table.line_to_offset(last).unwrap_or(self.piece_table.text_len());
+        let mut matches = Vec::new();
+        let mut from = self.line_start(first);
+        while let Some(found) = self.piece_table.find(regex, from..end) {
+            let range = found.range();
+            matches.push((self.index_to_position(range.start), self.index_to_position(range.end)));
+            // Don't find an empty match at the same place again
+            from = if range.is_empty() {range.end + self.piece_table.chars_at(range.end).next().map_or(1, char::len_utf8)} else {range.end};
+        }
+        matches
+    }
+
+    /// Returns the first match of `regex` which starts between `index` and the end of
+    /// row `last` (including its newline, or the end of the text if it is the last row)
+    pub(crate) fn find_before_row(&self, regex: &Regex, index: usize, last: usize) -> Option<Captures> {
+        let end = self.piece_table.line_to_offset(last).unwrap_or(self.piece_table.text_len() + 1);
+        self.piece_table.find(regex, index..end)
+    }
+
+    /// Returns the character at `index` of the text, if there is one
+    pub(crate) fn char_at(&self, index: usize) -> Option<char> {
+        self.piece_table.chars_at(index).next()
+    }
+
+    /// Returns the text from `range.start` (inclusive) to `range.end` (exclusive), as
+    /// indexes into the text
+    pub(crate) fn text_between(&self, range: Range<usize>) -> String {
+        self.piece_table.slice(range).to_string()
+    }
+
+    /// Replace the text from `range.start` (inclusive) to `range.end` (exclusive) with
+    /// `text`, moving the cursor to the start of it
+    pub(crate) fn replace_text(&mut self, range: Range<usize>, text: String) {
+        if !range.is_empty() {
+            self.piece_table.delete_text(range.start, range.end);
+        }
+        if !text.is_empty() {
+            self.piece_table.add_text(text, range.start);
+        }
+        self.goto_index(range.start);
+    }
+
+    /// Returns the number of the last line, loading all of the file. A trailing
+    /// newline ends the last line rather than starting an empty one
+    pub(crate) fn last_line(&mut self) -> usize {
+        self.read_to_eof();
+        let num_lines = self.num_lines();
+        if num_lines > 1 && self.line_len(num_lines) == 0 {num_lines - 1} else {num_lines}
+    }
+
+    /// Returns the first and last rows of the `num` paragraphs or runs of empty lines
+    /// starting at the cursor's. If `around`, each paragraph includes the empty lines
+    /// after it (or before it, if there are none after it)
+    pub(crate) fn paragraph_object(&mut self, num: usize, around: bool) -> (usize, usize) {
+        let empty = self.line_len(self.row) == 0;
+        let mut first = self.row;
+        while first > 1 && (self.line_len(first - 1) == 0) == empty {
+            first -= 1;
+        }
+        let blocks = if around {num * 2} else {num};
+        let mut last = self.row;
+        let mut found = 0;
+        while found < blocks {
+            if found > 0 {
+                if !self.has_line(last + 1) {
+                    break
+                }
+                last += 1;
+            }
+            let empty = self.line_len(last) == 0;
+            while self.has_line(last + 1) && (self.line_len(last + 1) == 0) == empty {
+                last += 1;
+            }
+            found += 1;
+        }
+        if around && found < blocks && !empty {
+            while first > 1 && self.line_len(first - 1) == 0 {
+                first -= 1;
+            }
+        }
+        (first, last)
+    }
+
+    /// Returns the start (inclusive) and end (exclusive) of the `num` words (or WORDs if
+    /// `bigword`) starting at the cursor, where the blanks between words count as words too
+    /// (`iw`). If `around`, each word includes the blanks after it, or before it if there
+    /// are none after it (`aw`). Like in Vim, the words don't go past the end of the line
+    pub(crate) fn word_object(&self, num: usize, bigword: bool, around: bool) -> ((usize, usize), (usize, usize)) {
+        let line = Scan::graphemes(self, self.row);
+        if line.is_empty() {
+            return ((self.row, 1), (self.row, 1))
+        }
+        let class = |col: usize| Class::of(&line[col - 1], bigword);
+        let col = min(self.col, line.len());
+        let mut start = col;
+        while start > 1 && class(start - 1) == class(col) {
+            start -= 1;
+        }
+        let runs = if around {num * 2} else {num};
+        let (mut end, mut found) = (col, 0);
+        while found < runs && end <= line.len() {
+            let run_class = class(end);
+            while end <= line.len() && class(end) == run_class {
+                end += 1;
+            }
+            found += 1;
+        }
+        if around && class(col) != Class::Blank && found % 2 == 1 {
+            while start > 1 && class(start - 1) == Class::Blank {
+                start -= 1;
+            }
+        }
+        ((self.row, start), (self.row, end))
+    }
+
+    /// Returns the start (inclusive) and end (exclusive) of the text between the `quote`
+    /// characters around the cursor, or the next ones on the line (`i"`). Quotes after a
+    /// backslash don't count. If `around`, the quotes are included, with the blanks after
+    /// them or before them if there are none after them (`a"`)
+    pub(crate) fn quote_object(&self, quote: char, around: bool) -> Option<((usize, usize), (usize, usize))> {
+        let line = Scan::graphemes(self, self.row);
+        let quote = quote.to_string();
+        let mut quotes = Vec::new();
+        let mut escaped = false;
+        for (i, grapheme) in line.iter().enumerate() {
+            if *grapheme == quote && !escaped {
+                quotes.push(i + 1);
+            }
+            escaped = grapheme == "\\" && !escaped;
+        }
+        // Quotes are paired up from the start of the line
+        let before = quotes.iter().filter(|col| **col < self.col).count();
+        let first = before - before % 2;
+        let (open, close) = (*quotes.get(first)?, *quotes.get(first + 1)?);
+        if !around {
+            return Some(((self.row, open + 1), (self.row, close)))
+        }
+        let blank = |col: usize| line.get(col - 1).is_some_and(|grapheme| Class::of(grapheme, true) == Class::Blank);
+        let (mut start, mut end) = (open, close + 1);
+        if blank(end) {
+            while blank(end) {
+                end += 1;
+            }
+        } else {
+            while start > 1 && blank(start - 1) {
+                start -= 1;
+            }
+        }
+        Some(((self.row, start), (self.row, end)))
+    }
+
+    /// Returns the start (inclusive) and end (exclusive) of the text inside the `num`-th
+    /// pair of `open` and `close` brackets around the cursor, which may be nested and span
+    /// lines (`i(`). If `around`, the brackets are included (`a(`). If the brackets are at
+    /// the end and start of their lines, the inner text ends at the start of the line of `close`
+    pub(crate) fn block_object(&mut self, num: usize, open: char, close: char, around: bool) -> Option<((usize, usize), (usize, usize))> {
+        let (open, close) = (open.to_string(), close.to_string());
+        let mut scan = Scan::new(self);
+        let (mut depth, mut found) = (0, 0);
+        let mut at_cursor = true;
+        loop {
+            if scan.grapheme() == Some(&open) {
+                if depth == 0 {
+                    found += 1;
+                    if found == num {
+                        break
+                    }
+                } else {
+                    depth -= 1;
+                }
+            } else if scan.grapheme() == Some(&close) && !at_cursor {
+                depth += 1;
+            }
+            at_cursor = false;
+            if !scan.prev(self) {
+                return None
+            }
+        }
+        let start = (scan.row, scan.col);
+        loop {
+            if !scan.next(self) {
+                return None
+            }
+            if scan.grapheme() == Some(&open) {
+                depth += 1;
+            } else if scan.grapheme() == Some(&close) {
+                if depth == 0 {
+                    break
+                }
+                depth -= 1;
+            }
+        }
+        let end = (scan.row, scan.col);
+        if around {
+            return Some((start, (end.0, end.1 + 1)))
+        }
+        let mut inner_start = (start.0, start.1 + 1);
+        if inner_start.1 > self.line_len(start.0) && start.0 < end.0 {
+            inner_start = (start.0 + 1, 1);
+        }
+        let mut inner_end = end;
+        if end.0 > inner_start.0 && self.text_range((end.0, 1), end).trim().is_empty() {
+            inner_end = (end.0, 1);
+        }
+        Some((inner_start, inner_end))
+    }
+
+    /// Returns the start (inclusive) and end (exclusive) of the text inside the `num`-th
+    /// XML or HTML element around the cursor (`it`). If `around`, the tags are included (`at`)
+    pub(crate) fn tag_object(&mut self, num: usize, around: bool) -> Option<((usize, usize), (usize, usize))> {
+        let cursor = (self.row, self.col);
+        let mut scan = Scan::new(self);
+        // Names of the closing tags between the scan and the cursor which aren't matched yet
+        let mut closed: Vec<String> = Vec::new();
+        let mut found = 0;
+        loop {
+            if let Some((name, closing, end)) = scan.tag(self) {
+                if closing {
+                    // A closing tag the cursor is on belongs to the element the cursor is in
+                    if end < cursor {
+                        closed.push(name);
+                    }
+                } else if closed.last() == Some(&name) {
+                    closed.pop();
+                } else if closed.is_empty() {
+                    if let Some((close_start, close_end)) = scan.matching_tag(self, &name) {
+                        found += 1;
+                        if found == num {
+                            return Some(if around {
+                                ((scan.row, scan.col), (close_end.0, close_end.1 + 1))
+                            } else {
+                                ((end.0, end.1 + 1), close_start)
+                            })
+                        }
+                    }
+                }
+            }
+            if !scan.prev(self) {
+                return None
+            }
+        }
+    }
+}
+
+/// Kind of character, for word motions. A word is a run of characters of the same class
+#[derive(Clone, Copy, PartialEq)]
+enum Class {
+    /// Whitespace
+    Blank,
+    /// Anything else that isn't a keyword character. In a WORD, everything that isn't blank
+    Punctuation,
+    /// Letters, digits and `_`
+    Keyword,
+}
+
+impl Class {
+    /// Returns the class of `grapheme`, for a WORD if `bigword`
+    fn of(grapheme: &str, bigword: bool) -> Class {
+        let c = grapheme.chars().next().unwrap_or(' ');
+        if c.is_whitespace() {
+            Class::Blank
+        } else if !bigword && (c.is_alphanumeric() || c == '_') {
+            Class::Keyword
+        } else {
+            Class::Punctuation
+        }
+    }
+}
+
+/// Position of a scan through the text of an `Editor`, one grapheme at a time, for word
+/// and sentence motions. Like in Vim, the end of each line (column `len + 1`) is a
+/// position too, which counts as a blank
+#[derive(Clone)]
+struct Scan {
+    /// Row (1-indexed)
+    row: usize,
+    /// Column (1-indexed), counted in grapheme clusters
+    col: usize,
+    /// Graphemes of the row
+    line: Vec<String>,
+}
+
+impl Scan {
+    /// Start a scan at the cursor of `editor`
+    fn new(editor: &Editor) -> Scan {
+        Scan {row: editor.row, col: editor.col, line: Scan::graphemes(editor, editor.row)}
+    }
+
+    /// Returns the graphemes of `row` of `editor`
+    fn graphemes(editor: &Editor, row: usize) -> Vec<String> {
+        editor.line_text(row).graphemes(true).map(String::from).collect()
+    }
+
+    /// Returns the grapheme at the position, or `None` at the end of the line
+    fn grapheme(&self) -> Option<&str> {
+        self.line.get(self.col - 1).map(String::as_str)
+    }
+
+    /// Returns the class of the grapheme at the position, for a WORD if `bigword`
+    fn class(&self, bigword: bool) -> Class {
+        self.grapheme().map_or(Class::Blank, |grapheme| Class::of(grapheme, bigword))
+    }
+
+    /// Returns whether the position is on an empty line
+    fn is_empty_line(&self) -> bool {
+        self.line.is_empty()
+    }
+
+    /// Move to the next position. Returns false if at the end of the text
+    fn next(&mut self, editor: &mut Editor) -> bool {
+        if self.col <= self.line.len() {
+            self.col += 1;
+        } else if editor.has_line(self.row + 1) {
+            self.row += 1;
+            self.col = 1;
+            self.line = Scan::graphemes(editor, self.row);
+        } else {
+            return false
+        }
+        true
+    }
+
+    /// Move to the previous position. Returns false if at the start of the text
+    fn prev(&mut self, editor: &Editor) -> bool {
+        if self.col > 1 {
+            self.col -= 1;
+        } else if self.row > 1 {
+            self.row -= 1;
+            self.line = Scan::graphemes(editor, self.row);
+            self.col = self.line.len() + 1;
+        } else {
+            return false
+        }
+        true
+    }
+
+    /// Move forward (or backward) past the graphemes of `class`. Returns false if
+    /// the start or end of the text was reached first
+    fn skip(&mut self, editor: &mut Editor, class: Class, bigword: bool, forward: bool) -> bool {
+        while self.class(bigword) == class {
+            if !(if forward {self.next(editor)} else {self.prev(editor)}) {
+                return false
+            }
+        }
+        true
+    }
+
+    /// Returns the name of the tag starting at the position, whether it is a closing tag
+    /// and the position of its `>`. Returns `None` if the position isn't at the start of an
+    /// opening or closing tag (comments and tags closing themselves, like `<br/>`, don't count)
+    fn tag(&self, editor: &mut Editor) -> Option<(String, bool, (usize, usize))> {
+        if self.grapheme() != Some("<") {
+            return None
+        }
+        let mut scan = self.clone();
+        let mut text = String::new();
+        loop {
+            if !scan.next(editor) {
+                return None
+            }
+            match scan.grapheme() {
+                Some(">") => break,
+                Some("<") => return None,
+                Some(grapheme) => text.push_str(grapheme),
+                None => text.push('\n'),
+            }
+        }
+        let closing = text.starts_with('/');
+        let name: String = text.trim_start_matches('/').chars().take_while(|c| !c.is_whitespace() && *c != '/').collect();
+        if !name.starts_with(char::is_alphanumeric) || text.ends_with('/') {
+            return None
+        }
+        Some((name, closing, (scan.row, scan.col)))
+    }
+
+    /// Returns the positions of the `<` and `>` of the closing tag which matches the
+    /// opening tag `name` at the position, taking nested elements into account
+    fn matching_tag(&self, editor: &mut Editor, name: &str) -> Option<((usize, usize), (usize, usize))> {
+        let mut scan = self.clone();
+        let mut depth = 0;
+        loop {
+            if let Some((_, closing, end)) = scan.tag(editor).filter(|(tag_name, _, _)| tag_name == name) {
+                if !closing {
+                    depth += 1;
+                } else if depth == 1 {
+                    return Some(((scan.row, scan.col), end))
+                } else {
+                    depth -= 1;
+                }
+            }
+            if !scan.next(editor) {
+                return None
+            }
+        }
+    }
+
+    /// Returns whether the position is at the start of a sentence, or on the first
+    /// empty line after a paragraph
+    fn at_sentence_start(&self, editor: &Editor) -> bool {
+        let mut scan = self.clone();
+        if self.is_empty_line() {
+            return !scan.prev(editor) || !scan.is_empty_line()
+        } else if self.class(true) == Class::Blank {
+            return false
+        }
+        // Go back over the blanks before the position, if any
+        if !scan.prev(editor) {
+            return true
+        } else if scan.class(true) != Class::Blank {
+            return false
+        }
+        while scan.class(true) == Class::Blank {
+            if scan.is_empty_line() || !scan.prev(editor) {
+                return true
+            }
+        }
+        while matches!(scan.grapheme(), Some(")") | Some("]") | Some("\"") | Some("'")) {
+            if !scan.prev(editor) {
+                return false
+            }
+        }
+        matches!(scan.grapheme(), Some(".") | Some("!") | Some("?"))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs;
 
     #[test]
     fn add_text() {
@@ -630,7 +1550,6 @@ mod tests {
         editor.goto_last_col();
         editor.delete_text(editor.row() + 1, 1).unwrap();
         assert_eq!(editor.text(), want_str);
-        println!("{:?}", editor.lines);
         assert_eq!(editor.num_lines(), want_str.lines().count());
 
         editor = Editor::new(String::new());
@@ -644,60 +1563,569 @@ mod tests {
         assert_eq!(editor.num_lines(), want_str.lines().count());
     }
 
+    #[test]
+    fn undo_redo() {
+        let mut editor = Editor::new(String::new());
+        editor.add_text("ab\ncd".to_string());
+        editor.goto(1, 2);
+        editor.delete_text(2, 2).unwrap();
+        assert_eq!(editor.text(), "ad");
+        assert_eq!(editor.num_lines(), 1);
+        editor.undo().unwrap();
+        assert_eq!(editor.text(), "ab\ncd");
+        assert_eq!(editor.num_lines(), 2);
+        assert_eq!((editor.row(), editor.col()), (1, 2));
+        editor.undo().unwrap();
+        assert_eq!(editor.text(), "");
+        assert_eq!(editor.num_lines(), 1);
+        assert_eq!((editor.row(), editor.col()), (1, 1));
+        assert!(editor.undo().is_err());
+        editor.redo().unwrap();
+        assert_eq!(editor.text(), "ab\ncd");
+        editor.redo().unwrap();
+        assert_eq!(editor.text(), "ad");
+        assert!(editor.redo().is_err());
+        editor.goto_last_col();
+        editor.add_text("e".to_string());
+        assert_eq!(editor.text(), "ade");
+    }
+
+    #[test]
+    fn graphemes() {
+        let mut editor = Editor::new(String::new());
+        editor.add_text("a😀é\n日本".to_string());
+        assert_eq!((editor.row(), editor.col(), editor.display_col()), (2, 3, 5));
+        editor.up(1);
+        assert_eq!((editor.row(), editor.col(), editor.display_col()), (1, 3, 4));
+        assert_eq!(editor.line_len(1), 3);
+        editor.right(1);
+        assert_eq!((editor.col(), editor.display_col()), (4, 5));
+        editor.left(2);
+        assert_eq!((editor.col(), editor.display_col()), (2, 2));
+        editor.delete_text(1, 3).unwrap();
+        assert_eq!(editor.text(), "aé\n日本");
+        editor.add_text("👨‍👩‍👧".to_string());
+        assert_eq!((editor.col(), editor.display_col(), editor.line_len(1)), (3, 4, 3));
+        editor.left(1);
+        editor.delete_text(1, 4).unwrap();
+        assert_eq!(editor.text(), "a\n日本");
+
+        editor.down(1);
+        assert_eq!(editor.display_col_to_col(2, 1), 1);
+        assert_eq!(editor.display_col_to_col(2, 2), 1);
+        assert_eq!(editor.display_col_to_col(2, 3), 2);
+        assert_eq!(editor.display_col_to_col(2, 10), 3);
+    }
+
+    #[test]
+    fn text_range() {
+        let mut editor = Editor::new(String::new());
+        editor.add_text("abc\nd😀f\n\ngh".to_string());
+        assert_eq!(editor.text_range((1, 2), (1, 4)), "bc");
+        assert_eq!(editor.text_range((1, 3), (2, 3)), "c\nd😀");
+        assert_eq!(editor.text_range((2, 2), (4, 1)), "😀f\n\n");
+        assert_eq!(editor.text_range((3, 1), (4, 3)), "\ngh");
+        assert_eq!(editor.text_range((4, 3), (4, 3)), "");
+    }
+
+    #[test]
+    fn read_file() {
+        let path = std::env::temp_dir().join(format!("via-editor-test-{}", std::process::id()));
+        fs::write(&path, "ab\ncd\nef").unwrap();
+        let mut editor = Editor::new(path.to_str().unwrap().to_string());
+        assert_eq!(editor.text(), "ab\n");
+        editor.down(1);
+        assert_eq!(editor.text(), "ab\ncd\n");
+        editor.goto_last_row();
+        assert_eq!((editor.row(), editor.col()), (3, 1));
+        editor.add_text("x".to_string());
+        editor.write_file().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "ab\ncd\nxef");
+        editor.undo().unwrap();
+        assert_eq!(editor.text(), "ab\ncd\nef");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn write_rows() {
+        let path = std::env::temp_dir().join(format!("via-editor-write-test-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let mut editor = Editor::new(String::new());
+        editor.add_text("ab\ncd\nef\n".to_string());
+        assert!(!editor.text_matches());
+        assert_eq!(editor.write_file(), Err("No file name".to_string()));
+        assert_eq!(editor.write_rows(&path, Some((2, 3)), false), Ok(6));
+        assert_eq!(editor.write_rows(&path, Some((1, 1)), true), Ok(3));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "cd\nef\nab\n");
+        assert!(!editor.text_matches());
+        editor.update_file_path(path.clone());
+        assert_eq!(editor.write_file(), Ok(9));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "ab\ncd\nef\n");
+        assert!(editor.text_matches());
+        editor.add_text("x".to_string());
+        assert!(!editor.text_matches());
+        editor.undo().unwrap();
+        assert!(editor.text_matches());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recover() {
+        let dir = std::env::temp_dir().join(format!("via-editor-recover-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // Keep the swap files out of the real state directory
+        let open = |path: &str| {
+            let mut editor = Editor::new(path.to_string());
+            editor.state_dir = Some(dir.clone());
+            editor
+        };
+        // All of the text, since only as much of the file as needed is loaded
+        let text = |editor: &mut Editor| {
+            editor.read_to_eof();
+            editor.text()
+        };
+        let path = dir.join("file").to_str().unwrap().to_string();
+        fs::write(&path, "ab\ncd\n").unwrap();
+        let mut editor = open(&path);
+        assert_eq!(editor.find_swap(), None);
+        assert_eq!(editor.recover(true), Err(format!("E305: No swap file found for {}", path)));
+        editor.open_swap().unwrap();
+        editor.replace_text(3..3, "x".to_string());
+        editor.sync_swap().unwrap();
+        editor.replace_text(0..1, "é".to_string());
+        editor.sync_swap().unwrap();
+        // As if via stopped without removing the swap file
+        drop(editor);
+
+        // The swap file of a via which is still running is left to it
+        let mut other = open(&path);
+        assert_eq!(other.recover(false), Ok(3));
+        assert_eq!(text(&mut other), "éb\nxcd\n");
+        other.replace_text(0..0, "z".to_string());
+        other.sync_swap().unwrap();
+        drop(other);
+
+        let mut editor = open(&path);
+        assert_eq!(editor.find_swap(), Some(std::process::id()));
+        assert_eq!(editor.recover(true), Ok(3));
+        assert_eq!(text(&mut editor), "éb\nxcd\n");
+        assert!(!editor.text_matches());
+        editor.undo().unwrap();
+        assert_eq!(editor.text(), "ab\ncd\n");
+        editor.redo().unwrap();
+        editor.sync_swap().unwrap();
+        drop(editor);
+
+        // The swap file it was recovered from is replaced by one with the same text
+        let mut editor = open(&path);
+        assert!(editor.recover(true).is_ok());
+        assert_eq!(text(&mut editor), "éb\nxcd\n");
+        // Once the file is written, the swap file starts over from it
+        editor.write_file().unwrap();
+        let mut other = open(&path);
+        assert_eq!(other.recover(true), Ok(0));
+        assert_eq!(text(&mut other), "éb\nxcd\n");
+        other.close_swap();
+        assert_eq!(editor.find_swap(), None);
+
+        // An unsaved text moved to another file is recovered from the file it was
+        // read from, until it is written to the other one
+        editor.open_swap().unwrap();
+        editor.replace_text(0..2, String::new());
+        let new_path = dir.join("new").to_str().unwrap().to_string();
+        editor.update_file_path(new_path.clone());
+        editor.sync_swap().unwrap();
+        assert!(open(&new_path).find_swap().is_none());
+        let mut other = open(&path);
+        assert_eq!(other.recover(false), Ok(1));
+        assert_eq!(text(&mut other), "b\nxcd\n");
+        editor.write_file().unwrap();
+        editor.replace_text(0..0, "a".to_string());
+        editor.sync_swap().unwrap();
+        assert!(open(&path).find_swap().is_none());
+        let mut other = open(&new_path);
+        assert_eq!(other.recover(false), Ok(1));
+        assert_eq!(text(&mut other), "ab\nxcd\n");
+
+        // Changes can't be made to a file which changed since
+        fs::write(&new_path, "changed").unwrap();
+        let mut other = open(&new_path);
+        assert_eq!(other.recover(true), Err(format!("\"{}\" changed after the swap file was written, so it can't be recovered", new_path)));
+        assert_eq!(other.text(), "changed");
+        editor.close_swap();
+
+        // None of the changes are made if one of them doesn't fit in the text
+        let mut swap = SwapFile::create(open(&path).swap_path().unwrap()).unwrap();
+        swap.reset(swap_file::stamp(&path)).unwrap();
+        swap.append_change(0, 2, "a").unwrap();
+        swap.append_change(1, 100, "").unwrap();
+        let mut other = open(&path);
+        assert_eq!(other.recover(true), Err(swap_file::DAMAGED.to_string()));
+        assert_eq!(text(&mut other), "éb\nxcd\n");
+        assert!(other.text_matches());
+        // Nor in the middle of a character
+        swap.reset(swap_file::stamp(&path)).unwrap();
+        swap.append_change(1, 0, "a").unwrap();
+        let mut other = open(&path);
+        assert_eq!(other.recover(true), Err(swap_file::DAMAGED.to_string()));
+        assert_eq!(text(&mut other), "éb\nxcd\n");
+        swap.remove().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn undo_file() {
+        let dir = std::env::temp_dir().join(format!("via-editor-undo-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // Keep the undo files out of the real state directory
+        let open = |path: &str| {
+            let mut editor = Editor::new(path.to_string());
+            editor.state_dir = Some(dir.clone());
+            let _ = editor.read_undo_file();
+            editor
+        };
+        let path = dir.join("file").to_str().unwrap().to_string();
+        fs::write(&path, "hello\n").unwrap();
+        let mut editor = open(&path);
+        editor.replace_text(0..1, String::new());
+        editor.replace_text(1..1, "h".to_string());
+        editor.write_file().unwrap();
+        editor.write_undo_file().unwrap();
+        let mut editor = open(&path);
+        assert_eq!(editor.text(), "ehllo\n");
+        assert!(editor.text_matches());
+        editor.undo().unwrap();
+        assert_eq!(editor.text(), "ello\n");
+        editor.undo().unwrap();
+        assert_eq!(editor.text(), "hello\n");
+        assert!(editor.undo().is_err());
+
+        // Text of the file from before is still there after it is written again
+        editor.replace_text(0..1, "j".to_string());
+        editor.replace_text(5..6, "!\n".to_string());
+        editor.write_file().unwrap();
+        editor.write_undo_file().unwrap();
+        let mut editor = open(&path);
+        assert_eq!(editor.text(), "jello!\n");
+        // Each replacement is a deletion and an insertion
+        for _ in 0..4 {
+            editor.undo().unwrap();
+        }
+        assert_eq!(editor.text(), "hello\n");
+        for _ in 0..4 {
+            editor.redo().unwrap();
+        }
+        editor.write_file().unwrap();
+        editor.write_undo_file().unwrap();
+        let mut editor = open(&path);
+        for _ in 0..4 {
+            editor.undo().unwrap();
+        }
+        assert_eq!(editor.text(), "hello\n");
+        assert!(editor.undo().is_err());
+        // The first changes are on another branch, from the same text
+        editor.later(2).unwrap();
+        assert_eq!(editor.text(), "ehllo\n");
+
+        // The rest of the file doesn't have to be loaded to write the undo file
+        let text = "line\n".repeat(1000);
+        fs::write(&path, &text).unwrap();
+        let mut editor = open(&path);
+        editor.replace_text(0..4, "first".to_string());
+        editor.write_file().unwrap();
+        editor.write_undo_file().unwrap();
+        assert!(!editor.eof_reached);
+        let mut editor = open(&path);
+        assert!(editor.text() == fs::read_to_string(&path).unwrap());
+        editor.undo().unwrap();
+        editor.undo().unwrap();
+        assert!(editor.text() == text);
+
+        // The undo file isn't used once the file changed
+        editor.write_file().unwrap();
+        editor.write_undo_file().unwrap();
+        fs::write(&path, "changed").unwrap();
+        let mut editor = open(&path);
+        assert_eq!(editor.text(), "changed");
+        assert!(editor.undo().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_unloaded() {
+        let path = std::env::temp_dir().join(format!("via-editor-unloaded-test-{}", std::process::id()));
+        // Not even valid UTF-8 at the end, which is only found once it is loaded
+        let text = [b"line\n".repeat(1000), b"\xff end".to_vec()].concat();
+        fs::write(&path, &text).unwrap();
+        let mut editor = Editor::new(path.to_str().unwrap().to_string());
+        editor.add_text("new ".to_string());
+        assert_eq!(editor.count_lines(), 1001);
+        // The rest of the file is written as it is, without being loaded
+        assert_eq!(editor.write_file(), Ok(text.len() + 4));
+        assert!(!editor.eof_reached);
+        assert_eq!(fs::read(&path).unwrap(), [b"new ".to_vec(), text.clone()].concat());
+        assert!(editor.text_matches());
+        editor.add_text("\n".to_string());
+        let appended = std::env::temp_dir().join(format!("via-editor-unloaded-append-test-{}", std::process::id()));
+        fs::write(&appended, "a\n").unwrap();
+        assert_eq!(editor.write_rows(appended.to_str().unwrap(), None, true), Ok(text.len() + 5));
+        assert_eq!(fs::read(&appended).unwrap(), [b"a\nnew \n".to_vec(), text].concat());
+        assert_eq!(editor.count_lines(), 1002);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&appended).unwrap();
+    }
+
+    #[test]
+    fn read_errors() {
+        let path = std::env::temp_dir().join(format!("via-editor-error-test-{}", std::process::id()));
+        fs::write(&path, b"ab\ncd\n\xffef\n").unwrap();
+        let mut editor = Editor::new(path.to_str().unwrap().to_string());
+        assert_eq!(editor.error(), None);
+        editor.goto_last_row();
+        assert_eq!(editor.text(), "ab\ncd\n");
+        assert_eq!(editor.error(), Some("Error reading file: Invalid UTF-8 at byte 6"));
+        assert_eq!(editor.write_file(), Err("Not writing, since the file wasn't read: Error reading file: Invalid UTF-8 at byte 6".to_string()));
+        assert_eq!(fs::read(&path).unwrap(), b"ab\ncd\n\xffef\n");
+        fs::remove_file(&path).unwrap();
+
+        let dir = std::env::temp_dir().to_str().unwrap().to_string();
+        let editor = Editor::new(dir.clone());
+        assert_eq!(editor.error(), Some(format!("\"{}\" is a directory", dir).as_str()));
+        assert_eq!(editor.text(), "");
+    }
+
     #[test]
     fn text_lines() {
         let mut editor = Editor::new(String::new());
         let mut want_str = "abc";
         editor.add_text("abc".to_string());
-        assert_eq!(editor.text_lines(1, 2), want_str);
+        assert_eq!(editor.text_lines(1, 2).to_string(), want_str);
 
         editor = Editor::new(String::new());
         want_str = "abc";
         editor.add_text("abc\n\ncd".to_string());
-        assert_eq!(editor.text_line(1), want_str);
+        assert_eq!(editor.text_line(1).to_string(), want_str);
 
         editor = Editor::new(String::new());
         want_str = "abc";
         editor.add_text("abc\n\ncd".to_string());
-        assert_eq!(editor.text_lines(1, 2), want_str);
+        assert_eq!(editor.text_lines(1, 2).to_string(), want_str);
 
         editor = Editor::new(String::new());
         want_str = "";
         editor.add_text("abc\n\ncd".to_string());
-        assert_eq!(editor.text_line(2), want_str);
+        assert_eq!(editor.text_line(2).to_string(), want_str);
 
         editor = Editor::new(String::new());
         want_str = "\ncd\ne";
         editor.add_text("abc\n\ncd\ne".to_string());
-        assert_eq!(editor.text_lines(2, 5), want_str);
+        assert_eq!(editor.text_lines(2, 5).to_string(), want_str);
+    }
+
+    #[test]
+    fn words() {
+        let mut editor = Editor::new(String::new());
+        editor.add_text("foo.bar(baz) qux
+
+  quux-x".to_string());
+        editor.goto(1, 1);
+        editor.word_forward(1, false);
+        assert_eq!((editor.row(), editor.col()), (1, 4));
+        editor.word_forward(3, false);
+        assert_eq!((editor.row(), editor.col()), (1, 9));
+        editor.word_forward(2, false);
+        assert_eq!((editor.row(), editor.col()), (1, 14));
+        editor.word_forward(1, false);
+        assert_eq!((editor.row(), editor.col()), (2, 1));
+        editor.word_forward(1, false);
+        assert_eq!((editor.row(), editor.col()), (3, 3));
+        editor.word_backward(1, false);
+        assert_eq!((editor.row(), editor.col()), (2, 1));
+        editor.word_backward(1, false);
+        assert_eq!((editor.row(), editor.col()), (1, 14));
+        editor.word_backward(1, true);
+        assert_eq!((editor.row(), editor.col()), (1, 1));
+        editor.word_forward(1, true);
+        assert_eq!((editor.row(), editor.col()), (1, 14));
+
+        editor.goto(1, 1);
+        editor.word_end(1, false);
+        assert_eq!((editor.row(), editor.col()), (1, 3));
+        editor.word_end(1, false);
+        assert_eq!((editor.row(), editor.col()), (1, 4));
+        editor.word_end(1, true);
+        assert_eq!((editor.row(), editor.col()), (1, 12));
+        editor.word_end(2, false);
+        assert_eq!((editor.row(), editor.col()), (3, 6));
+        editor.word_end_backward(1, false);
+        assert_eq!((editor.row(), editor.col()), (2, 1));
+        editor.word_end_backward(1, false);
+        assert_eq!((editor.row(), editor.col()), (1, 16));
+        editor.word_end_backward(2, true);
+        assert_eq!((editor.row(), editor.col()), (1, 1));
+
+        // Can't go past the start or end of the text
+        editor.word_backward(1, false);
+        assert_eq!((editor.row(), editor.col()), (1, 1));
+        editor.goto(3, 3);
+        editor.word_forward(5, false);
+        assert_eq!((editor.row(), editor.col()), (3, 9));
+        editor.word_end(1, true);
+        assert_eq!((editor.row(), editor.col()), (3, 9));
+
+        // Words of graphemes with more than one char
+        editor = Editor::new(String::new());
+        editor.add_text("e\u{301}e\u{301} x".to_string());
+        editor.goto(1, 1);
+        editor.word_forward(1, false);
+        assert_eq!((editor.row(), editor.col()), (1, 4));
+        editor.word_end_backward(1, false);
+        assert_eq!((editor.row(), editor.col()), (1, 2));
+    }
+
+    #[test]
+    fn sentences_and_paragraphs() {
+        let mut editor = Editor::new(String::new());
+        editor.add_text("One. Two (three.) Four\nfive?  Six.\n\n\nSeven.".to_string());
+        editor.goto(1, 1);
+        editor.sentence_forward(1);
+        assert_eq!((editor.row(), editor.col()), (1, 6));
+        editor.sentence_forward(1);
+        assert_eq!((editor.row(), editor.col()), (1, 19));
+        editor.sentence_forward(1);
+        assert_eq!((editor.row(), editor.col()), (2, 8));
+        editor.sentence_forward(1);
+        assert_eq!((editor.row(), editor.col()), (3, 1));
+        editor.sentence_forward(1);
+        assert_eq!((editor.row(), editor.col()), (5, 1));
+        editor.sentence_backward(1);
+        assert_eq!((editor.row(), editor.col()), (3, 1));
+        editor.sentence_backward(2);
+        assert_eq!((editor.row(), editor.col()), (1, 19));
+        editor.goto(1, 12);
+        editor.sentence_backward(1);
+        assert_eq!((editor.row(), editor.col()), (1, 6));
+        editor.sentence_backward(5);
+        assert_eq!((editor.row(), editor.col()), (1, 1));
+
+        editor.paragraph_forward(1);
+        assert_eq!((editor.row(), editor.col()), (3, 1));
+        editor.paragraph_forward(1);
+        assert_eq!((editor.row(), editor.col()), (5, 7));
+        editor.paragraph_backward(1);
+        assert_eq!((editor.row(), editor.col()), (4, 1));
+        editor.paragraph_backward(1);
+        assert_eq!((editor.row(), editor.col()), (1, 1));
+        editor.goto(2, 3);
+        assert_eq!(editor.paragraph_object(1, false), (1, 2));
+        assert_eq!(editor.paragraph_object(1, true), (1, 4));
+        editor.goto(5, 1);
+        assert_eq!(editor.paragraph_object(1, true), (3, 5));
     }
-}
 
-/*
-/// Returns a reader for a file and if a temp file had to be created
-// fn create_reader(file_path: String) -> Result<(BufReader<File>, bool), String> {
-fn create_reader(file_path: String) -> (Result<BufReader<File>, String>, bool) {
-    // Only will work in Linux/MacOS systems
-    let tmp_path;
-    if file_path.starts_with("/") {
-        tmp_path = "/tmp/via/".to_string() + &file_path.replace("/", "%");
-    } else {
-        let curr_dir = env::current_dir().unwrap().to_str().unwrap();
-        tmp_path = "/tmp/via/".to_string() + &curr_dir.replace("/", "%") + &file_path.replace("/", "%");
-    }
-    if Path::new(&file_path).is_file() {
-        // return Ok((BufReader::new(File::open(file_path).unwrap()), false))
-        return (Ok(BufReader::new(File::open(file_path).unwrap())), false);
-    } else if !Path::new(&tmp_path).is_file() {
-        // return Ok((BufReader::new(File::open(tmp_path).unwrap()), true))
-        return (Ok(BufReader::new(File::open(tmp_path).unwrap())), true);
-    }
-    /*
-    let error_message = "File is already being edited and/or last session of Via didn't exit cleanly\n
-        To remove this message, delete ".to_string() + tmp_path.as_str();
-    Err(error_message.to_string())
-    */
-    panic!("File is already being edited and/or last session of Via didn't exit cleanly\n
-    To remove this message, delete {}", tmp_path);
+    #[test]
+    fn find_char() {
+        let mut editor = Editor::new(String::new());
+        editor.add_text("a,b,c,d\n,".to_string());
+        editor.goto(1, 1);
+        assert!(editor.find_char(1, ',', true, false, false));
+        assert_eq!(editor.col(), 2);
+        assert!(editor.find_char(2, ',', true, false, false));
+        assert_eq!(editor.col(), 6);
+        assert!(!editor.find_char(1, ',', true, false, false));
+        assert_eq!(editor.col(), 6);
+        assert!(editor.find_char(1, 'a', false, true, false));
+        assert_eq!(editor.col(), 2);
+        assert!(editor.find_char(1, 'c', true, true, false));
+        assert_eq!(editor.col(), 4);
+        assert!(!editor.find_char(3, ',', false, false, false));
+        assert_eq!(editor.col(), 4);
+
+        // Repeating `t` skips the character right next to the cursor
+        editor.goto(1, 1);
+        assert!(editor.find_char(1, ',', true, true, false));
+        assert_eq!(editor.col(), 1);
+        assert!(editor.find_char(1, ',', true, true, true));
+        assert_eq!(editor.col(), 3);
+        assert!(editor.find_char(1, ',', false, true, false));
+        assert_eq!(editor.col(), 3);
+        assert!(!editor.find_char(1, ',', false, true, true));
+        assert_eq!(editor.col(), 3);
+    }
+
+    #[test]
+    fn text_objects() {
+        let mut editor = Editor::new(String::new());
+        editor.add_text("say  \"hi \\\" there\" (a, (b)) end".to_string());
+        editor.goto(1, 2);
+        assert_eq!(editor.word_object(1, false, false), ((1, 1), (1, 4)));
+        assert_eq!(editor.word_object(2, false, false), ((1, 1), (1, 6)));
+        assert_eq!(editor.word_object(1, false, true), ((1, 1), (1, 6)));
+        assert_eq!(editor.word_object(1, true, true), ((1, 1), (1, 6)));
+        editor.goto(1, 5);
+        assert_eq!(editor.word_object(1, false, true), ((1, 4), (1, 7)));
+        editor.goto(1, 30);
+        assert_eq!(editor.word_object(1, false, true), ((1, 28), (1, 32)));
+
+        editor.goto(1, 1);
+        assert_eq!(editor.quote_object('"', false), Some(((1, 7), (1, 18))));
+        editor.goto(1, 10);
+        assert_eq!(editor.quote_object('"', false), Some(((1, 7), (1, 18))));
+        assert_eq!(editor.quote_object('"', true), Some(((1, 6), (1, 20))));
+        assert_eq!(editor.quote_object('\'', true), None);
+
+        editor.goto(1, 25);
+        assert_eq!(editor.block_object(1, '(', ')', false), Some(((1, 25), (1, 26))));
+        assert_eq!(editor.block_object(1, '(', ')', true), Some(((1, 24), (1, 27))));
+        assert_eq!(editor.block_object(2, '(', ')', false), Some(((1, 21), (1, 27))));
+        editor.goto(1, 27);
+        assert_eq!(editor.block_object(1, '(', ')', false), Some(((1, 21), (1, 27))));
+        assert_eq!(editor.block_object(2, '(', ')', false), None);
+        assert_eq!(editor.block_object(1, '[', ']', false), None);
+
+        // Blocks and tags over several lines
+        editor = Editor::new(String::new());
+        editor.add_text("fn f() {\n    {x}\n}\n<a><b>\n<a>1</a> <br/>2</b></a>".to_string());
+        editor.goto(2, 2);
+        assert_eq!(editor.block_object(1, '{', '}', false), Some(((2, 1), (3, 1))));
+        assert_eq!(editor.block_object(1, '{', '}', true), Some(((1, 8), (3, 2))));
+        editor.goto(5, 11);
+        assert_eq!(editor.tag_object(1, false), Some(((4, 7), (5, 16))));
+        assert_eq!(editor.tag_object(1, true), Some(((4, 4), (5, 20))));
+        assert_eq!(editor.tag_object(2, false), Some(((4, 4), (5, 20))));
+        editor.goto(5, 5);
+        assert_eq!(editor.tag_object(1, false), Some(((5, 4), (5, 5))));
+        editor.goto(5, 22);
+        assert_eq!(editor.tag_object(1, false), Some(((4, 4), (5, 20))));
+        editor.goto(1, 1);
+        assert_eq!(editor.tag_object(1, false), None);
+    }
+
+    #[test]
+    fn search() {
+        let path = std::env::temp_dir().join(format!("via-editor-search-test-{}", std::process::id()));
+        let mut text = "x\n".repeat(3 * SEARCH_LINES);
+        text.push_str("a foo\nfoo b\n");
+        fs::write(&path, &text).unwrap();
+        let mut editor = Editor::new(path.to_str().unwrap().to_string());
+        let foo = Regex::new("foo").unwrap();
+        assert_eq!(editor.search(&foo, true, 1), Some(((3 * SEARCH_LINES + 1, 3), false)));
+        assert_eq!(editor.search(&foo, true, 2), Some(((3 * SEARCH_LINES + 2, 1), false)));
+        editor.goto(3 * SEARCH_LINES + 2, 1);
+        assert_eq!(editor.search(&foo, true, 1), Some(((3 * SEARCH_LINES + 1, 3), true)));
+        assert_eq!(editor.search(&foo, false, 1), Some(((3 * SEARCH_LINES + 1, 3), false)));
+        assert_eq!(editor.search(&Regex::new("bar").unwrap(), true, 1), None);
+        assert_eq!(editor.find_all(&foo, 3 * SEARCH_LINES + 1, 3 * SEARCH_LINES + 2),
+            vec![((3 * SEARCH_LINES + 1, 3), (3 * SEARCH_LINES + 1, 6)), ((3 * SEARCH_LINES + 2, 1), (3 * SEARCH_LINES + 2, 4))]);
+        assert_eq!(editor.find_all(&Regex::new("o*").unwrap(), 3 * SEARCH_LINES + 2, 3 * SEARCH_LINES + 2),
+            vec![((3 * SEARCH_LINES + 2, 1), (3 * SEARCH_LINES + 2, 1)), ((3 * SEARCH_LINES + 2, 2), (3 * SEARCH_LINES + 2, 4)),
+                ((3 * SEARCH_LINES + 2, 4), (3 * SEARCH_LINES + 2, 4)), ((3 * SEARCH_LINES + 2, 5), (3 * SEARCH_LINES + 2, 5)),
+                ((3 * SEARCH_LINES + 2, 6), (3 * SEARCH_LINES + 2, 6))]);
+        editor.goto(1, 1);
+        assert_eq!(editor.search(&Regex::new("x\\nx").unwrap(), false, 1), Some(((3 * SEARCH_LINES - 1, 1), true)));
+        editor.goto(3 * SEARCH_LINES + 2, 1);
+        assert_eq!(editor.search(&Regex::new("x\\n\\a").unwrap(), false, 1), Some(((3 * SEARCH_LINES, 1), false)));
+        assert_eq!(editor.search(&Regex::new("\\<b").unwrap(), true, 1), Some(((3 * SEARCH_LINES + 2, 5), false)));
+        fs::remove_file(&path).unwrap();
+    }
 }
-*/
\ No newline at end of file
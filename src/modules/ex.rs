@@ -0,0 +1,414 @@
+use std::convert::TryFrom;
+
+use super::editor::Editor;
+use super::message::Messages;
+use super::regex::Regex;
+use super::substitute::Substitution;
+
+/// First and last rows (inclusive) a command applies to
+pub(crate) type Rows = (usize, usize);
+
+/// Flag for commands which accept a range
+pub(crate) const RANGE: u8 = 1;
+/// Flag for commands which accept a `!` after their name
+pub(crate) const BANG: u8 = 2;
+
+/// Function carrying out a command
+pub(crate) type Handler = fn(&mut Context, &Args) -> Result<(), String>;
+
+/// Part of the range before a command
+#[derive(Debug, PartialEq)]
+enum Token {
+    /// A line number
+    Line(usize),
+    /// `.`
+    Current,
+    /// `$`
+    Last,
+    /// `%`, short for `1,$`
+    All,
+    /// `'x`
+    Mark(char),
+    /// `/pattern/` (forward) or `?pattern?`
+    Search(String, bool),
+    /// `+n` or `-n`, relative to the address before it (or the current line)
+    Offset(isize),
+    /// `,` or `;` (which also moves to the address before it)
+    Separator(char),
+}
+
+/// A command line split into its range, name, `!` and arguments, such as `.,+3w! file`
+#[derive(Debug, PartialEq)]
+pub(crate) struct Command {
+    range: Vec<Token>,
+    name: String,
+    bang: bool,
+    args: String,
+}
+
+impl Command {
+    /// Split `text` (which is without the `:`) into its parts
+    pub(crate) fn parse(text: &str) -> Result<Command, String> {
+        let (range, rest) = tokenize_range(text)?;
+        let rest = rest.trim_start_matches([' ', ':']);
+        let name_len = match rest.find(|c: char| !c.is_ascii_alphabetic()) {
+            // Commands which aren't letters, such as `&` or `<`, are a single character
+            Some(0) => rest.chars().next().map_or(0, char::len_utf8),
+            Some(len) => len,
+            None => rest.len(),
+        };
+        let (name, rest) = rest.split_at(name_len);
+        let (bang, rest) = match rest.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        Ok(Command {
+            range,
+            name: name.to_string(),
+            bang,
+            args: rest.trim_start().to_string(),
+        })
+    }
+
+    /// Returns the rows the range refers to, or `None` if there is no range. Rows aren't
+    /// checked to exist, but a range going backwards is swapped. `/pattern/` and `?pattern?`
+    /// search from the line before them, with an empty pattern meaning `last_search`
+    fn rows(&self, editor: &mut Editor, last_search: &mut Option<(String, bool)>) -> Result<Option<Rows>, String> {
+        let mut addresses: Vec<usize> = Vec::new();
+        let mut current = editor.row();
+        let mut address: Option<isize> = None;
+        for token in &self.range {
+            let row = match token {
+                Token::Line(row) => *row,
+                Token::Current => current,
+                Token::Last => editor.last_line(),
+                Token::All => {
+                    addresses.extend([1, editor.last_line()]);
+                    continue
+                },
+                Token::Mark(name) => editor.mark(*name).ok_or("E20: Mark not set")?.0,
+                Token::Search(pattern, forward) => {
+                    let from = match address {
+                        Some(row) => usize::try_from(row).map_err(|_| "E16: Invalid range")?,
+                        None => current,
+                    };
+                    search_address(editor, last_search, pattern, *forward, from)?
+                },
+                Token::Offset(offset) => {
+                    address = Some(address.unwrap_or(current as isize) + offset);
+                    continue
+                },
+                Token::Separator(separator) => {
+                    let row = address.take().unwrap_or(current as isize);
+                    let row = usize::try_from(row).map_err(|_| "E16: Invalid range")?;
+                    if *separator == ';' {
+                        current = row;
+                    }
+                    addresses.push(row);
+                    continue
+                },
+            };
+            address = Some(row as isize);
+        }
+        if let Some(row) = address {
+            addresses.push(usize::try_from(row).map_err(|_| "E16: Invalid range")?);
+        } else if let Some(Token::Separator(_)) = self.range.last() {
+            addresses.push(current);
+        }
+        Ok(match addresses[..] {
+            [] => None,
+            [row] => Some((row, row)),
+            [.., first, last] => Some((first.min(last), first.max(last))),
+        })
+    }
+}
+
+/// Split the range at the start of `text` into tokens, returning them and the rest of the text
+fn tokenize_range(text: &str) -> Result<(Vec<Token>, &str), String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start_matches([' ', ':']);
+    loop {
+        rest = rest.trim_start();
+        let mut chars = rest.chars();
+        let token = match chars.next() {
+            Some(c) if c.is_ascii_digit() => {
+                let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                let row = rest[..digits].parse().map_err(|_| "E16: Invalid range")?;
+                rest = &rest[digits..];
+                tokens.push(Token::Line(row));
+                continue
+            },
+            Some('.') => Token::Current,
+            Some('$') => Token::Last,
+            Some('%') => Token::All,
+            Some('\'') => match chars.next() {
+                Some(name) => Token::Mark(name),
+                None => return Err("E20: Mark not set".to_string()),
+            },
+            Some(c @ ('/' | '?')) => {
+                let (pattern, after) = split_at(chars.as_str(), c);
+                chars = after.unwrap_or("").chars();
+                Token::Search(pattern.to_string(), c == '/')
+            },
+            Some(c @ ('+' | '-')) => {
+                let after = chars.as_str();
+                let digits = after.find(|c: char| !c.is_ascii_digit()).unwrap_or(after.len());
+                let num: isize = if digits == 0 {1} else {after[..digits].parse().map_err(|_| "E16: Invalid range")?};
+                rest = &after[digits..];
+                tokens.push(Token::Offset(if c == '+' {num} else {-num}));
+                continue
+            },
+            Some(c @ (',' | ';')) => Token::Separator(c),
+            _ => return Ok((tokens, rest)),
+        };
+        tokens.push(token);
+        rest = chars.as_str();
+    }
+}
+
+/// Returns the row of the first match of `pattern` after row `from`, or the last one
+/// before it if not `forward`, leaving the cursor where it was
+fn search_address(editor: &mut Editor, last_search: &mut Option<(String, bool)>, pattern: &str, forward: bool, from: usize) -> Result<usize, String> {
+    if !pattern.is_empty() {
+        *last_search = Some((pattern.to_string(), forward));
+    }
+    let pattern = match last_search {
+        Some((pattern, _)) => pattern.clone(),
+        None => return Err("E35: No previous regular expression".to_string()),
+    };
+    let regex = Regex::new(&pattern)?;
+    let cursor = (editor.row(), editor.col());
+    editor.goto(from, 1);
+    if forward {
+        editor.goto_last_col();
+    }
+    let found = editor.search(&regex, forward, 1);
+    editor.goto(cursor.0, cursor.1);
+    match found {
+        Some(((row, _), _)) => Ok(row),
+        None => Err(format!("E486: Pattern not found: {}", pattern)),
+    }
+}
+
+/// Split `text` at the first `delimiter` which isn't escaped with a backslash, returning
+/// the text before it and (if there is a delimiter) the text after it
+pub(crate) fn split_at(text: &str, delimiter: char) -> (&str, Option<&str>) {
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == delimiter {
+            return (&text[..i], Some(&text[i + c.len_utf8()..]))
+        }
+    }
+    (text, None)
+}
+
+/// What a command is carried out on, and what it leaves for Via to do afterwards
+pub(crate) struct Context<'a> {
+    pub(crate) editor: &'a mut Editor,
+    /// Pattern last searched for and whether it was searched forward
+    pub(crate) last_search: &'a mut Option<(String, bool)>,
+    /// Text last yanked or deleted, and how (see `Via::register_kind`)
+    pub(crate) register: &'a mut String,
+    pub(crate) register_kind: &'a mut char,
+    /// Messages and output to show
+    pub(crate) messages: &'a mut Messages,
+    /// Whether to quit Via
+    pub(crate) quit: bool,
+    /// Mode to switch to, if any
+    pub(crate) mode: Option<usize>,
+    /// Substitution waiting for its matches to be confirmed
+    pub(crate) substitution: Option<Substitution>,
+}
+
+impl<'a> Context<'a> {
+    pub(crate) fn new(editor: &'a mut Editor, last_search: &'a mut Option<(String, bool)>, register: &'a mut String, register_kind: &'a mut char, messages: &'a mut Messages) -> Context<'a> {
+        Context {
+            editor,
+            last_search,
+            register,
+            register_kind,
+            messages,
+            quit: false,
+            mode: None,
+            substitution: None,
+        }
+    }
+}
+
+/// Arguments a command is called with
+pub(crate) struct Args<'a> {
+    /// Rows given as range, or else the current row
+    pub(crate) rows: Rows,
+    /// Whether a range was given
+    pub(crate) range: bool,
+    /// Whether there was a `!` after the name
+    pub(crate) bang: bool,
+    /// Text after the name
+    pub(crate) arg: &'a str,
+}
+
+/// A command which can be called by its name
+struct Entry {
+    name: String,
+    /// Shortest abbreviation of `name` allowed
+    min_len: usize,
+    flags: u8,
+    handler: Handler,
+}
+
+/// Commands which can be run from the command line
+pub(crate) struct Registry {
+    entries: Vec<Entry>,
+}
+
+impl Registry {
+    pub(crate) fn new() -> Registry {
+        Registry {entries: Vec::new()}
+    }
+
+    /// Add a command. `name` marks the optional part in brackets, like Vim's documentation:
+    /// `w[rite]` can be called as `:w`, `:wr` up to `:write`. If several commands match an
+    /// abbreviation, the one added first is called
+    pub(crate) fn add(&mut self, name: &str, flags: u8, handler: Handler) {
+        let (name, min_len) = match name.find('[') {
+            Some(min_len) => {
+                let full = format!("{}{}", &name[..min_len], name[min_len + 1..].trim_end_matches(']'));
+                (full, min_len)
+            },
+            None => (name.to_string(), name.len()),
+        };
+        self.entries.push(Entry {name, min_len, flags, handler});
+    }
+
+    /// Returns the command called `name`, which may be abbreviated
+    fn find(&self, name: &str) -> Option<&Entry> {
+        self.entries.iter().find(|entry| name.len() >= entry.min_len && entry.name.starts_with(name))
+    }
+
+    /// Parse and carry out `text` (which is without the `:`). A range without a command
+    /// moves to its last row
+    pub(crate) fn execute(&self, text: &str, context: &mut Context) -> Result<(), String> {
+        let command = Command::parse(text)?;
+        let rows = command.rows(context.editor, context.last_search)?;
+        if command.name.is_empty() {
+            if let Some((_, last)) = rows {
+                let last_line = context.editor.last_line();
+                context.editor.goto(last.clamp(1, last_line), 1);
+            }
+            return Ok(())
+        }
+        let entry = match self.find(&command.name) {
+            Some(entry) => entry,
+            None => return Err(format!("E492: Not an editor command: {}", text.trim())),
+        };
+        if rows.is_some() && entry.flags & RANGE == 0 {
+            return Err("E481: No range allowed".to_string())
+        } else if command.bang && entry.flags & BANG == 0 {
+            return Err("E477: No ! allowed".to_string())
+        }
+        if let Some((first, last)) = rows {
+            if first == 0 || !context.editor.has_line(last) {
+                return Err("E16: Invalid range".to_string())
+            }
+        }
+        let args = Args {
+            rows: rows.unwrap_or((context.editor.row(), context.editor.row())),
+            range: rows.is_some(),
+            bang: command.bang,
+            arg: &command.args,
+        };
+        (entry.handler)(context, &args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(text: &str, editor: &mut Editor) -> Result<Option<Rows>, String> {
+        Command::parse(text)?.rows(editor, &mut None)
+    }
+
+    #[test]
+    fn parse() {
+        let command = Command::parse(".,+3w! file").unwrap();
+        assert_eq!(command.range, vec![Token::Current, Token::Separator(','), Token::Offset(3)]);
+        assert_eq!((command.name.as_str(), command.bang, command.args.as_str()), ("w", true, "file"));
+        let command = Command::parse(":'a;/b\\/c/-2 s#x#y#").unwrap();
+        assert_eq!(command.range, vec![Token::Mark('a'), Token::Separator(';'), Token::Search("b\\/c".to_string(), true), Token::Offset(-2)]);
+        assert_eq!((command.name.as_str(), command.args.as_str()), ("s", "#x#y#"));
+        let command = Command::parse("w !cat").unwrap();
+        assert_eq!((command.name.as_str(), command.bang, command.args.as_str()), ("w", false, "!cat"));
+        let command = Command::parse("%&&").unwrap();
+        assert_eq!((command.range, command.name.as_str(), command.args.as_str()), (vec![Token::All], "&", "&"));
+        assert_eq!(Command::parse("10").unwrap().range, vec![Token::Line(10)]);
+        assert_eq!(Command::parse("'").err(), Some("E20: Mark not set".to_string()));
+    }
+
+    #[test]
+    fn ranges() {
+        let mut editor = Editor::new(String::new());
+        editor.add_text("a\nb\nc\nd\n".to_string());
+        editor.goto(2, 1);
+        editor.set_mark('<', 3, 1);
+        editor.set_mark('>', 4, 1);
+        assert_eq!(rows("s/a/b/", &mut editor), Ok(None));
+        assert_eq!(rows("%s/a/b/", &mut editor), Ok(Some((1, 4))));
+        assert_eq!(rows(".,$s", &mut editor), Ok(Some((2, 4))));
+        assert_eq!(rows("3", &mut editor), Ok(Some((3, 3))));
+        assert_eq!(rows("3,1 s", &mut editor), Ok(Some((1, 3))));
+        assert_eq!(rows(",3s", &mut editor), Ok(Some((2, 3))));
+        assert_eq!(rows("'<,'>s", &mut editor), Ok(Some((3, 4))));
+        assert_eq!(rows("'a,'>s", &mut editor), Err("E20: Mark not set".to_string()));
+        assert_eq!(rows("+,+2", &mut editor), Ok(Some((3, 4))));
+        assert_eq!(rows("$-3", &mut editor), Ok(Some((1, 1))));
+        assert_eq!(rows("-5", &mut editor), Err("E16: Invalid range".to_string()));
+        assert_eq!(rows("3;+1", &mut editor), Ok(Some((3, 4))));
+        assert_eq!(rows("3,+1", &mut editor), Ok(Some((3, 3))));
+        assert_eq!(rows("/c/", &mut editor), Ok(Some((3, 3))));
+        assert_eq!(rows("/b/", &mut editor), Ok(Some((2, 2))));
+        assert_eq!(rows("?a?,/d/+0", &mut editor), Ok(Some((1, 4))));
+        assert_eq!(rows("/x/", &mut editor), Err("E486: Pattern not found: x".to_string()));
+        assert_eq!((editor.row(), editor.col()), (2, 1));
+        assert_eq!(rows("wq", &mut editor), Ok(None));
+    }
+
+    #[test]
+    fn execute() {
+        let mut registry = Registry::new();
+        registry.add("wr[ite]", BANG, |context, args| {
+            context.messages.print(format!("write {} {}", args.bang, args.arg));
+            Ok(())
+        });
+        registry.add("w", 0, |_, _| Err("not reached".to_string()));
+        registry.add("p[rint]", RANGE, |context, args| {
+            context.messages.print(format!("print {:?} {}", args.rows, args.range));
+            Ok(())
+        });
+        let mut editor = Editor::new(String::new());
+        editor.add_text("a\nb\nc".to_string());
+        editor.goto(2, 1);
+        let (mut last_search, mut register, mut register_kind, mut messages) = (None, String::new(), 'v', Messages::new());
+        let mut context = Context::new(&mut editor, &mut last_search, &mut register, &mut register_kind, &mut messages);
+        assert_eq!(registry.execute("writ! file", &mut context), Ok(()));
+        assert_eq!(registry.execute("wr", &mut context), Ok(()));
+        assert_eq!(registry.execute("w", &mut context), Err("not reached".to_string()));
+        assert_eq!(registry.execute("p", &mut context), Ok(()));
+        assert_eq!(registry.execute("1,3print", &mut context), Ok(()));
+        let output: Vec<&str> = context.messages.current().iter().map(|message| message.text.as_str()).collect();
+        assert_eq!(output, vec!["write true file", "write false ", "print (2, 2) false", "print (1, 3) true"]);
+        assert_eq!(registry.execute("writes", &mut context), Err("E492: Not an editor command: writes".to_string()));
+        assert_eq!(registry.execute("1wr", &mut context), Err("E481: No range allowed".to_string()));
+        assert_eq!(registry.execute("p!", &mut context), Err("E477: No ! allowed".to_string()));
+        assert_eq!(registry.execute("1,4p", &mut context), Err("E16: Invalid range".to_string()));
+        assert_eq!(registry.execute("0p", &mut context), Err("E16: Invalid range".to_string()));
+        assert_eq!(registry.execute("1", &mut context), Ok(()));
+        assert_eq!(context.editor.row(), 1);
+        assert_eq!(registry.execute("99", &mut context), Ok(()));
+        assert_eq!(context.editor.row(), 3);
+    }
+}
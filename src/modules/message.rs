@@ -0,0 +1,110 @@
+/// Number of messages kept for `:messages`
+const HISTORY_LEN: usize = 200;
+
+/// How important a message is, which decides how it is shown
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Message {
+    pub(crate) severity: Severity,
+    pub(crate) text: String,
+}
+
+/// Messages shown at the bottom of the terminal until the next key is typed,
+/// and the history of those which were shown
+pub(crate) struct Messages {
+    current: Vec<Message>,
+    history: Vec<Message>,
+}
+
+impl Messages {
+    pub(crate) fn new() -> Messages {
+        Messages {current: Vec::new(), history: Vec::new()}
+    }
+
+    /// Show `text` and keep it in the history. Nothing is shown if it is empty
+    pub(crate) fn add(&mut self, severity: Severity, text: String) {
+        if text.is_empty() {
+            return
+        }
+        let message = Message {severity, text};
+        if self.history.len() == HISTORY_LEN {
+            self.history.remove(0);
+        }
+        self.history.push(message.clone());
+        self.current.push(message);
+    }
+
+    pub(crate) fn info(&mut self, text: String) {
+        self.add(Severity::Info, text);
+    }
+
+    pub(crate) fn warn(&mut self, text: String) {
+        self.add(Severity::Warning, text);
+    }
+
+    pub(crate) fn error(&mut self, text: String) {
+        self.add(Severity::Error, text);
+    }
+
+    /// Show `text` without keeping it in the history, such as the output of `:print`
+    pub(crate) fn print(&mut self, text: String) {
+        self.current.push(Message {severity: Severity::Info, text});
+    }
+
+    /// Returns the messages being shown, oldest first
+    pub(crate) fn current(&self) -> &[Message] {
+        &self.current
+    }
+
+    /// Stop showing the current messages
+    pub(crate) fn clear(&mut self) {
+        self.current.clear();
+    }
+
+    /// Show the messages shown so far again, for `:messages`
+    pub(crate) fn show_history(&mut self) {
+        self.current.extend(self.history.iter().cloned());
+    }
+
+    /// Returns the messages shown so far, oldest first
+    pub(crate) fn history(&self) -> &[Message] {
+        &self.history
+    }
+
+    pub(crate) fn clear_history(&mut self) {
+        self.history.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history() {
+        let mut messages = Messages::new();
+        messages.info("a".to_string());
+        messages.print("b".to_string());
+        messages.error(String::new());
+        messages.error("c".to_string());
+        let texts = |messages: &[Message]| messages.iter().map(|message| message.text.clone()).collect::<Vec<_>>();
+        assert_eq!(texts(messages.current()), vec!["a", "b", "c"]);
+        assert_eq!(texts(messages.history()), vec!["a", "c"]);
+        assert_eq!(messages.history()[1].severity, Severity::Error);
+        messages.clear();
+        assert!(messages.current().is_empty());
+        for i in 0..HISTORY_LEN {
+            messages.warn(i.to_string());
+        }
+        assert_eq!(messages.history().len(), HISTORY_LEN);
+        assert_eq!(messages.history()[0].text, "0");
+        messages.clear_history();
+        assert!(messages.history().is_empty());
+    }
+}
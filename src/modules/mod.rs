@@ -1,3 +1,13 @@
 pub mod via;
 pub(crate) mod editor;
-pub(crate) mod piece_table;
\ No newline at end of file
+pub(crate) mod ex;
+pub(crate) mod message;
+pub(crate) mod motion;
+pub(crate) mod original_buffer;
+pub(crate) mod piece_table;
+pub(crate) mod piece_tree;
+pub(crate) mod regex;
+pub(crate) mod save;
+pub(crate) mod substitute;
+pub(crate) mod swap_file;
+pub(crate) mod undo_file;
\ No newline at end of file
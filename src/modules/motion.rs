@@ -0,0 +1,460 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+use super::editor::Editor;
+
+/// A motion, which moves the cursor or gives the range of text an operator acts on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Motion {
+    /// `h`
+    Left,
+    /// `l`
+    Right,
+    /// `k`
+    Up,
+    /// `j`
+    Down,
+    /// `0`
+    LineStart,
+    /// `$`
+    LineEnd,
+    /// Enter
+    NextLine,
+    /// `gg`
+    FirstLine,
+    /// `G`
+    LastLine,
+    /// `w`, or `W` if true
+    WordForward(bool),
+    /// `b`, or `B` if true
+    WordBackward(bool),
+    /// `e`, or `E` if true
+    WordEnd(bool),
+    /// `ge`, or `gE` if true
+    WordEndBackward(bool),
+    /// `)`
+    SentenceForward,
+    /// `(`
+    SentenceBackward,
+    /// `}`
+    ParagraphForward,
+    /// `{`
+    ParagraphBackward,
+    /// `f`, `F`, `t` or `T` followed by `target`. `repeat` if repeated with `;` or `,`
+    FindChar {target: char, forward: bool, till: bool, repeat: bool},
+    /// The current line, for an operator typed twice like `dd`
+    Line,
+    /// A text object, like `iw`, or `aw` if true
+    Object(TextObject, bool),
+}
+
+/// A text object, which is a region of text around the cursor
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TextObject {
+    /// `w`, or `W` if true
+    Word(bool),
+    /// `"`, `'` or `` ` ``
+    Quote(char),
+    /// A pair of brackets, like `(` and `)`
+    Block(char, char),
+    /// `p`
+    Paragraph,
+    /// `t`
+    Tag,
+}
+
+/// Text an operator acts on, from `start` (inclusive) to `end` (exclusive) as (row, col).
+/// If `linewise`, all of the rows from `start.0` to `end.0` (inclusive) instead
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Range {
+    pub(crate) start: (usize, usize),
+    pub(crate) end: (usize, usize),
+    pub(crate) linewise: bool,
+}
+
+impl Motion {
+    /// Whether an operator acts on whole lines for this motion
+    fn is_linewise(&self) -> bool {
+        matches!(self, Motion::Up | Motion::Down | Motion::NextLine | Motion::FirstLine | Motion::LastLine | Motion::Line | Motion::Object(TextObject::Paragraph, _))
+    }
+
+    /// Whether an operator also acts on the character the motion moves to
+    fn is_inclusive(&self) -> bool {
+        matches!(self, Motion::LineEnd | Motion::WordEnd(_) | Motion::WordEndBackward(_) | Motion::FindChar {forward: true, ..})
+    }
+
+    /// Move the cursor of `editor`. `count` is the number typed before the motion, if any.
+    /// Returns false if the motion failed, like `f` when the character isn't found
+    pub(crate) fn apply(&self, editor: &mut Editor, count: Option<usize>) -> bool {
+        let num = count.unwrap_or(1);
+        match self {
+            Motion::Left => editor.left(num),
+            Motion::Right => editor.right(num),
+            Motion::Up => editor.up(num),
+            Motion::Down => editor.down(num),
+            Motion::Line => editor.down(num - 1),
+            Motion::LineStart => editor.goto_col(1),
+            Motion::LineEnd => {
+                editor.down(num - 1);
+                editor.goto_last_col();
+                editor.left(1);
+            },
+            Motion::NextLine => {
+                let row = editor.row();
+                editor.down(num);
+                if editor.row() != row {
+                    editor.goto_col(1);
+                }
+            },
+            Motion::FirstLine => editor.goto(num, 1),
+            Motion::LastLine => match count {
+                Some(row) => editor.goto(row, 1),
+                None => editor.goto_last_row(),
+            },
+            Motion::WordForward(bigword) => editor.word_forward(num, *bigword),
+            Motion::WordBackward(bigword) => editor.word_backward(num, *bigword),
+            Motion::WordEnd(bigword) => editor.word_end(num, *bigword),
+            Motion::WordEndBackward(bigword) => editor.word_end_backward(num, *bigword),
+            Motion::SentenceForward => editor.sentence_forward(num),
+            Motion::SentenceBackward => editor.sentence_backward(num),
+            Motion::ParagraphForward => editor.paragraph_forward(num),
+            Motion::ParagraphBackward => editor.paragraph_backward(num),
+            Motion::FindChar {target, forward, till, repeat} => return editor.find_char(num, *target, *forward, *till, *repeat),
+            Motion::Object(..) => {},
+        }
+        true
+    }
+
+    /// Returns the text `operator` acts on for this motion, or `None` if there is none
+    pub(crate) fn range(&self, editor: &mut Editor, count: Option<usize>, operator: char) -> Option<Range> {
+        let cursor = (editor.row(), editor.col());
+        let num = count.unwrap_or(1);
+        let (start, mut end) = match self {
+            Motion::Object(TextObject::Paragraph, around) => {
+                let (first_row, last_row) = editor.paragraph_object(num, *around);
+                ((first_row, 1), (last_row, 1))
+            },
+            Motion::Object(TextObject::Word(bigword), around) => editor.word_object(num, *bigword, *around),
+            Motion::Object(TextObject::Quote(quote), around) => editor.quote_object(*quote, *around)?,
+            Motion::Object(TextObject::Block(open, close), around) => editor.block_object(num, *open, *close, *around)?,
+            Motion::Object(TextObject::Tag, around) => editor.tag_object(num, *around)?,
+            _ => {
+                if !self.apply(editor, count) {
+                    return None
+                }
+                let target = (editor.row(), editor.col());
+                editor.goto(cursor.0, cursor.1);
+                (cursor.min(target), cursor.max(target))
+            },
+        };
+        if self.is_linewise() {
+            return Some(Range {start, end, linewise: true});
+        }
+        if self.is_inclusive() && end.1 <= editor.line_len(end.0) {
+            end.1 += 1;
+        }
+        if let Motion::WordForward(_) = self {
+            // Like Vim, an operator stops at the end of the line of the last word moved over
+            self.apply(editor, Some(num - 1));
+            let row = editor.row();
+            editor.goto(cursor.0, cursor.1);
+            if row < end.0 {
+                end = (row, editor.line_len(row) + 1);
+            }
+        }
+        if let (Motion::WordForward(_), 'c') = (self, operator) {
+            // Like Vim, `cw` on a word only changes the word, not the blanks after it
+            let text = editor.text_range(start, end);
+            if !text.starts_with(char::is_whitespace) {
+                let trimmed = text.trim_end().len();
+                return Some(Range {start, end: position_after(start, &text[..trimmed]), linewise: false});
+            }
+        }
+        if !self.is_inclusive() && end.1 == 1 && end.0 > start.0 {
+            // An exclusive motion to the start of a line stops at the end of the line before it,
+            // and acts on whole lines if it started before the first non-blank character
+            end = (end.0 - 1, editor.line_len(end.0 - 1) + 1);
+            if editor.text_range((start.0, 1), start).trim().is_empty() {
+                return Some(Range {start, end, linewise: true});
+            }
+        }
+        Some(Range {start, end, linewise: false}).filter(|range| range.start != range.end)
+    }
+}
+
+/// Returns the position (row, col) just after `text` if it starts at `start`
+fn position_after(start: (usize, usize), text: &str) -> (usize, usize) {
+    let last_line = text.rsplit('\n').next().unwrap_or_default();
+    let graphemes = last_line.graphemes(true).count();
+    match text.matches('\n').count() {
+        0 => (start.0, start.1 + graphemes),
+        newlines => (start.0 + newlines, graphemes + 1),
+    }
+}
+
+/// A complete normal mode command
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Command {
+    /// Move the cursor
+    Move(Motion, Option<usize>),
+    /// Apply an operator (`d`, `c`, `y`, `~`, `>` or `<`) to the text covered by a motion
+    Operate(char, Motion, Option<usize>),
+    /// Any other keys, to be handled by the caller
+    Other(String, Option<usize>),
+}
+
+/// Parses the keys of normal mode commands: `[count] [operator [count]] motion`,
+/// or `[count] keys` for commands which aren't motions
+pub(crate) struct Parser {
+    /// Count typed before the operator (or before the motion if there's no operator)
+    count: Option<usize>,
+    /// Operator typed, if any
+    operator: Option<char>,
+    /// Count typed after the operator
+    motion_count: Option<usize>,
+    /// Key typed which needs another key to complete, like the `g` in `gg`
+    prefix: Option<char>,
+    /// Last character searched for with `f`, `F`, `t` or `T` (target, forward, till),
+    /// to be repeated with `;` and `,`
+    last_find: Option<(char, bool, bool)>,
+}
+
+impl Parser {
+    /// Initialize a parser with no keys typed
+    pub(crate) fn new() -> Parser {
+        Parser {count: None, operator: None, motion_count: None, prefix: None, last_find: None}
+    }
+
+    /// Forget all keys typed so far
+    pub(crate) fn clear(&mut self) {
+        self.count = None;
+        self.operator = None;
+        self.motion_count = None;
+        self.prefix = None;
+    }
+
+    /// Returns whether keys were typed which aren't a complete command yet
+    pub(crate) fn is_pending(&self) -> bool {
+        self.count.is_some() || self.operator.is_some() || self.prefix.is_some()
+    }
+
+    /// Add the key `c`. Returns the command if it is complete. If not `operators`
+    /// (such as in visual mode), operator keys are returned as `Command::Other`,
+    /// and text objects are returned as `Command::Move`
+    pub(crate) fn push(&mut self, c: char, operators: bool) -> Option<Command> {
+        if let Some(prefix) = self.prefix.take() {
+            let motion = match (prefix, c) {
+                ('f', _) | ('F', _) | ('t', _) | ('T', _) => {
+                    let (forward, till) = (prefix.is_lowercase(), prefix.eq_ignore_ascii_case(&'t'));
+                    self.last_find = Some((c, forward, till));
+                    Motion::FindChar {target: c, forward, till, repeat: false}
+                },
+                ('g', 'g') => Motion::FirstLine,
+                ('g', 'e') | ('g', 'E') => Motion::WordEndBackward(c == 'E'),
+                ('i', _) | ('a', _) => {
+                    let object = match c {
+                        'w' | 'W' => TextObject::Word(c == 'W'),
+                        '"' | '\'' | '`' => TextObject::Quote(c),
+                        '(' | ')' | 'b' => TextObject::Block('(', ')'),
+                        '[' | ']' => TextObject::Block('[', ']'),
+                        '{' | '}' | 'B' => TextObject::Block('{', '}'),
+                        '<' | '>' => TextObject::Block('<', '>'),
+                        'p' => TextObject::Paragraph,
+                        't' => TextObject::Tag,
+                        _ => {
+                            self.clear();
+                            return None
+                        },
+                    };
+                    Motion::Object(object, prefix == 'a')
+                },
+                ('g', _) if self.operator.is_none() => return Some(self.finish_other(format!("g{}", c))),
+                _ => {
+                    self.clear();
+                    return None
+                },
+            };
+            return Some(self.finish(motion));
+        }
+
+        let count = if self.operator.is_some() {&mut self.motion_count} else {&mut self.count};
+        if let Some(digit) = c.to_digit(10).filter(|digit| *digit != 0 || count.is_some()) {
+            *count = Some(count.unwrap_or(0).saturating_mul(10).saturating_add(digit as usize));
+            return None
+        }
+
+        let motion = match c {
+            'h' => Motion::Left,
+            'l' => Motion::Right,
+            'k' => Motion::Up,
+            'j' => Motion::Down,
+            '0' => Motion::LineStart,
+            '$' => Motion::LineEnd,
+            '\n' => Motion::NextLine,
+            'G' => Motion::LastLine,
+            'w' | 'W' => Motion::WordForward(c == 'W'),
+            'b' | 'B' => Motion::WordBackward(c == 'B'),
+            'e' | 'E' => Motion::WordEnd(c == 'E'),
+            ')' => Motion::SentenceForward,
+            '(' => Motion::SentenceBackward,
+            '}' => Motion::ParagraphForward,
+            '{' => Motion::ParagraphBackward,
+            ';' | ',' => match self.last_find {
+                Some((target, forward, till)) => Motion::FindChar {target, forward: forward == (c == ';'), till, repeat: true},
+                None => {
+                    self.clear();
+                    return None
+                },
+            },
+            'g' | 'f' | 'F' | 't' | 'T' => {
+                self.prefix = Some(c);
+                return None
+            },
+            'i' | 'a' if self.operator.is_some() || !operators => {
+                self.prefix = Some(c);
+                return None
+            },
+            _ if self.operator == Some(c) => Motion::Line,
+            'd' | 'c' | 'y' | '>' | '<' if operators && self.operator.is_none() => {
+                self.operator = Some(c);
+                return None
+            },
+            'x' | 'X' | 'D' | 'C' | 'Y' if operators && self.operator.is_none() => {
+                let (operator, motion) = match c {
+                    'x' => ('d', Motion::Right),
+                    'X' => ('d', Motion::Left),
+                    'D' => ('d', Motion::LineEnd),
+                    'C' => ('c', Motion::LineEnd),
+                    _ => ('y', Motion::Line),
+                };
+                self.operator = Some(operator);
+                motion
+            },
+            _ if self.operator.is_some() => {
+                self.clear();
+                return None
+            },
+            _ => return Some(self.finish_other(c.to_string())),
+        };
+        Some(self.finish(motion))
+    }
+
+    /// Returns the command for `motion` with the keys typed before it, and clear them
+    fn finish(&mut self, motion: Motion) -> Command {
+        let count = match (self.count, self.motion_count) {
+            (None, None) => None,
+            (count, motion_count) => Some(count.unwrap_or(1).saturating_mul(motion_count.unwrap_or(1))),
+        };
+        let command = match self.operator {
+            Some(operator) => Command::Operate(operator, motion, count),
+            None => Command::Move(motion, count),
+        };
+        self.clear();
+        command
+    }
+
+    /// Returns the command for the other `keys` with the count typed before them, and clear it
+    fn finish_other(&mut self, keys: String) -> Command {
+        let command = Command::Other(keys, self.count);
+        self.clear();
+        command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the command typed with `keys`, if complete
+    fn parse(keys: &str) -> Option<Command> {
+        let mut parser = Parser::new();
+        let mut command = None;
+        for c in keys.chars() {
+            assert!(command.is_none(), "{} completed early", keys);
+            command = parser.push(c, true);
+        }
+        command
+    }
+
+    #[test]
+    fn parser() {
+        assert_eq!(parse("j"), Some(Command::Move(Motion::Down, None)));
+        assert_eq!(parse("10j"), Some(Command::Move(Motion::Down, Some(10))));
+        assert_eq!(parse("0"), Some(Command::Move(Motion::LineStart, None)));
+        assert_eq!(parse("d2w"), Some(Command::Operate('d', Motion::WordForward(false), Some(2))));
+        assert_eq!(parse("2d3W"), Some(Command::Operate('d', Motion::WordForward(true), Some(6))));
+        assert_eq!(parse("c$"), Some(Command::Operate('c', Motion::LineEnd, None)));
+        assert_eq!(parse("y}"), Some(Command::Operate('y', Motion::ParagraphForward, None)));
+        assert_eq!(parse("3dd"), Some(Command::Operate('d', Motion::Line, Some(3))));
+        assert_eq!(parse("dG"), Some(Command::Operate('d', Motion::LastLine, None)));
+        assert_eq!(parse(">ip"), Some(Command::Operate('>', Motion::Object(TextObject::Paragraph, false), None)));
+        assert_eq!(parse("dap"), Some(Command::Operate('d', Motion::Object(TextObject::Paragraph, true), None)));
+        assert_eq!(parse("gg"), Some(Command::Move(Motion::FirstLine, None)));
+        assert_eq!(parse("c2i("), Some(Command::Operate('c', Motion::Object(TextObject::Block('(', ')'), false), Some(2))));
+        assert_eq!(parse("yaW"), Some(Command::Operate('y', Motion::Object(TextObject::Word(true), true), None)));
+        assert_eq!(parse("dix"), None);
+        assert_eq!(parse("2dtx"), Some(Command::Operate('d', Motion::FindChar {target: 'x', forward: true, till: true, repeat: false}, Some(2))));
+        assert_eq!(parse("Fg"), Some(Command::Move(Motion::FindChar {target: 'g', forward: false, till: false, repeat: false}, None)));
+        assert_eq!(parse(";"), None);
+        assert_eq!(parse("3ge"), Some(Command::Move(Motion::WordEndBackward(false), Some(3))));
+        assert_eq!(parse("dgE"), Some(Command::Operate('d', Motion::WordEndBackward(true), None)));
+        assert_eq!(parse("c2("), Some(Command::Operate('c', Motion::SentenceBackward, Some(2))));
+        assert_eq!(parse("5x"), Some(Command::Operate('d', Motion::Right, Some(5))));
+        assert_eq!(parse("g-"), Some(Command::Other("g-".to_string(), None)));
+        assert_eq!(parse("3p"), Some(Command::Other("p".to_string(), Some(3))));
+        assert_eq!(parse("dq"), None);
+        assert_eq!(parse("d"), None);
+
+        let mut parser = Parser::new();
+        assert_eq!(parser.push('d', false), Some(Command::Other("d".to_string(), None)));
+        assert_eq!(parser.push('d', true), None);
+        assert!(parser.is_pending());
+        parser.clear();
+        assert_eq!(parser.push('i', false), None);
+        assert_eq!(parser.push('t', false), Some(Command::Move(Motion::Object(TextObject::Tag, false), None)));
+        assert_eq!(parser.push('i', true), Some(Command::Other("i".to_string(), None)));
+        assert_eq!(parser.push('d', true), None);
+        parser.clear();
+        assert!(!parser.is_pending());
+
+        // `;` and `,` repeat the last `f`, `F`, `t` or `T`
+        assert_eq!(parser.push('T', true), None);
+        assert_eq!(parser.push('(', true), Some(Command::Move(Motion::FindChar {target: '(', forward: false, till: true, repeat: false}, None)));
+        assert_eq!(parser.push(';', true), Some(Command::Move(Motion::FindChar {target: '(', forward: false, till: true, repeat: true}, None)));
+        assert_eq!(parser.push('d', true), None);
+        assert_eq!(parser.push(',', true), Some(Command::Operate('d', Motion::FindChar {target: '(', forward: true, till: true, repeat: true}, None)));
+    }
+
+    #[test]
+    fn ranges() {
+        let mut editor = Editor::new(String::new());
+        editor.add_text("one two\n  three\n\nfour".to_string());
+        editor.goto(1, 5);
+        assert_eq!(Motion::WordForward(false).range(&mut editor, None, 'd'), Some(Range {start: (1, 5), end: (1, 8), linewise: false}));
+        assert_eq!(Motion::WordForward(false).range(&mut editor, None, 'c'), Some(Range {start: (1, 5), end: (1, 8), linewise: false}));
+        assert_eq!((editor.row(), editor.col()), (1, 5));
+        assert_eq!(Motion::LineEnd.range(&mut editor, None, 'd'), Some(Range {start: (1, 5), end: (1, 8), linewise: false}));
+        assert_eq!(Motion::Left.range(&mut editor, Some(2), 'd'), Some(Range {start: (1, 3), end: (1, 5), linewise: false}));
+        assert_eq!(Motion::FindChar {target: 'o', forward: true, till: false, repeat: false}.range(&mut editor, None, 'd'), Some(Range {start: (1, 5), end: (1, 8), linewise: false}));
+        assert_eq!(Motion::FindChar {target: 'o', forward: false, till: true, repeat: false}.range(&mut editor, None, 'd'), Some(Range {start: (1, 2), end: (1, 5), linewise: false}));
+        assert_eq!(Motion::FindChar {target: 'x', forward: true, till: false, repeat: false}.range(&mut editor, None, 'd'), None);
+        assert_eq!(Motion::WordEnd(false).range(&mut editor, None, 'd'), Some(Range {start: (1, 5), end: (1, 8), linewise: false}));
+        assert_eq!(Motion::WordBackward(false).range(&mut editor, None, 'd'), Some(Range {start: (1, 1), end: (1, 5), linewise: false}));
+        editor.goto(1, 1);
+        assert_eq!(Motion::WordForward(false).range(&mut editor, Some(2), 'd'), Some(Range {start: (1, 1), end: (1, 8), linewise: false}));
+        assert_eq!(Motion::WordForward(false).range(&mut editor, Some(3), 'c'), Some(Range {start: (1, 1), end: (2, 8), linewise: false}));
+        assert_eq!(Motion::Left.range(&mut editor, None, 'd'), None);
+        assert_eq!(Motion::Line.range(&mut editor, Some(2), 'd'), Some(Range {start: (1, 1), end: (2, 1), linewise: true}));
+        assert_eq!(Motion::LastLine.range(&mut editor, None, 'd'), Some(Range {start: (1, 1), end: (4, 1), linewise: true}));
+        assert_eq!(Motion::ParagraphForward.range(&mut editor, None, 'y'), Some(Range {start: (1, 1), end: (2, 8), linewise: true}));
+        editor.goto(2, 3);
+        assert_eq!(Motion::Object(TextObject::Paragraph, false).range(&mut editor, None, '>'), Some(Range {start: (1, 1), end: (2, 1), linewise: true}));
+        assert_eq!(Motion::Object(TextObject::Paragraph, true).range(&mut editor, None, 'd'), Some(Range {start: (1, 1), end: (3, 1), linewise: true}));
+
+        editor = Editor::new(String::new());
+        editor.add_text("if x {\n    y\n} \"\"".to_string());
+        editor.goto(2, 1);
+        assert_eq!(Motion::Object(TextObject::Block('{', '}'), false).range(&mut editor, None, 'd'), Some(Range {start: (2, 1), end: (2, 6), linewise: true}));
+        editor.goto(3, 3);
+        assert_eq!(Motion::Object(TextObject::Quote('"'), false).range(&mut editor, None, 'd'), None);
+    }
+}
@@ -0,0 +1,162 @@
+use std::fs::File;
+use std::ops::Range;
+use std::str;
+
+use memmap2::Mmap;
+
+/// Number of bytes in each block of `OriginalBuffer::block_newlines`
+const BLOCK_LEN: usize = 4096;
+
+/// The text of the file being edited, which table entries in the original buffer
+/// point to. Only the first `len` bytes are loaded, which have been checked to be
+/// valid UTF-8 and had their newlines indexed. The rest is loaded as needed
+pub(crate) struct OriginalBuffer {
+    /// All bytes, loaded or not
+    data: Data,
+    /// Number of bytes loaded
+    len: usize,
+    /// Number of newlines before the start of every block of `BLOCK_LEN` bytes
+    /// which starts in the loaded text
+    block_newlines: Vec<usize>,
+}
+
+/// Where the bytes of an `OriginalBuffer` are stored
+enum Data {
+    /// Text in memory
+    Text(String),
+    /// A read-only memory map of a file
+    Mapped(Mmap),
+}
+
+impl OriginalBuffer {
+    /// Initialize an empty original buffer, which text can be added to with `push_str`
+    pub(crate) fn new() -> OriginalBuffer {
+        OriginalBuffer {data: Data::Text(String::new()), len: 0, block_newlines: vec![0]}
+    }
+
+    /// Initialize an original buffer which maps the file at `path` into memory
+    pub(crate) fn open(path: &str) -> Result<OriginalBuffer, String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        // SAFETY: The map is read-only and Via never writes to a file it has mapped
+        // (saving replaces the file instead). Other programs changing the file
+        // while it is open can't be prevented, just like with any mmap
+        let map = unsafe { Mmap::map(&file) }.map_err(|e| e.to_string())?;
+        Ok(OriginalBuffer {data: Data::Mapped(map), len: 0, block_newlines: vec![0]})
+    }
+
+    /// Returns all bytes, loaded or not
+    fn bytes(&self) -> &[u8] {
+        match &self.data {
+            Data::Text(text) => text.as_bytes(),
+            Data::Mapped(map) => map,
+        }
+    }
+
+    /// Returns the number of bytes loaded
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the bytes which haven't been loaded yet
+    pub(crate) fn unloaded(&self) -> &[u8] {
+        &self.bytes()[self.len..]
+    }
+
+    /// Append `text` to the (not yet loaded) bytes. Only for buffers created with `new`
+    pub(crate) fn push_str(&mut self, text: &str) {
+        match &mut self.data {
+            Data::Text(buffer) => buffer.push_str(text),
+            Data::Mapped(_) => panic!("Can't add text to a mapped file"),
+        }
+    }
+
+    /// Load the next `len` bytes, which must be valid UTF-8
+    pub(crate) fn load(&mut self, len: usize) -> Result<(), String> {
+        let end = self.len + len;
+        if end > self.bytes().len() {
+            return Err(format!("Can't load {} bytes, only {} left", len, self.unloaded().len()));
+        }
+        str::from_utf8(&self.bytes()[self.len..end]).map_err(|e| format!("Invalid UTF-8 at byte {}", self.len + e.valid_up_to()))?;
+        let mut block = self.block_newlines.len();
+        while block * BLOCK_LEN <= end {
+            let block_start = (block - 1) * BLOCK_LEN;
+            let newlines = count_newlines(&self.bytes()[block_start..block_start + BLOCK_LEN]);
+            self.block_newlines.push(self.block_newlines[block - 1] + newlines);
+            block += 1;
+        }
+        self.len = end;
+        Ok(())
+    }
+
+    /// Returns the loaded text in `range`
+    pub(crate) fn get(&self, range: Range<usize>) -> Option<&str> {
+        let loaded = &self.bytes()[..self.len];
+        // SAFETY: Every byte loaded was checked to be valid UTF-8 in `load`. Since each
+        // call to `load` checks whole characters, the loaded bytes are valid as a whole
+        unsafe { str::from_utf8_unchecked(loaded) }.get(range)
+    }
+
+    /// Returns the number of newlines before `index` (which must be loaded)
+    pub(crate) fn newlines_before(&self, index: usize) -> usize {
+        let block = index / BLOCK_LEN;
+        self.block_newlines[block] + count_newlines(&self.bytes()[block * BLOCK_LEN..index])
+    }
+
+    /// Returns the index of the `n`-th newline (1-indexed) of the loaded text
+    pub(crate) fn nth_newline(&self, n: usize) -> usize {
+        let block = self.block_newlines.partition_point(|newlines| *newlines < n) - 1;
+        let block_start = block * BLOCK_LEN;
+        let skip = n - self.block_newlines[block] - 1;
+        let (i, _) = self.bytes()[block_start..self.len].iter().enumerate()
+            .filter(|(_, byte)| **byte == b'\n').nth(skip).unwrap();
+        block_start + i
+    }
+}
+
+/// Returns the number of newlines in `bytes`
+fn count_newlines(bytes: &[u8]) -> usize {
+    bytes.iter().filter(|byte| **byte == b'\n').count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn newlines() {
+        let mut text = String::new();
+        for i in 0..3000 {
+            text.push_str(&"x".repeat(i % 7));
+            text.push('\n');
+        }
+        let mut original_buffer = OriginalBuffer::new();
+        original_buffer.push_str(&text);
+        original_buffer.load(BLOCK_LEN + 10).unwrap();
+        original_buffer.load(text.len() - BLOCK_LEN - 10).unwrap();
+        assert!(original_buffer.unloaded().is_empty());
+        let newlines: Vec<usize> = text.match_indices('\n').map(|(i, _)| i).collect();
+        for (n, index) in newlines.iter().enumerate() {
+            assert_eq!(original_buffer.nth_newline(n + 1), *index);
+            assert_eq!(original_buffer.newlines_before(*index), n);
+            assert_eq!(original_buffer.newlines_before(*index + 1), n + 1);
+        }
+        assert_eq!(original_buffer.get(0..text.len()), Some(text.as_str()));
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let path = env::temp_dir().join(format!("via-original-buffer-test-{}", std::process::id()));
+        fs::write(&path, b"ab\n\xffc\n").unwrap();
+        let mut original_buffer = OriginalBuffer::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(original_buffer.unloaded(), b"ab\n\xffc\n");
+        original_buffer.load(3).unwrap();
+        assert_eq!(original_buffer.load(3), Err("Invalid UTF-8 at byte 3".to_string()));
+        assert_eq!(original_buffer.len(), 3);
+        assert_eq!(original_buffer.get(0..3), Some("ab\n"));
+        assert_eq!(original_buffer.get(0..4), None);
+        assert!(original_buffer.load(10).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
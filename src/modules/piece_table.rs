@@ -1,350 +1,942 @@
-// use unicode_segmentation::UnicodeSegmentation;
+use std::cmp::{min, max};
+use std::fmt;
+use std::io::{self, Write};
+use std::mem;
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::original_buffer::OriginalBuffer;
+use super::piece_tree::{self, PieceTree};
+use super::regex::{Captures, Regex};
+use super::undo_file::{Decoder, write_u64, write_bytes};
+
+/// Maximum number of actions kept in the undo history. Once there are more,
+/// the oldest are dropped until only `UNDO_LEVELS * 3 / 4` remain
+const UNDO_LEVELS: usize = 1000;
 
 /// The main structure for storing text
 pub(crate) struct PieceTable {
-    /// The main table, contains `TableEntry`'s
-    table: Vec<TableEntry>,
+    /// The main table, contains `TableEntry`'s in the order their text appears
+    table: PieceTree,
     /// Original buffer
-    original_buffer: String,
+    original_buffer: OriginalBuffer,
     /// Add buffer
     add_buffer: String,
-    /// All active text. Only to be used when `text_up_to_date == true`
-    text: String,
-    /// Length of text if it was up to date (`text` may not not be up to date)
-    text_len: usize,
-    /// Whether `text` is up to date
-    text_up_to_date: bool,
-    /// List of actions, which are lists of table entries' indices
-    actions: Vec<Vec<usize>>,
-    /// Where in `self.actions` we are currently at
+    /// Where the newlines are in the add buffer
+    newline_index: NewlineIndex,
+    /// Every action ever taken, in chronological order. Actions form a tree,
+    /// where the `n`-th action (1-indexed) is stored at `self.actions[n - 1]`
+    actions: Vec<Action>,
+    /// The action we are currently at (1-indexed)
     /// 
-    /// **NOTE**: A value of 0 means no actions have been taken
+    /// **NOTE**: A value of 0 means no actions are in effect
     actions_index: usize,
+    /// For every action (and 0 for the original text), the child action to go to on redo
+    redo_children: Vec<usize>,
+    /// When the piece table was created (or when the oldest remaining action was taken)
+    created: SystemTime,
+    /// Whether actions were dropped from the start of the history, so that
+    /// the original text can no longer be reached
+    history_trimmed: bool,
+    /// Whether actions are currently being merged into a single group
+    grouping: bool,
+    /// Number of actions added to the current group
+    group_len: usize,
+    /// The action (or 0 for the original text) whose text was last written to
+    /// the file, or `None` if it was dropped from the history
+    saved: Option<usize>,
+    /// Changes to the text not yet taken with `take_journal`, if they are being recorded
+    journal: Option<Vec<u8>>,
 }
 
 impl PieceTable {
     /// Initializes a piece table
     pub(crate) fn new() -> PieceTable {
-        PieceTable {
-            table: Vec::new(),
-            original_buffer: String::new(),
+        PieceTable::from_original(OriginalBuffer::new())
+    }
+
+    /// Initializes a piece table for the text of `original_buffer`, of which
+    /// only what has been loaded is part of the text
+    pub(crate) fn from_original(original_buffer: OriginalBuffer) -> PieceTable {
+        let mut piece_table = PieceTable {
+            table: PieceTree::new(),
+            original_buffer,
             add_buffer: String::new(),
-            text: String::new(),
-            text_len: 0,
-            text_up_to_date: true,
+            newline_index: NewlineIndex::new(),
             actions: Vec::new(),
             actions_index: 0,
+            redo_children: vec![0],
+            created: SystemTime::now(),
+            history_trimmed: false,
+            grouping: false,
+            group_len: 0,
+            saved: Some(0),
+            journal: None,
+        };
+        let len = piece_table.original_buffer.len();
+        if len > 0 {
+            piece_table.replace(0, 0, &[TableEntry::new(false, 0, len)]);
         }
+        piece_table
     }
 
-    /// Returns if any actions have been taken
+    /// Returns if any actions are in effect
     pub(crate) fn actions_taken(&self) -> bool {
-        !self.actions.is_empty()
+        self.actions_index != 0 || self.history_trimmed
+    }
+
+    /// Remember that the text is what was written to the file
+    pub(crate) fn mark_saved(&mut self) {
+        self.saved = Some(self.actions_index);
+    }
+
+    /// Returns whether the text is what was last written to the file
+    pub(crate) fn is_saved(&self) -> bool {
+        self.saved == Some(self.actions_index)
     }
 
-    /// Append text to the original buffer and add a table entry
+    /// Append text to the original buffer and add it to the end of the text
     pub(crate) fn update_original_buffer(&mut self, text: String) {
-        let org_buffer_len = self.original_buffer.len();
         self.original_buffer.push_str(&text);
-        self.table.push(TableEntry::new(false, org_buffer_len, org_buffer_len + text.len()));
-        self.text_len += text.len();
-        self.text_up_to_date = false;
+        self.load_original(text.len()).unwrap();
+    }
+
+    /// Returns the bytes of the original buffer which aren't part of the text yet
+    pub(crate) fn unloaded_original(&self) -> &[u8] {
+        self.original_buffer.unloaded()
+    }
+
+    /// Add the next `len` bytes of the original buffer to the end of the text
+    pub(crate) fn load_original(&mut self, len: usize) -> Result<(), String> {
+        if len == 0 {
+            return Ok(());
+        }
+        let org_buffer_len = self.original_buffer.len();
+        self.original_buffer.load(len)?;
+        let table_entry = TableEntry::new(false, org_buffer_len, org_buffer_len + len);
+        self.replace(self.text_len(), 0, &[table_entry]);
+        Ok(())
     }
 
     /// Add text at a certain index
     pub(crate) fn add_text(&mut self, text: String, index: usize) {
-        let text_len = text.len();
+        if index > self.text_len() {
+            panic!("index ({}) is a greater value than text len ({})", index, self.text_len());
+        }
+        self.record_change(index, 0, &text);
         let add_buffer_len = self.add_buffer.len();
-        if index > self.text_len {
-            panic!("index ({}) is a greater value than text len ({})", index, self.text_len);
+        self.newline_index.push(add_buffer_len, &text);
+        self.add_buffer.push_str(&text);
+        let inserted = vec![TableEntry::new(true, add_buffer_len, add_buffer_len + text.len())];
+        self.replace(index, 0, &inserted);
+        self.add_action(Change {index, removed: Vec::new(), inserted});
+    }
+
+    /// Delete text from `start` to `end`
+    pub(crate) fn delete_text(&mut self, start: usize, end: usize) {
+        if start >= end || end == 0 || end > self.text_len()  {
+            panic!("Can't delete from start ({}) to end ({}) of text size {}", start, end, self.text_len());
         }
-        let mut curr_pos = 0;
-        let mut action: Vec<usize> = Vec::new();
-        let mut add_table: Vec<TableEntry> = Vec::with_capacity(3);
-        let mut add_table_indices: Vec<usize> = Vec::with_capacity(3);
-        if index == 0 {
-            self.table.insert(0, TableEntry::new(true, add_buffer_len, add_buffer_len + text.len()));
-            action.push(0);
-        } else if index == self.text_len {
-            self.table.push(TableEntry::new(true, add_buffer_len, add_buffer_len + text.len()));
-            action.push(self.table.len());
+        self.record_change(start, end - start, "");
+        let removed = self.replace(start, end - start, &[]);
+        self.add_action(Change {index: start, removed, inserted: Vec::new()});
+    }
+
+    /// Start recording every change to the text (but not text loaded from the
+    /// original buffer), for `take_journal`
+    pub(crate) fn start_journal(&mut self) {
+        self.journal = Some(Vec::new());
+    }
+
+    /// Stop recording changes to the text
+    pub(crate) fn stop_journal(&mut self) {
+        self.journal = None;
+    }
+
+    /// Returns the changes to the text since the last call, each written as the
+    /// index, the number of bytes removed and the text inserted there
+    pub(crate) fn take_journal(&mut self) -> Vec<u8> {
+        self.journal.as_mut().map(mem::take).unwrap_or_default()
+    }
+
+    /// Record that `removed` bytes at `index` are replaced by `inserted`, if recording
+    fn record_change(&mut self, index: usize, removed: usize, inserted: &str) {
+        if let Some(journal) = &mut self.journal {
+            write_u64(journal, index as u64);
+            write_u64(journal, removed as u64);
+            write_bytes(journal, inserted.as_bytes());
+        }
+    }
+
+    /// Replace `len` bytes of text at `index` with the text of `table_entries`.
+    /// Returns the table entries that were removed
+    fn replace(&mut self, index: usize, len: usize, table_entries: &[TableEntry]) -> Vec<TableEntry> {
+        let (newline_index, original_buffer) = (&self.newline_index, &self.original_buffer);
+        let newlines = |table_entry: &TableEntry| newline_index.count(original_buffer, table_entry);
+        let removed = if len == 0 {Vec::new()} else {self.table.remove(index, index + len, &newlines)};
+        let mut curr_pos = index;
+        for table_entry in table_entries {
+            self.table.insert(curr_pos, table_entry.clone(), &newlines);
+            curr_pos += table_entry.len();
+        }
+        removed
+    }
+
+    /// Add a change to actions, as a new action after the current one
+    /// (or as part of the current action when grouping)
+    fn add_action(&mut self, change: Change) {
+        if self.grouping && self.group_len > 0 {
+            let group = &mut self.actions[self.actions_index - 1];
+            group.changes.push(change);
+            group.time = SystemTime::now();
         } else {
-            for (i, table_entry) in self.table.iter_mut().enumerate() {
-                if table_entry.active {
-                    let len = table_entry.end_index - table_entry.start_index;
-                    if curr_pos == index {
-                        add_table.push(TableEntry::new(true, add_buffer_len, add_buffer_len + text.len()));
-                        add_table_indices.push(i);
-                        break
-                    } else if curr_pos + len > index {
-                        // Split into 2 parts and disable original [ab] + [c] -> [a][c][b]
-                        let split_point = index - curr_pos;
-
-                        table_entry.active = false;
-                        action.push(i);
-
-                        add_table.push(TableEntry::new(table_entry.is_add_buffer, table_entry.start_index, table_entry.start_index + split_point));
-                        action.push(i + 1);
-                        add_table.push(TableEntry::new(true, add_buffer_len, add_buffer_len + text.len()));
-                        action.push(i + 2);
-                        add_table.push(TableEntry::new(table_entry.is_add_buffer, table_entry.start_index + split_point, table_entry.end_index));
-                        action.push(i + 3);
-    
-                        add_table_indices.push(i);
-                        add_table_indices.push(i + 1);
-                        add_table_indices.push(i + 2);
-                        break
-                    }
-                    curr_pos += len;
-                }
-            }
-    
-            for (i, table_entry) in add_table_indices.iter().zip(add_table) {
-                self.table.insert(*i, table_entry);
+            let action = Action::new(change, self.actions_index);
+            self.actions.push(action);
+            self.redo_children.push(0);
+            self.redo_children[self.actions_index] = self.actions.len();
+            self.actions_index = self.actions.len();
+            if self.actions.len() > UNDO_LEVELS {
+                self.trim_history(UNDO_LEVELS * 3 / 4);
+                self.collect_garbage();
             }
         }
-
-        self.add_buffer.push_str(&text);
-        self.add_action(action);
-        self.text_len += text_len;
-        self.text_up_to_date = false;
+        if self.grouping {
+            self.group_len += 1;
+        }
     }
 
-    /// Delete text from `start` to `end`
-    pub(crate) fn delete_text(&mut self, start: usize, end: usize) {
-        if start >= end || end == 0 || end > self.text_len  {
-            panic!("Can't delete from start ({}) to end ({}) of text size {}", start, end, self.text_len);
+    /// Drop the oldest actions until at most `max_actions` remain. Actions
+    /// on the path to the current one are only dropped by making them part
+    /// of the text undo can go back to
+    fn trim_history(&mut self, max_actions: usize) {
+        let num_actions = self.actions.len();
+        if num_actions <= max_actions {
+            return;
+        }
+        // The children of each action (and the original text) in order, and the
+        // number of actions descending from each, including itself
+        let mut children = vec![Vec::new(); num_actions + 1];
+        let mut sizes = vec![1; num_actions + 1];
+        for (i, action) in self.actions.iter().enumerate() {
+            children[action.parent].push(i + 1);
+        }
+        for (i, action) in self.actions.iter().enumerate().rev() {
+            sizes[action.parent] += sizes[i + 1];
+        }
+        let mut on_path = vec![false; num_actions + 1];
+        let mut action = self.actions_index;
+        while action != 0 {
+            on_path[action] = true;
+            action = self.actions[action - 1].parent;
         }
-        let mut curr_pos = 0;
-        let mut action: Vec<usize> = Vec::new();
-        let mut add_table: Vec<TableEntry> = Vec::with_capacity(4);
-        let mut add_table_indices: Vec<usize> = Vec::with_capacity(4);
 
-        for (i, table_entry) in self.table.iter_mut().enumerate() {
-            if curr_pos == end {
-                break
+        // The oldest action left is always a child of the root (the text undo can go back
+        // to). Either it is on the path to the current action and becomes the root,
+        // dropping every other branch, or its branch is dropped
+        let mut root = 0;
+        let mut oldest = 0;
+        let mut num_left = num_actions;
+        while num_left > max_actions {
+            let child = children[root][oldest];
+            if on_path[child] {
+                num_left = sizes[child] - 1;
+                root = child;
+                oldest = 0;
+            } else {
+                num_left -= sizes[child];
+                oldest += 1;
             }
-            if table_entry.active {
-                let len = table_entry.end_index - table_entry.start_index;
-                if start <= curr_pos && end >= curr_pos + len {
-                    // At table entry to continue/end at
-                    // OR start & end is this exact table entry
-                    table_entry.active = false;
-                    action.push(i + add_table.len());
-                } else if start > curr_pos && start < curr_pos + len && end >= curr_pos + len {
-                    // At table entry to start at (split table entry in two)
-                    // Only occurs once, and will be the first
-                    let split_point = start - curr_pos;
-                    
-                    table_entry.active = false;
-                    action.push(i);
-
-                    add_table.push(TableEntry::new(table_entry.is_add_buffer, table_entry.start_index, table_entry.start_index + split_point));
-                    add_table_indices.push(i + 1);
-                    action.push(i + 1);
-                } else if start <= curr_pos && end > curr_pos && end < curr_pos + len {
-                    // At table entry to end at (split table entry in two)
-                    let split_point = end - curr_pos;
-
-                    table_entry.active = false;
-                    action.push(i + add_table.len());
-
-                    add_table.push(TableEntry::new(table_entry.is_add_buffer, table_entry.start_index + split_point, table_entry.end_index));
-                    add_table_indices.push(i + add_table.len());
-                    action.push(i + add_table.len());
-                    break
-                } else if start > curr_pos && end < curr_pos + len {
-                    // At table entry to split into 3 [abc] -> [a](b)[c]
-                    // Only occurs once, and will be the first/last
-                    let first_split = start - curr_pos;
-                    let second_split = end - curr_pos;
-
-                    table_entry.active = false;
-                    action.push(i);
-
-                    add_table.push(TableEntry::new(table_entry.is_add_buffer, table_entry.start_index, table_entry.start_index + first_split));
-                    add_table_indices.push(i + 1);
-                    action.push(i + 1);
-
-                    add_table.push(TableEntry::new(table_entry.is_add_buffer, table_entry.start_index + second_split, table_entry.end_index));
-                    add_table_indices.push(i + 2);
-                    action.push(i + 2);
-                    break
-                }
-                curr_pos += len;
+        }
+        // The actions kept are the ones descending from a child of the root which is
+        // at least as new as the oldest child left
+        let first_kept = children[root].get(oldest).copied().unwrap_or(usize::MAX);
+        let mut branch = vec![0; num_actions + 1];
+        let mut new_index = vec![0; num_actions + 1];
+        let mut num_kept = 0;
+        for action in 1..=num_actions {
+            let parent = self.actions[action - 1].parent;
+            branch[action] = if parent == root {action} else {branch[parent]};
+            if branch[action] >= first_kept {
+                num_kept += 1;
+                new_index[action] = num_kept;
+            }
+        }
+
+        if root != 0 {
+            self.created = self.actions[root - 1].time;
+        }
+        let mut redo_children = vec![new_index[self.redo_children[root]]];
+        let mut actions = Vec::with_capacity(num_kept);
+        for (i, action) in mem::take(&mut self.actions).into_iter().enumerate() {
+            if new_index[i + 1] != 0 {
+                redo_children.push(new_index[self.redo_children[i + 1]]);
+                actions.push(Action {parent: new_index[action.parent], ..action});
+            }
+        }
+        // Redo children that were dropped are replaced by the latest remaining child
+        let dropped: Vec<bool> = redo_children.iter().map(|child| *child == 0).collect();
+        for (i, action) in actions.iter().enumerate() {
+            if dropped[action.parent] {
+                redo_children[action.parent] = i + 1;
             }
         }
+        self.actions = actions;
+        self.redo_children = redo_children;
+        self.actions_index = new_index[self.actions_index];
+        // The text of the root is the one undo goes back to now
+        self.saved = self.saved.and_then(|saved| if saved == root {Some(0)} else {Some(new_index[saved]).filter(|saved| *saved != 0)});
+        self.history_trimmed |= root != 0;
+    }
+
+    /// Drop text in the add buffer that neither the text nor the undo history
+    /// uses, and merge table entries whose text is contiguous
+    pub(crate) fn compact(&mut self) {
+        self.collect_garbage();
+        let table_entries = merge_entries(self.table.iter().cloned().collect());
+        self.table.clear();
+        self.replace(0, 0, &table_entries);
+    }
+
+    /// Drop text in the add buffer that neither the text nor the undo history uses
+    fn collect_garbage(&mut self) {
+        let ranges = used_ranges(self.table.iter()
+            .chain(self.history_entries())
+            .filter(|table_entry| table_entry.is_add_buffer), 0);
+        let mut add_buffer = String::new();
+        for range in &ranges {
+            add_buffer.push_str(&self.add_buffer[range.0..range.1]);
+        }
+        let remap = |table_entry: &mut TableEntry| {
+            if table_entry.is_add_buffer {
+                *table_entry = remap_entry(&ranges, table_entry);
+            }
+        };
 
-        for (i, table_entry) in add_table_indices.iter().zip(add_table) {
-            self.table.insert(*i, table_entry);
+        self.table.for_each_mut(remap);
+        for change in self.actions.iter_mut().flat_map(|action| &mut action.changes) {
+            change.removed.iter_mut().chain(&mut change.inserted).for_each(remap);
+            change.removed = merge_entries(mem::take(&mut change.removed));
+            change.inserted = merge_entries(mem::take(&mut change.inserted));
         }
+        self.add_buffer = add_buffer;
+        self.newline_index.add.clear();
+        self.newline_index.push(0, &self.add_buffer);
+    }
 
-        self.add_action(action);
-        self.text_len -= end - start;
-        self.text_up_to_date = false;
+    /// Returns every table entry removed or inserted by an action
+    fn history_entries(&self) -> impl Iterator<Item = &TableEntry> {
+        self.actions.iter().flat_map(|action| &action.changes).flat_map(|change| change.removed.iter().chain(&change.inserted))
     }
 
-    /// Add a table entry to actions
-    fn add_action(&mut self, action: Vec<usize>) {
-        // Remove actions after current index
-        // TODO: Remove all unecessary TableEntry's
-        self.actions = self.actions[..self.actions_index].to_vec();
-        self.actions.push(action);
-        self.actions_index = self.actions.len();
+    /// Start merging all following actions into one, until `commit_group` is called
+    pub(crate) fn begin_group(&mut self) {
+        self.grouping = true;
+        self.group_len = 0;
     }
 
-    /// Undo an action. Errors if no actions to undo
-    fn undo(&mut self) {
-        if self.actions.is_empty() {
-            panic!("Unable to undo");
+    /// Stop merging actions. Returns the number of actions that were merged
+    pub(crate) fn commit_group(&mut self) -> usize {
+        self.grouping = false;
+        self.group_len
+    }
+
+    /// Undo an action. Returns the index the action took place at
+    pub(crate) fn undo(&mut self) -> Result<usize, String> {
+        if self.actions_index == 0 {
+            return Err("Already at oldest change".to_string());
         }
+        let action = self.actions_index;
+        let parent = self.actions[action - 1].parent;
+        self.redo_children[parent] = action;
+        self.actions_index = parent;
+        Ok(self.switch_action(action - 1, true))
+    }
 
-        for index in self.actions.get(self.actions_index - 1).unwrap() {
-            let table_entry = self.table.get_mut(*index).unwrap();
-            table_entry.switch();
-            if table_entry.active {
-                self.text_len += table_entry.end_index - table_entry.start_index;
-            } else {
-                self.text_len -= table_entry.end_index - table_entry.start_index;
-            }
+    /// Redo an action. Returns the index the action took place at
+    pub(crate) fn redo(&mut self) -> Result<usize, String> {
+        let child = self.redo_children[self.actions_index];
+        if child == 0 {
+            return Err("Already at newest change".to_string());
         }
+        self.actions_index = child;
+        Ok(self.switch_action(child - 1, false))
+    }
 
-        self.text_up_to_date = false;
-        self.actions_index -= 1;
+    /// Go back `steps` actions in time, regardless of which branch they are on.
+    /// Returns the index the last action took place at
+    pub(crate) fn earlier(&mut self, steps: usize) -> Result<usize, String> {
+        if self.actions_index == 0 {
+            return Err("Already at oldest change".to_string());
+        }
+        self.goto_action(self.actions_index.saturating_sub(steps))
     }
 
-    /// Redo an action. Errors if no actions to redo
-    fn redo(&mut self) {
-        if self.actions.is_empty() || self.actions.len() <= self.actions_index {
-            panic!("Unable to redo");
+    /// Go forward `steps` actions in time, regardless of which branch they are on.
+    /// Returns the index the last action took place at
+    pub(crate) fn later(&mut self, steps: usize) -> Result<usize, String> {
+        if self.actions_index == self.actions.len() {
+            return Err("Already at newest change".to_string());
         }
-        for index in self.actions.get(self.actions_index).unwrap() {
-            let table_entry = self.table.get_mut(*index).unwrap();
-            table_entry.switch();
-            if table_entry.active {
-                self.text_len += table_entry.end_index - table_entry.start_index;
-            } else {
-                self.text_len -= table_entry.end_index - table_entry.start_index;
+        self.goto_action(min(self.actions_index + steps, self.actions.len()))
+    }
+
+    /// Go back to the text as it was `duration` before the current action.
+    /// Returns the index the last action took place at
+    pub(crate) fn earlier_time(&mut self, duration: Duration) -> Result<usize, String> {
+        if self.actions_index == 0 {
+            return Err("Already at oldest change".to_string());
+        }
+        let time = self.action_time(self.actions_index).checked_sub(duration).unwrap_or(UNIX_EPOCH);
+        self.goto_action(min(self.last_action_at(time), self.actions_index - 1))
+    }
+
+    /// Go forward to the text as it was `duration` after the current action.
+    /// Returns the index the last action took place at
+    pub(crate) fn later_time(&mut self, duration: Duration) -> Result<usize, String> {
+        if self.actions_index == self.actions.len() {
+            return Err("Already at newest change".to_string());
+        }
+        let action = self.action_time(self.actions_index).checked_add(duration).map_or(self.actions.len(), |time| self.last_action_at(time));
+        self.goto_action(max(action, self.actions_index + 1))
+    }
+
+    /// Returns when the `action`-th action was taken (or when the piece table was created for 0)
+    fn action_time(&self, action: usize) -> SystemTime {
+        if action == 0 {self.created} else {self.actions[action - 1].time}
+    }
+
+    /// Returns the latest action taken at or before `time` (0 if there are none)
+    fn last_action_at(&self, time: SystemTime) -> usize {
+        (1..=self.actions.len()).rev().find(|action| self.action_time(*action) <= time).unwrap_or(0)
+    }
+
+    /// Undo and redo actions along the tree until `target` is the current action.
+    /// Returns the index the last action took place at
+    fn goto_action(&mut self, target: usize) -> Result<usize, String> {
+        // Actions from `target` up to (but not including) the original text
+        let mut path = Vec::new();
+        let mut action = target;
+        while action != 0 {
+            path.push(action);
+            action = self.actions[action - 1].parent;
+        }
+
+        let mut index = Err("Already at that change".to_string());
+        while self.actions_index != 0 && !path.contains(&self.actions_index) {
+            index = self.undo();
+        }
+        let common = path.iter().position(|action| *action == self.actions_index).unwrap_or(path.len());
+        for action in path[..common].iter().rev() {
+            self.redo_children[self.actions_index] = *action;
+            index = self.redo();
+        }
+        index
+    }
+
+    /// Undo (or redo) the changes of the `action_index`-th action.
+    /// Returns the index in the text where the action took place
+    fn switch_action(&mut self, action_index: usize, undo: bool) -> usize {
+        let changes = mem::take(&mut self.actions[action_index].changes);
+        let mut apply = |removed: &[TableEntry], inserted: &[TableEntry], index: usize| {
+            if self.journal.is_some() {
+                let text: String = inserted.iter().map(|table_entry| self.table_entry_text(table_entry)).collect();
+                self.record_change(index, entries_len(removed), &text);
+            }
+            self.replace(index, entries_len(removed), inserted);
+        };
+        if undo {
+            for change in changes.iter().rev() {
+                apply(&change.inserted, &change.removed, change.index);
+            }
+        } else {
+            for change in &changes {
+                apply(&change.removed, &change.inserted, change.index);
             }
         }
-        self.text_up_to_date = false;
-        self.actions_index += 1;
+        let action = &mut self.actions[action_index];
+        action.changes = changes;
+        action.changes[0].index
     }
 
     /// Returns the text represented by a table entry
     fn table_entry_text(&self, table_entry: &TableEntry) -> &str {
-        let buffer = if table_entry.is_add_buffer {&self.add_buffer} else {&self.original_buffer};
-        buffer.get(table_entry.start_index..table_entry.end_index).unwrap()
+        let range = table_entry.start_index..table_entry.end_index;
+        if table_entry.is_add_buffer {&self.add_buffer[range]} else {self.original_buffer.get(range).unwrap()}
     }
 
     /// Returns length of text
     pub(crate) fn text_len(&self) -> usize {
-        self.text_len
+        self.table.len()
     }
 
-    /// Returns all visible text
+    /// Returns a copy of all visible text. This is O(text length), so prefer
+    /// `chunks`, `slice` or `chars_at` where possible
     /// 
     /// If you want to get the length of the text, use `text_len(&self)` instead
-    pub(crate) fn text(&mut self) -> &str {
-        self.update_text();
-        &self.text
+    pub(crate) fn text(&self) -> String {
+        let mut text = String::with_capacity(self.text_len());
+        text.extend(self.chunks());
+        text
     }
 
-    /// Updates all text for the piece table
-    fn update_text(&mut self) {
-        if self.text_up_to_date {
-            return;
+    /// Write the text to `writer` a piece at a time, followed by the bytes of the
+    /// original buffer which aren't part of it yet, as they are. Returns the number
+    /// of bytes written
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<usize> {
+        for chunk in self.chunks() {
+            writer.write_all(chunk.as_bytes())?;
+        }
+        let unloaded = self.unloaded_original();
+        writer.write_all(unloaded)?;
+        Ok(self.text_len() + unloaded.len())
+    }
+
+    /// Returns all visible text, as the text of each table entry in order
+    pub(crate) fn chunks(&self) -> Chunks<'_> {
+        self.slice(0..self.text_len())
+    }
+
+    /// Returns the visible text in `range`, in chunks
+    pub(crate) fn slice(&self, range: Range<usize>) -> Chunks<'_> {
+        let (table_entries, skip) = self.table.iter_at(range.start);
+        Chunks {
+            piece_table: self,
+            table_entries,
+            skip,
+            remaining: range.end.saturating_sub(range.start),
         }
+    }
 
-        let mut text = String::new();
-        for table_entry in &self.table {
-            if table_entry.active {
-                text.push_str(self.table_entry_text(table_entry));
-            }
+    /// Returns the characters of the visible text starting at `index`
+    pub(crate) fn chars_at(&self, index: usize) -> impl Iterator<Item = char> + '_ {
+        self.slice(index..self.text_len()).flat_map(|chunk| chunk.chars())
+    }
+
+    /// Returns whether `index` is at the start or end of a character of the text
+    pub(crate) fn is_char_boundary(&self, index: usize) -> bool {
+        if index >= self.text_len() {
+            return index == self.text_len()
         }
-        
-        self.text = text;
-        self.text_up_to_date = true;
+        let (mut table_entries, skip) = self.table.iter_at(index);
+        table_entries.next().is_some_and(|table_entry| self.table_entry_text(table_entry).is_char_boundary(skip))
     }
 
-    /// Insert a table entry to a specific index
-    fn insert(&mut self, index: usize, table_entry: TableEntry) {
-        self.table.insert(index, table_entry);
-        self.text_up_to_date = false;
+    /// Returns the character which ends at `index`, if there is one
+    fn char_before(&self, index: usize) -> Option<char> {
+        if index == 0 {
+            return None
+        }
+        let (mut table_entries, skip) = self.table.iter_at(index - 1);
+        let text = self.table_entry_text(table_entries.next()?);
+        let mut start = skip;
+        while !text.is_char_boundary(start) {
+            start -= 1;
+        }
+        text[start..].chars().next()
+    }
+
+    /// Returns the first match of `regex` which starts in `range`. The match may go
+    /// past the end of `range`, and the text is read from the pieces as it is matched
+    pub(crate) fn find(&self, regex: &Regex, range: Range<usize>) -> Option<Captures> {
+        regex.find_at(self.chars_at(range.start), self.char_before(range.start), range)
+    }
+
+    /// Returns the last match of `regex` which starts in `range`
+    pub(crate) fn rfind(&self, regex: &Regex, range: Range<usize>) -> Option<Captures> {
+        let start = regex.find_last_start(self.chars_at(range.start), self.char_before(range.start), range)?;
+        self.find(regex, start..start + 1)
+    }
+
+    /// Append the undo history to `bytes`, to be read by `decode`. The text has to be
+    /// what was just written to the file, which is the original buffer once the file is
+    /// read again. So the history is written as taking place before that text, with what
+    /// it has from the current original buffer copied to the end of the add buffer
+    pub(crate) fn encode(&self, bytes: &mut Vec<u8>) {
+        let ranges = used_ranges(self.history_entries().filter(|table_entry| !table_entry.is_add_buffer), self.add_buffer.len());
+        let copied_len: usize = ranges.iter().map(|range| range.1 - range.0).sum();
+        write_u64(bytes, (self.text_len() + self.unloaded_original().len()) as u64);
+        write_u64(bytes, (self.add_buffer.len() + copied_len) as u64);
+        bytes.extend_from_slice(self.add_buffer.as_bytes());
+        for range in &ranges {
+            bytes.extend_from_slice(self.original_buffer.get(range.0..range.1).unwrap().as_bytes());
+        }
+        let remap = |table_entry: &TableEntry| if table_entry.is_add_buffer {table_entry.clone()} else {remap_entry(&ranges, table_entry)};
+
+        write_u64(bytes, self.actions.len() as u64);
+        for action in &self.actions {
+            write_u64(bytes, action.parent as u64);
+            write_time(bytes, action.time);
+            write_u64(bytes, action.changes.len() as u64);
+            for change in &action.changes {
+                write_u64(bytes, change.index as u64);
+                for table_entries in &[&change.removed, &change.inserted] {
+                    write_u64(bytes, table_entries.len() as u64);
+                    for table_entry in table_entries.iter() {
+                        write_table_entry(bytes, &remap(table_entry));
+                    }
+                }
+            }
+        }
+        write_u64(bytes, self.actions_index as u64);
+        for child in &self.redo_children {
+            write_u64(bytes, *child as u64);
+        }
+        write_time(bytes, self.created);
+        write_u64(bytes, self.history_trimmed as u64);
     }
 
-    /// Add a table entry to the end of the table
-    fn push(&mut self, table_entry: TableEntry) {
-        self.table.push(table_entry);
-        self.text_up_to_date = false;
+    /// Read the undo history written by `encode`, for the text in `original_buffer`,
+    /// which must not have been loaded yet
+    pub(crate) fn decode(decoder: &mut Decoder, mut original_buffer: OriginalBuffer) -> Result<PieceTable, String> {
+        let text_len = decoder.read_usize(usize::MAX)?;
+        if text_len != original_buffer.len() + original_buffer.unloaded().len() {
+            return Err("Undo file is for a different file".to_string());
+        }
+        // The history can take place anywhere in the text, so all of it has to be loaded
+        original_buffer.load(text_len)?;
+        let mut piece_table = PieceTable::from_original(original_buffer);
+        piece_table.add_buffer = String::from_utf8(decoder.read_bytes()?.to_vec()).map_err(|_| "Invalid text in undo file".to_string())?;
+        piece_table.newline_index.push(0, &piece_table.add_buffer);
+
+        let num_actions = decoder.read_usize(usize::MAX)?;
+        for i in 0..num_actions {
+            let parent = decoder.read_usize(i)?;
+            let time = read_time(decoder)?;
+            let mut changes = Vec::new();
+            for _ in 0..decoder.read_usize(usize::MAX)? {
+                let index = decoder.read_usize(usize::MAX)?;
+                let mut removed = Vec::new();
+                for _ in 0..decoder.read_usize(usize::MAX)? {
+                    removed.push(piece_table.read_table_entry(decoder)?);
+                }
+                let mut inserted = Vec::new();
+                for _ in 0..decoder.read_usize(usize::MAX)? {
+                    inserted.push(piece_table.read_table_entry(decoder)?);
+                }
+                changes.push(Change {index, removed, inserted});
+            }
+            if changes.is_empty() {
+                return Err("Invalid action in undo file".to_string());
+            }
+            piece_table.actions.push(Action {changes, parent, time});
+        }
+        piece_table.actions_index = decoder.read_usize(num_actions)?;
+        piece_table.redo_children.clear();
+        for parent in 0..=num_actions {
+            let child = decoder.read_usize(num_actions)?;
+            if child != 0 && piece_table.actions[child - 1].parent != parent {
+                return Err("Invalid redo in undo file".to_string());
+            }
+            piece_table.redo_children.push(child);
+        }
+        piece_table.created = read_time(decoder)?;
+        piece_table.history_trimmed = decoder.read_usize(1)? == 1;
+        piece_table.check_history(text_len)?;
+        Ok(piece_table)
     }
 
-    /// Return the index-th line
-    /// TODO: Remove this and do something better regarding lines
-    pub(crate) fn line(&mut self, index: usize) -> Result<&str, &str> {
-        for (i, line) in self.text().lines().enumerate() {
-            if i == index {
-                return Ok(line)
+    /// Check that every change of every action fits in the text it is made to, given
+    /// that the text is `text_len` long at the current action
+    fn check_history(&self, text_len: usize) -> Result<(), String> {
+        let invalid = || "Invalid change in undo file".to_string();
+        // How much longer each action makes the text
+        let growth = |action: &Action| action.changes.iter()
+            .map(|change| entries_len(&change.inserted) as i128 - entries_len(&change.removed) as i128).sum::<i128>();
+        let mut root_len = text_len as i128;
+        let mut action = self.actions_index;
+        while action != 0 {
+            root_len -= growth(&self.actions[action - 1]);
+            action = self.actions[action - 1].parent;
+        }
+        // The length of the text at each action, and 0 for the original text
+        let mut lens = vec![root_len];
+        for action in &self.actions {
+            let mut len = lens[action.parent];
+            for change in &action.changes {
+                if len < 0 || change.index as i128 + entries_len(&change.removed) as i128 > len {
+                    return Err(invalid());
+                }
+                len += entries_len(&change.inserted) as i128 - entries_len(&change.removed) as i128;
             }
+            lens.push(len);
+        }
+        if lens.iter().any(|len| *len < 0) {
+            return Err(invalid());
+        }
+        Ok(())
+    }
+
+    /// Read a table entry written by `write_table_entry`, making sure it points to valid text
+    fn read_table_entry(&self, decoder: &mut Decoder) -> Result<TableEntry, String> {
+        let is_add_buffer = decoder.read_usize(1)? == 1;
+        let start_index = decoder.read_usize(usize::MAX)?;
+        let end_index = decoder.read_usize(usize::MAX)?;
+        let range = start_index..end_index;
+        let valid = if is_add_buffer {self.add_buffer.get(range).is_some()} else {self.original_buffer.get(range).is_some()};
+        if start_index > end_index || !valid {
+            return Err("Invalid table entry in undo file".to_string());
+        }
+        Ok(TableEntry::new(is_add_buffer, start_index, end_index))
+    }
+
+    /// Returns the number of lines
+    pub(crate) fn line_count(&self) -> usize {
+        self.table.newlines() + 1
+    }
+
+    /// Returns the index the `line`-th line (0-indexed) starts at
+    pub(crate) fn line_to_offset(&self, line: usize) -> Option<usize> {
+        if line == 0 {
+            return Some(0)
         }
-        Err("Invalid line index")
+        self.table.after_newline(line, &|table_entry, n| self.newline_index.nth(&self.original_buffer, table_entry, n))
+    }
+
+    /// Returns the line (0-indexed) containing `offset`
+    pub(crate) fn offset_to_line(&self, offset: usize) -> usize {
+        self.table.newlines_before(offset, &|table_entry| self.newline_index.count(&self.original_buffer, table_entry))
+    }
+
+    /// Returns the length of the `line`-th line (0-indexed), not including the newline
+    pub(crate) fn line_len(&self, line: usize) -> Option<usize> {
+        let start = self.line_to_offset(line)?;
+        let end = match self.line_to_offset(line + 1) {
+            Some(next_start) => next_start - 1,
+            None => self.text_len(),
+        };
+        Some(end - start)
+    }
+}
+
+/// Borrowed view of part of the visible text of a `PieceTable`,
+/// which iterates over the text of each table entry in order
+#[derive(Clone)]
+pub(crate) struct Chunks<'a> {
+    piece_table: &'a PieceTable,
+    /// Table entries left to go through
+    table_entries: piece_tree::Iter<'a>,
+    /// Number of bytes to skip at the start of the next table entry
+    skip: usize,
+    /// Number of bytes left to return
+    remaining: usize,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        if self.remaining == 0 {
+            return None
+        }
+        let text = &self.piece_table.table_entry_text(self.table_entries.next()?)[self.skip..];
+        let len = min(text.len(), self.remaining);
+        self.skip = 0;
+        self.remaining -= len;
+        Some(&text[..len])
+    }
+}
+
+impl fmt::Display for Chunks<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for chunk in self.clone() {
+            f.write_str(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// Append `table_entry` to `bytes`
+fn write_table_entry(bytes: &mut Vec<u8>, table_entry: &TableEntry) {
+    write_u64(bytes, table_entry.is_add_buffer as u64);
+    write_u64(bytes, table_entry.start_index as u64);
+    write_u64(bytes, table_entry.end_index as u64);
+}
+
+/// Append `time` to `bytes`, to be read by `read_time`
+fn write_time(bytes: &mut Vec<u8>, time: SystemTime) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    write_u64(bytes, since_epoch.as_secs());
+    write_u64(bytes, since_epoch.subsec_nanos() as u64);
+}
+
+/// Read a time written by `write_time`
+fn read_time(decoder: &mut Decoder) -> Result<SystemTime, String> {
+    let secs = decoder.read_u64()?;
+    let nanos = decoder.read_usize(999_999_999)?;
+    UNIX_EPOCH.checked_add(Duration::new(secs, nanos as u32)).ok_or_else(|| "Invalid time in undo file".to_string())
+}
+
+/// Returns the length of the text of all `table_entries`
+fn entries_len(table_entries: &[TableEntry]) -> usize {
+    table_entries.iter().map(|table_entry| table_entry.len()).sum()
+}
+
+/// Returns the ranges of a buffer that `table_entries` point to, with overlapping and
+/// adjacent ones merged, as (old start, old end, new start) where the new start is
+/// where the range goes when they are all put one after the other from `start`
+fn used_ranges<'a>(table_entries: impl Iterator<Item = &'a TableEntry>, start: usize) -> Vec<(usize, usize, usize)> {
+    let mut used: Vec<&TableEntry> = table_entries.collect();
+    used.sort_by_key(|table_entry| table_entry.start_index);
+    let mut ranges: Vec<(usize, usize, usize)> = Vec::new();
+    for table_entry in used {
+        match ranges.last_mut() {
+            Some(range) if table_entry.start_index <= range.1 => range.1 = max(range.1, table_entry.end_index),
+            _ => ranges.push((table_entry.start_index, table_entry.end_index, 0)),
+        }
+    }
+    let mut new_start = start;
+    for range in &mut ranges {
+        range.2 = new_start;
+        new_start += range.1 - range.0;
+    }
+    ranges
+}
+
+/// Returns `table_entry` moved to the add buffer where `used_ranges` put its text
+fn remap_entry(ranges: &[(usize, usize, usize)], table_entry: &TableEntry) -> TableEntry {
+    let (old_start, _, new_start) = ranges[ranges.partition_point(|range| range.0 <= table_entry.start_index) - 1];
+    TableEntry::new(true, table_entry.start_index - old_start + new_start, table_entry.end_index - old_start + new_start)
+}
+
+/// Returns `table_entries` with every run of contiguous table entries merged into one
+fn merge_entries(table_entries: Vec<TableEntry>) -> Vec<TableEntry> {
+    let mut merged: Vec<TableEntry> = Vec::with_capacity(table_entries.len());
+    for table_entry in table_entries {
+        match merged.last_mut() {
+            Some(last) if last.continues_with(&table_entry) => last.end_index = table_entry.end_index,
+            _ => merged.push(table_entry),
+        }
+    }
+    merged
+}
+
+/// Indices of every newline in the add buffer, used (along with `OriginalBuffer`'s
+/// own index) to count the newlines of a table entry without looking at its text
+struct NewlineIndex {
+    /// Indices of newlines in the add buffer, in order
+    add: Vec<usize>,
+}
+
+impl NewlineIndex {
+    /// Initialize an empty newline index
+    fn new() -> NewlineIndex {
+        NewlineIndex {add: Vec::new()}
+    }
+
+    /// Add the newlines of `text`, which is being appended to the add buffer at `buffer_len`
+    fn push(&mut self, buffer_len: usize, text: &str) {
+        self.add.extend(text.bytes().enumerate().filter(|(_, byte)| *byte == b'\n').map(|(i, _)| buffer_len + i));
+    }
+
+    /// Returns the index in the text of `table_entry` of its `n`-th newline (1-indexed)
+    fn nth(&self, original_buffer: &OriginalBuffer, table_entry: &TableEntry, n: usize) -> usize {
+        if !table_entry.is_add_buffer {
+            let newlines_before = original_buffer.newlines_before(table_entry.start_index);
+            return original_buffer.nth_newline(newlines_before + n) - table_entry.start_index;
+        }
+        self.add[self.add.partition_point(|i| *i < table_entry.start_index) + n - 1] - table_entry.start_index
+    }
+
+    /// Returns the number of newlines in the text of `table_entry`
+    fn count(&self, original_buffer: &OriginalBuffer, table_entry: &TableEntry) -> usize {
+        if !table_entry.is_add_buffer {
+            return original_buffer.newlines_before(table_entry.end_index) - original_buffer.newlines_before(table_entry.start_index);
+        }
+        self.add.partition_point(|i| *i < table_entry.end_index) - self.add.partition_point(|i| *i < table_entry.start_index)
+    }
+}
+
+/// A single change to the text: `removed` was replaced by `inserted` at `index`
+struct Change {
+    /// Index in the text where the change took place
+    index: usize,
+    /// Table entries that were removed
+    removed: Vec<TableEntry>,
+    /// Table entries that were inserted
+    inserted: Vec<TableEntry>,
+}
+
+/// A single undoable action
+struct Action {
+    /// Changes made, in order
+    changes: Vec<Change>,
+    /// The action this was taken after (0 for the original text)
+    parent: usize,
+    /// When the action was taken
+    time: SystemTime,
+}
+
+impl Action {
+    /// Initialize an action
+    fn new(change: Change, parent: usize) -> Action {
+        Action {changes: vec![change], parent, time: SystemTime::now()}
     }
 }
 
 /// An entry in PieceTable's table
-struct TableEntry {
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct TableEntry {
     /// Whether this table entry points to the add buffer
-    is_add_buffer: bool,
+    pub(crate) is_add_buffer: bool,
     /// Start index
-    start_index: usize,
+    pub(crate) start_index: usize,
     /// End index
-    end_index: usize,
-    /// Whether this table is visible
-    active: bool,
+    pub(crate) end_index: usize,
 }
 
 impl TableEntry {
     /// Initalize a table entry
     pub(crate) fn new(is_add_buffer: bool, start_index: usize, end_index: usize) -> TableEntry {
         TableEntry {
-            is_add_buffer: is_add_buffer, 
-            start_index: start_index, 
-            end_index: end_index, 
-            active: true,
+            is_add_buffer,
+            start_index,
+            end_index,
         }
     }
 
-    /// Change from active to deactivated and visa versa
-    fn switch(&mut self) {
-        self.active = !self.active;
+    /// Length of the text this table entry represents
+    pub(crate) fn len(&self) -> usize {
+        self.end_index - self.start_index
+    }
+
+    /// Returns two new table entries, split at `split_point` (relative to `start_index`)
+    pub(crate) fn split(&self, split_point: usize) -> (TableEntry, TableEntry) {
+        (TableEntry::new(self.is_add_buffer, self.start_index, self.start_index + split_point),
+            TableEntry::new(self.is_add_buffer, self.start_index + split_point, self.end_index))
+    }
+
+    /// Returns whether `other`'s text comes right after this one's in the same buffer
+    pub(crate) fn continues_with(&self, other: &TableEntry) -> bool {
+        self.is_add_buffer == other.is_add_buffer && self.end_index == other.start_index
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use unicode_segmentation::UnicodeSegmentation;
 
     #[test]
     fn add_text() {
         let mut piece_table = PieceTable::new();
         let mut want_str = "a";
         piece_table.add_text("a".to_string(), 0);
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
 
         piece_table.add_text(" b".to_string(), 1);
         want_str = "a b";
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
 
         piece_table.add_text("c ".to_string(), 2);
         want_str = "a c b";
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
 
         piece_table.add_text("d ".to_string(), 0);
         want_str = "d a c b";
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
     }
 
@@ -354,28 +946,28 @@ mod tests {
         piece_table.add_text("abc".to_string(), 0);
         piece_table.delete_text(0, 1);
         let mut want_str = "bc";
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
 
         piece_table = PieceTable::new();
         piece_table.add_text("abc".to_string(), 0);
         piece_table.delete_text(1, 2);
         want_str = "ac";
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
 
         piece_table = PieceTable::new();
         piece_table.add_text("abc".to_string(), 0);
         piece_table.delete_text(2, 3);
         want_str = "ab";
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
 
         piece_table = PieceTable::new();
         piece_table.add_text("abc".to_string(), 0);
         piece_table.delete_text(2, 3);
         want_str = "ab";
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
 
         piece_table = PieceTable::new();
@@ -383,7 +975,7 @@ mod tests {
         piece_table.add_text("cd".to_string(), 2);
         piece_table.delete_text(2, 3);
         want_str = "abd";
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
 
         piece_table = PieceTable::new();
@@ -392,7 +984,7 @@ mod tests {
         piece_table.add_text("ef".to_string(), 4);
         piece_table.delete_text(1, 5);
         want_str = "af";
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
 
         piece_table = PieceTable::new();
@@ -401,7 +993,7 @@ mod tests {
         piece_table.add_text("ef".to_string(), 4);
         piece_table.delete_text(1, 6);
         want_str = "a";
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
 
         piece_table = PieceTable::new();
@@ -410,7 +1002,7 @@ mod tests {
         piece_table.add_text("ef".to_string(), 4);
         piece_table.delete_text(0, 6);
         want_str = "";
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
     }
 
@@ -420,35 +1012,370 @@ mod tests {
         piece_table.add_text("abc".to_string(), 0);
         piece_table.delete_text(0, 1);
         let mut want_str = "abc";
-        piece_table.undo();
-        assert_eq!(piece_table.text_len, want_str.len());
+        piece_table.undo().unwrap();
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
 
-        piece_table.redo();
+        piece_table.redo().unwrap();
         want_str = "bc";
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
 
         piece_table = PieceTable::new();
         piece_table.add_text("abc".to_string(), 0);
         piece_table.add_text("d".to_string(), 3); // "abcd"
         piece_table.delete_text(0, 2); // "cd"
-        piece_table.undo();
+        piece_table.undo().unwrap();
         want_str = "abcd";
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
-        piece_table.undo();
+        piece_table.undo().unwrap();
         want_str = "abc";
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
-        piece_table.redo();
+        piece_table.redo().unwrap();
         want_str = "abcd";
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
-        piece_table.redo();
+        piece_table.redo().unwrap();
         want_str = "cd";
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(piece_table.text_len(), want_str.len());
+        assert_eq!(piece_table.text(), want_str);
+
+        piece_table = PieceTable::new();
+        assert!(piece_table.undo().is_err());
+        piece_table.add_text("abc".to_string(), 0);
+        piece_table.add_text("x".to_string(), 1); // "axbc"
+        piece_table.delete_text(0, 1); // "xbc"
+        assert_eq!(piece_table.undo(), Ok(0));
+        want_str = "axbc";
+        assert_eq!(piece_table.text(), want_str);
+        assert_eq!(piece_table.undo(), Ok(1));
+        want_str = "abc";
+        assert_eq!(piece_table.text(), want_str);
+        assert_eq!(piece_table.undo(), Ok(0));
+        want_str = "";
+        assert_eq!(piece_table.text(), want_str);
+        assert!(piece_table.undo().is_err());
+        piece_table.redo().unwrap();
+        piece_table.redo().unwrap();
+        piece_table.redo().unwrap();
+        want_str = "xbc";
+        assert_eq!(piece_table.text_len(), want_str.len());
+        assert_eq!(piece_table.text(), want_str);
+        assert!(piece_table.redo().is_err());
+    }
+
+    #[test]
+    fn undo_group() {
+        let mut piece_table = PieceTable::new();
+        piece_table.add_text("ab".to_string(), 0);
+        piece_table.begin_group();
+        piece_table.add_text("c".to_string(), 1);
+        piece_table.add_text("d".to_string(), 2);
+        piece_table.delete_text(2, 3);
+        piece_table.add_text("e".to_string(), 2);
+        assert_eq!(piece_table.commit_group(), 4);
+        let mut want_str = "aceb";
+        assert_eq!(piece_table.text(), want_str);
+        assert_eq!(piece_table.undo(), Ok(1));
+        want_str = "ab";
+        assert_eq!(piece_table.text_len(), want_str.len());
+        assert_eq!(piece_table.text(), want_str);
+        piece_table.redo().unwrap();
+        want_str = "aceb";
+        assert_eq!(piece_table.text_len(), want_str.len());
+        assert_eq!(piece_table.text(), want_str);
+
+        piece_table.begin_group();
+        assert_eq!(piece_table.commit_group(), 0);
+        piece_table.undo().unwrap();
+        want_str = "ab";
         assert_eq!(piece_table.text(), want_str);
+        piece_table.undo().unwrap();
+        want_str = "";
+        assert_eq!(piece_table.text(), want_str);
+    }
+
+    #[test]
+    fn undo_tree() {
+        let mut piece_table = PieceTable::new();
+        piece_table.add_text("a".to_string(), 0);
+        piece_table.add_text("b".to_string(), 1);
+        piece_table.undo().unwrap();
+        piece_table.add_text("c".to_string(), 1);
+        let mut want_str = "ac";
+        assert_eq!(piece_table.text(), want_str);
+        assert!(piece_table.redo().is_err());
+
+        // Chronologically, "ab" came right before "ac"
+        piece_table.earlier(1).unwrap();
+        want_str = "ab";
+        assert_eq!(piece_table.text(), want_str);
+        piece_table.earlier(1).unwrap();
+        want_str = "a";
+        assert_eq!(piece_table.text(), want_str);
+        // Redo follows the branch we last came from
+        piece_table.redo().unwrap();
+        want_str = "ab";
+        assert_eq!(piece_table.text(), want_str);
+        piece_table.later(5).unwrap();
+        want_str = "ac";
+        assert_eq!(piece_table.text_len(), want_str.len());
+        assert_eq!(piece_table.text(), want_str);
+        assert!(piece_table.later(1).is_err());
+        piece_table.earlier(5).unwrap();
+        want_str = "";
+        assert_eq!(piece_table.text_len(), want_str.len());
+        assert_eq!(piece_table.text(), want_str);
+        assert!(piece_table.earlier(1).is_err());
+        piece_table.later(2).unwrap();
+        want_str = "ab";
+        assert_eq!(piece_table.text(), want_str);
+
+        let minute = Duration::from_secs(60);
+        piece_table.created = UNIX_EPOCH;
+        piece_table.actions[0].time = UNIX_EPOCH + minute;
+        piece_table.actions[1].time = UNIX_EPOCH + minute * 2;
+        piece_table.actions[2].time = UNIX_EPOCH + minute * 10;
+        piece_table.later_time(minute * 5).unwrap();
+        want_str = "ac";
+        assert_eq!(piece_table.text(), want_str);
+        piece_table.earlier_time(minute * 5).unwrap();
+        want_str = "ab";
+        assert_eq!(piece_table.text(), want_str);
+        piece_table.earlier_time(minute * 60).unwrap();
+        want_str = "";
+        assert_eq!(piece_table.text(), want_str);
+        piece_table.later_time(Duration::from_secs(1)).unwrap();
+        want_str = "a";
+        assert_eq!(piece_table.text(), want_str);
+        piece_table.later_time(Duration::MAX).unwrap();
+        want_str = "ac";
+        assert_eq!(piece_table.text(), want_str);
+    }
+
+    #[test]
+    fn lines() {
+        let mut piece_table = PieceTable::new();
+        assert_eq!(piece_table.line_count(), 1);
+        assert_eq!(piece_table.line_len(0), Some(0));
+        piece_table.update_original_buffer("ab\ncd\n".to_string());
+        piece_table.add_text("\nef\n".to_string(), 4);
+        piece_table.add_text("g".to_string(), 0);
+        let want_str = "gab\nc\nef\nd\n";
+        assert_eq!(piece_table.text(), want_str);
+        assert_eq!(piece_table.line_count(), 5);
+        let want_starts = [0, 4, 6, 9, 11];
+        let want_lens = [3, 1, 2, 1, 0];
+        for line in 0..5 {
+            assert_eq!(piece_table.line_to_offset(line), Some(want_starts[line]));
+            assert_eq!(piece_table.line_len(line), Some(want_lens[line]));
+        }
+        assert_eq!(piece_table.line_to_offset(5), None);
+        assert_eq!(piece_table.line_len(5), None);
+        for offset in 0..=want_str.len() {
+            assert_eq!(piece_table.offset_to_line(offset), want_str[..offset].matches('\n').count());
+        }
+        piece_table.delete_text(2, 8);
+        assert_eq!(piece_table.text(), "ga\nd\n");
+        assert_eq!(piece_table.line_count(), 3);
+        assert_eq!(piece_table.line_to_offset(1), Some(3));
+        piece_table.undo().unwrap();
+        assert_eq!(piece_table.line_count(), 5);
+    }
+
+    #[test]
+    fn trim_history() {
+        let mut piece_table = PieceTable::new();
+        piece_table.add_text("a".to_string(), 0);
+        piece_table.add_text("b".to_string(), 1);
+        piece_table.undo().unwrap();
+        piece_table.undo().unwrap();
+        piece_table.add_text("c".to_string(), 0);
+        piece_table.trim_history(2);
+        assert_eq!(piece_table.actions.len(), 1);
+        assert_eq!(piece_table.text(), "c");
+        piece_table.undo().unwrap();
+        assert_eq!(piece_table.text(), "");
+        assert!(!piece_table.actions_taken());
+        assert!(piece_table.is_saved());
+        piece_table.redo().unwrap();
+        assert_eq!(piece_table.text(), "c");
+        assert!(!piece_table.is_saved());
+
+        piece_table.add_text("d".to_string(), 1);
+        piece_table.mark_saved();
+        piece_table.trim_history(1);
+        assert_eq!(piece_table.text(), "cd");
+        assert!(piece_table.is_saved());
+        piece_table.undo().unwrap();
+        assert_eq!(piece_table.text(), "c");
+        assert!(piece_table.actions_taken());
+        assert!(!piece_table.is_saved());
+        assert!(piece_table.undo().is_err());
+        piece_table.compact();
+        assert_eq!(piece_table.add_buffer, "cd");
+        piece_table.redo().unwrap();
+        assert_eq!(piece_table.text(), "cd");
+    }
+
+    #[test]
+    fn journal() {
+        let mut piece_table = PieceTable::new();
+        piece_table.update_original_buffer("abc".to_string());
+        piece_table.add_text("x".to_string(), 0);
+        piece_table.start_journal();
+        piece_table.add_text("yz".to_string(), 2);
+        piece_table.delete_text(0, 2);
+        piece_table.undo().unwrap();
+        piece_table.undo().unwrap();
+        piece_table.undo().unwrap();
+        piece_table.redo().unwrap();
+        piece_table.redo().unwrap();
+        piece_table.compact();
+        // Replaying the journal on the text it started from gives the text
+        let journal = piece_table.take_journal();
+        let mut decoder = Decoder::new(&journal);
+        let mut text = "xabc".to_string();
+        while !decoder.is_empty() {
+            let index = decoder.read_usize(text.len()).unwrap();
+            let removed = decoder.read_usize(text.len() - index).unwrap();
+            let inserted = std::str::from_utf8(decoder.read_bytes().unwrap()).unwrap();
+            text.replace_range(index..index + removed, inserted);
+        }
+        assert_eq!(text, "xayzbc");
+        assert_eq!(piece_table.text(), text);
+        assert!(piece_table.take_journal().is_empty());
+        piece_table.stop_journal();
+        piece_table.add_text("x".to_string(), 0);
+        assert!(piece_table.take_journal().is_empty());
+    }
+
+    #[test]
+    fn write_to() {
+        let mut piece_table = PieceTable::new();
+        piece_table.update_original_buffer("abc".to_string());
+        piece_table.add_text("é".to_string(), 1);
+        piece_table.delete_text(3, 4);
+        let mut bytes = Vec::new();
+        assert_eq!(piece_table.write_to(&mut bytes).unwrap(), 4);
+        assert_eq!(bytes, "aéc".as_bytes());
+    }
+
+    #[test]
+    fn encode_decode() {
+        let original = |text: &str| {
+            let mut original_buffer = OriginalBuffer::new();
+            original_buffer.push_str(text);
+            original_buffer
+        };
+        let mut piece_table = PieceTable::new();
+        piece_table.update_original_buffer("abc".to_string());
+        piece_table.delete_text(0, 1);
+        piece_table.add_text("x".to_string(), 0);
+        let mut bytes = Vec::new();
+        piece_table.encode(&mut bytes);
+
+        let mut decoded = PieceTable::decode(&mut Decoder::new(&bytes), original("xbc")).unwrap();
+        assert_eq!(decoded.text(), "xbc");
+        decoded.undo().unwrap();
+        assert_eq!(decoded.text(), "bc");
+        decoded.undo().unwrap();
+        assert_eq!(decoded.text(), "abc");
+        assert!(PieceTable::decode(&mut Decoder::new(&bytes), original("xb")).is_err());
+
+        // Move the deletion of the first action past the end of the text it is made to.
+        // Its index comes after the text length, the add buffer, the number of actions,
+        // the parent, the time and the number of changes
+        bytes[58] = 3;
+        assert_eq!(PieceTable::decode(&mut Decoder::new(&bytes), original("xbc")).err(), Some("Invalid change in undo file".to_string()));
+    }
+
+    #[test]
+    fn compact() {
+        let mut piece_table = PieceTable::new();
+        piece_table.update_original_buffer("abc".to_string());
+        piece_table.add_text("x".to_string(), 1);
+        piece_table.undo().unwrap();
+        assert_eq!(piece_table.table.num_entries(), 2);
+        piece_table.compact();
+        assert_eq!(piece_table.table.num_entries(), 1);
+        assert_eq!(piece_table.text(), "abc");
+        piece_table.redo().unwrap();
+        assert_eq!(piece_table.text(), "axbc");
+
+        piece_table = PieceTable::new();
+        piece_table.update_original_buffer("x".to_string());
+        let mut want_str = "x".to_string();
+        for i in 0..UNDO_LEVELS {
+            let len = piece_table.text_len();
+            piece_table.add_text(format!("{}-", i % 10), len);
+            piece_table.delete_text(len + 1, len + 2);
+            want_str.push_str(&(i % 10).to_string());
+        }
+        assert_eq!(piece_table.text(), want_str);
+        assert!(piece_table.actions.len() <= UNDO_LEVELS);
+        assert!(piece_table.add_buffer.len() < 2 * UNDO_LEVELS);
+
+        let mut undos = 0;
+        while piece_table.undo().is_ok() {
+            undos += 1;
+        }
+        assert_eq!(undos, piece_table.actions.len());
+        assert_eq!(piece_table.text(), want_str[..want_str.len() - undos / 2]);
+        while piece_table.redo().is_ok() {}
+        assert_eq!(piece_table.text(), want_str);
+    }
+
+    #[test]
+    fn chunks() {
+        let mut piece_table = PieceTable::new();
+        piece_table.update_original_buffer("hello\n".to_string());
+        piece_table.update_original_buffer("world".to_string());
+        piece_table.add_text(", ".to_string(), 5);
+        piece_table.add_text("é".to_string(), 7);
+        let want_str = "hello, é\nworld";
+        assert_eq!(piece_table.chunks().collect::<Vec<&str>>(), vec!["hello", ", é", "\nworld"]);
+        assert_eq!(piece_table.chunks().to_string(), want_str);
+        for start in 0..=want_str.len() {
+            for end in start..=want_str.len() {
+                if want_str.is_char_boundary(start) && want_str.is_char_boundary(end) {
+                    assert_eq!(piece_table.slice(start..end).to_string(), want_str[start..end]);
+                }
+            }
+        }
+        assert_eq!(piece_table.chars_at(7).collect::<String>(), "é\nworld");
+        assert_eq!(piece_table.chars_at(want_str.len()).next(), None);
+        assert_eq!(PieceTable::new().chunks().next(), None);
+    }
+
+    #[test]
+    fn find() {
+        let mut piece_table = PieceTable::new();
+        piece_table.update_original_buffer("abcab\n".to_string());
+        piece_table.add_text("cabé".to_string(), 6);
+        piece_table.add_text("ab".to_string(), 0);
+        assert_eq!(piece_table.text(), "ababcab\ncabé");
+        let find = |pattern: &str, range: Range<usize>| piece_table.find(&Regex::new(pattern).unwrap(), range).map(|captures| captures.range());
+        let rfind = |pattern: &str, range: Range<usize>| piece_table.rfind(&Regex::new(pattern).unwrap(), range).map(|captures| captures.range());
+        let len = piece_table.text_len();
+        assert_eq!(find("abc", 0..len), Some(2..5));
+        assert_eq!(find("abc", 3..len), None);
+        assert_eq!(find("b\nca", 0..len), Some(6..10));
+        assert_eq!(find("bab", 0..1), None);
+        assert_eq!(find("aba", 0..1), Some(0..3));
+        assert_eq!(find("^ca", 1..len), Some(8..10));
+        assert_eq!(find("\\<ab", 1..len), None);
+        assert_eq!(find("b$", 0..len), Some(6..7));
+        assert_eq!(find("c.*", 5..len), Some(8..13));
+        assert_eq!(rfind("ab", 0..len), Some(9..11));
+        assert_eq!(rfind("ab", 0..9), Some(5..7));
+        assert_eq!(rfind("abab", 0..len), Some(0..4));
+        assert_eq!(rfind("bé", 0..len), Some(10..13));
+        assert_eq!(rfind("a\\w*", 0..len), Some(9..11));
+        assert_eq!(find("é", 11..len), Some(11..13));
+        assert_eq!(find("\\%(b\\|é\\)$", 11..len), Some(11..13));
     }
 
     #[test]
@@ -457,24 +1384,22 @@ mod tests {
         piece_table.add_text("\n".to_string(), 0);
         let mut want_str = "\n";
         assert_eq!(want_str.len(), 1);
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
 
         piece_table = PieceTable::new();
         piece_table.add_text("\n\n\n\n".to_string(), 0);
         want_str = "\n\n\n\n";
         assert_eq!(want_str.len(), 4);
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
 
-        // TODO: Add support for graphemes
-        // https://stackoverflow.com/a/46290728
         piece_table = PieceTable::new();
         piece_table.add_text("😀".to_string(), 0);
         want_str = "😀";
         assert_eq!(want_str.len(), 4);
-        // assert_eq!(want_str.graphemes(true).count(), 1);
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(want_str.graphemes(true).count(), 1);
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
 
         piece_table = PieceTable::new();
@@ -482,8 +1407,8 @@ mod tests {
         want_str = "é";
         assert_eq!(want_str.len(), 3);
         assert_eq!(want_str.chars().count(), 2);
-        // assert_eq!(want_str.graphemes(true).count(), 1);
-        assert_eq!(piece_table.text_len, want_str.len());
+        assert_eq!(want_str.graphemes(true).count(), 1);
+        assert_eq!(piece_table.text_len(), want_str.len());
         assert_eq!(piece_table.text(), want_str);
     }
 }
\ No newline at end of file
@@ -0,0 +1,491 @@
+use std::cmp::max;
+
+use super::piece_table::TableEntry;
+
+/// A balanced (AVL) tree of `TableEntry`'s, ordered by where they appear in the text.
+/// Every node keeps the length and number of newlines of its subtree, so finding
+/// an index, inserting and deleting are all O(log n)
+pub(crate) struct PieceTree {
+    /// All nodes, including freed ones
+    nodes: Vec<Node>,
+    /// Root node
+    root: Option<usize>,
+    /// Indices of freed nodes in `self.nodes`, to be reused
+    free: Vec<usize>,
+}
+
+/// A node of `PieceTree`
+struct Node {
+    /// The table entry this node represents
+    table_entry: TableEntry,
+    /// Number of newlines in `table_entry`
+    newlines: usize,
+    /// Left child
+    left: Option<usize>,
+    /// Right child
+    right: Option<usize>,
+    /// Height of the subtree (a leaf has a height of 1)
+    height: usize,
+    /// Length of the text of the subtree
+    len: usize,
+    /// Number of newlines in the subtree
+    subtree_newlines: usize,
+}
+
+impl PieceTree {
+    /// Initialize an empty tree
+    pub(crate) fn new() -> PieceTree {
+        PieceTree {
+            nodes: Vec::new(),
+            root: None,
+            free: Vec::new(),
+        }
+    }
+
+    /// Length of all text
+    pub(crate) fn len(&self) -> usize {
+        self.len_of(self.root)
+    }
+
+    /// Number of newlines in all text
+    pub(crate) fn newlines(&self) -> usize {
+        self.newlines_of(self.root)
+    }
+
+    /// Number of table entries
+    pub(crate) fn num_entries(&self) -> usize {
+        self.nodes.len() - self.free.len()
+    }
+
+    /// Returns all table entries in order
+    pub(crate) fn iter(&self) -> Iter<'_> {
+        Iter {tree: self, stack: Vec::new(), next: self.root}
+    }
+
+    /// Call `f` on every table entry. `f` must not change the text of the table entry
+    pub(crate) fn for_each_mut<F: FnMut(&mut TableEntry)>(&mut self, mut f: F) {
+        let mut stack: Vec<usize> = self.root.into_iter().collect();
+        while let Some(node) = stack.pop() {
+            f(&mut self.nodes[node].table_entry);
+            stack.extend(self.nodes[node].left);
+            stack.extend(self.nodes[node].right);
+        }
+    }
+
+    /// Returns the table entries in order, starting at the one containing `index`,
+    /// along with where `index` is in the text of that first table entry
+    pub(crate) fn iter_at(&self, mut index: usize) -> (Iter<'_>, usize) {
+        let mut stack = Vec::new();
+        let mut node = self.root;
+        while let Some(n) = node {
+            let left_len = self.len_of(self.nodes[n].left);
+            let entry_len = self.nodes[n].table_entry.len();
+            if index < left_len {
+                stack.push(n);
+                node = self.nodes[n].left;
+            } else if index < left_len + entry_len {
+                stack.push(n);
+                return (Iter {tree: self, stack, next: None}, index - left_len)
+            } else {
+                index -= left_len + entry_len;
+                node = self.nodes[n].right;
+            }
+        }
+        (Iter {tree: self, stack, next: None}, 0)
+    }
+
+    /// Insert `table_entry` so its text starts at `index`. If it continues the
+    /// table entry right before it, that entry is extended instead.
+    /// `newlines` returns the number of newlines in a table entry
+    pub(crate) fn insert<F: Fn(&TableEntry) -> usize>(&mut self, index: usize, table_entry: TableEntry, newlines: &F) {
+        if table_entry.len() == 0 {
+            return
+        }
+        let (left, right) = self.split(self.root, index, newlines);
+        let (left, middle) = match self.split_last(left) {
+            Some((rest, last)) if self.nodes[last].table_entry.continues_with(&table_entry) => {
+                let node = &mut self.nodes[last];
+                node.table_entry.end_index = table_entry.end_index;
+                node.newlines += newlines(&table_entry);
+                (rest, last)
+            },
+            Some((rest, last)) => (self.join(rest, last, None), self.new_node(table_entry, newlines)),
+            None => (None, self.new_node(table_entry, newlines)),
+        };
+        self.root = self.join(left, middle, right);
+    }
+
+    /// Remove the text from `start` to `end`, returning the removed table entries in order.
+    /// `newlines` returns the number of newlines in a table entry
+    pub(crate) fn remove<F: Fn(&TableEntry) -> usize>(&mut self, start: usize, end: usize, newlines: &F) -> Vec<TableEntry> {
+        let (left, rest) = self.split(self.root, start, newlines);
+        let (middle, right) = self.split(rest, end - start, newlines);
+        let mut removed = Vec::new();
+        self.drain(middle, &mut removed);
+        self.root = self.join2(left, right);
+        removed
+    }
+
+    /// Returns the table entry containing `index` and the index its text starts at.
+    /// At the boundary between two table entries, the later one is returned
+    pub(crate) fn find(&self, mut index: usize) -> Option<(&TableEntry, usize)> {
+        let mut node = self.root;
+        let mut start = 0;
+        while let Some(n) = node {
+            let left_len = self.len_of(self.nodes[n].left);
+            let entry_len = self.nodes[n].table_entry.len();
+            if index < left_len {
+                node = self.nodes[n].left;
+            } else if index < left_len + entry_len {
+                return Some((&self.nodes[n].table_entry, start + left_len))
+            } else {
+                index -= left_len + entry_len;
+                start += left_len + entry_len;
+                node = self.nodes[n].right;
+            }
+        }
+        None
+    }
+
+    /// Returns the index right after the `n`-th newline (1-indexed).
+    /// `find_newline` returns the index in a table entry's text of its `n`-th newline
+    pub(crate) fn after_newline<F: Fn(&TableEntry, usize) -> usize>(&self, mut n: usize, find_newline: &F) -> Option<usize> {
+        let mut node = self.root;
+        let mut start = 0;
+        while let Some(i) = node {
+            let n_node = &self.nodes[i];
+            let left_newlines = self.newlines_of(n_node.left);
+            if n <= left_newlines {
+                node = n_node.left;
+            } else if n <= left_newlines + n_node.newlines {
+                let entry_start = start + self.len_of(n_node.left);
+                return Some(entry_start + find_newline(&n_node.table_entry, n - left_newlines) + 1)
+            } else {
+                n -= left_newlines + n_node.newlines;
+                start += self.len_of(n_node.left) + n_node.table_entry.len();
+                node = n_node.right;
+            }
+        }
+        None
+    }
+
+    /// Returns the number of newlines before `index`.
+    /// `newlines` returns the number of newlines in a table entry
+    pub(crate) fn newlines_before<F: Fn(&TableEntry) -> usize>(&self, mut index: usize, newlines: &F) -> usize {
+        let mut node = self.root;
+        let mut count = 0;
+        while let Some(i) = node {
+            let n_node = &self.nodes[i];
+            let left_len = self.len_of(n_node.left);
+            if index < left_len {
+                node = n_node.left;
+                continue
+            }
+            count += self.newlines_of(n_node.left);
+            index -= left_len;
+            if index < n_node.table_entry.len() {
+                return count + newlines(&n_node.table_entry.split(index).0)
+            }
+            count += n_node.newlines;
+            index -= n_node.table_entry.len();
+            node = n_node.right;
+        }
+        count
+    }
+
+    /// Remove every table entry
+    pub(crate) fn clear(&mut self) {
+        self.nodes.clear();
+        self.free.clear();
+        self.root = None;
+    }
+
+    /// Add a new node for `table_entry`
+    fn new_node<F: Fn(&TableEntry) -> usize>(&mut self, table_entry: TableEntry, newlines: &F) -> usize {
+        let node_newlines = newlines(&table_entry);
+        let node = Node {
+            len: table_entry.len(),
+            table_entry,
+            newlines: node_newlines,
+            left: None,
+            right: None,
+            height: 1,
+            subtree_newlines: node_newlines,
+        };
+        match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            },
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            },
+        }
+    }
+
+    /// Free every node of the subtree `node`, adding its table entries to `table_entries` in order
+    fn drain(&mut self, node: Option<usize>, table_entries: &mut Vec<TableEntry>) {
+        if let Some(n) = node {
+            self.drain(self.nodes[n].left, table_entries);
+            table_entries.push(self.nodes[n].table_entry.clone());
+            self.free.push(n);
+            self.drain(self.nodes[n].right, table_entries);
+        }
+    }
+
+    fn height_of(&self, node: Option<usize>) -> usize {
+        node.map_or(0, |n| self.nodes[n].height)
+    }
+
+    fn len_of(&self, node: Option<usize>) -> usize {
+        node.map_or(0, |n| self.nodes[n].len)
+    }
+
+    fn newlines_of(&self, node: Option<usize>) -> usize {
+        node.map_or(0, |n| self.nodes[n].subtree_newlines)
+    }
+
+    /// Set the children of `node` and recalculate its summaries. Returns `node`
+    fn set_children(&mut self, node: usize, left: Option<usize>, right: Option<usize>) -> usize {
+        let height = 1 + max(self.height_of(left), self.height_of(right));
+        let len = self.len_of(left) + self.len_of(right) + self.nodes[node].table_entry.len();
+        let subtree_newlines = self.newlines_of(left) + self.newlines_of(right) + self.nodes[node].newlines;
+        let n = &mut self.nodes[node];
+        n.left = left;
+        n.right = right;
+        n.height = height;
+        n.len = len;
+        n.subtree_newlines = subtree_newlines;
+        node
+    }
+
+    fn rotate_left(&mut self, node: usize) -> usize {
+        let right = self.nodes[node].right.unwrap();
+        let (left, right_left, right_right) = (self.nodes[node].left, self.nodes[right].left, self.nodes[right].right);
+        let node = self.set_children(node, left, right_left);
+        self.set_children(right, Some(node), right_right)
+    }
+
+    fn rotate_right(&mut self, node: usize) -> usize {
+        let left = self.nodes[node].left.unwrap();
+        let (left_left, left_right, right) = (self.nodes[left].left, self.nodes[left].right, self.nodes[node].right);
+        let node = self.set_children(node, left_right, right);
+        self.set_children(left, left_left, Some(node))
+    }
+
+    /// Returns a balanced tree of `left`, then `middle`, then `right`
+    fn join(&mut self, left: Option<usize>, middle: usize, right: Option<usize>) -> Option<usize> {
+        let (left_height, right_height) = (self.height_of(left), self.height_of(right));
+        if left_height > right_height + 1 {
+            Some(self.join_right(left.unwrap(), middle, right))
+        } else if right_height > left_height + 1 {
+            Some(self.join_left(left, middle, right.unwrap()))
+        } else {
+            Some(self.set_children(middle, left, right))
+        }
+    }
+
+    /// `join` where `left` is the taller tree
+    fn join_right(&mut self, left: usize, middle: usize, right: Option<usize>) -> usize {
+        let (left_left, left_right) = (self.nodes[left].left, self.nodes[left].right);
+        if self.height_of(left_right) <= self.height_of(right) + 1 {
+            let new_right = self.set_children(middle, left_right, right);
+            if self.nodes[new_right].height <= self.height_of(left_left) + 1 {
+                self.set_children(left, left_left, Some(new_right))
+            } else {
+                let new_right = self.rotate_right(new_right);
+                let node = self.set_children(left, left_left, Some(new_right));
+                self.rotate_left(node)
+            }
+        } else {
+            let new_right = self.join_right(left_right.unwrap(), middle, right);
+            let node = self.set_children(left, left_left, Some(new_right));
+            if self.nodes[new_right].height <= self.height_of(left_left) + 1 {
+                node
+            } else {
+                self.rotate_left(node)
+            }
+        }
+    }
+
+    /// `join` where `right` is the taller tree
+    fn join_left(&mut self, left: Option<usize>, middle: usize, right: usize) -> usize {
+        let (right_left, right_right) = (self.nodes[right].left, self.nodes[right].right);
+        if self.height_of(right_left) <= self.height_of(left) + 1 {
+            let new_left = self.set_children(middle, left, right_left);
+            if self.nodes[new_left].height <= self.height_of(right_right) + 1 {
+                self.set_children(right, Some(new_left), right_right)
+            } else {
+                let new_left = self.rotate_left(new_left);
+                let node = self.set_children(right, Some(new_left), right_right);
+                self.rotate_right(node)
+            }
+        } else {
+            let new_left = self.join_left(left, middle, right_left.unwrap());
+            let node = self.set_children(right, Some(new_left), right_right);
+            if self.nodes[new_left].height <= self.height_of(right_right) + 1 {
+                node
+            } else {
+                self.rotate_right(node)
+            }
+        }
+    }
+
+    /// Returns a balanced tree of `left`, then `right`
+    fn join2(&mut self, left: Option<usize>, right: Option<usize>) -> Option<usize> {
+        match self.split_last(left) {
+            Some((rest, last)) => self.join(rest, last, right),
+            None => right,
+        }
+    }
+
+    /// Remove the last node of `node`. Returns the rest of the tree and the last node
+    fn split_last(&mut self, node: Option<usize>) -> Option<(Option<usize>, usize)> {
+        let n = node?;
+        let (left, right) = (self.nodes[n].left, self.nodes[n].right);
+        match self.split_last(right) {
+            Some((rest, last)) => Some((self.join(left, n, rest), last)),
+            None => Some((left, n)),
+        }
+    }
+
+    /// Split `node` into two trees, where the first contains the first `index` bytes of text.
+    /// A table entry containing `index` is split in two
+    fn split<F: Fn(&TableEntry) -> usize>(&mut self, node: Option<usize>, index: usize, newlines: &F) -> (Option<usize>, Option<usize>) {
+        let n = match node {
+            Some(n) => n,
+            None => return (None, None),
+        };
+        let (left, right) = (self.nodes[n].left, self.nodes[n].right);
+        let left_len = self.len_of(left);
+        let entry_len = self.nodes[n].table_entry.len();
+        if index <= left_len {
+            let (left_left, left_right) = self.split(left, index, newlines);
+            (left_left, self.join(left_right, n, right))
+        } else if index < left_len + entry_len {
+            let (first, second) = self.nodes[n].table_entry.split(index - left_len);
+            let second_node = self.new_node(second, newlines);
+            let first_newlines = newlines(&first);
+            self.nodes[n].table_entry = first;
+            self.nodes[n].newlines = first_newlines;
+            (self.join(left, n, None), self.join(None, second_node, right))
+        } else {
+            let (right_left, right_right) = self.split(right, index - left_len - entry_len, newlines);
+            (self.join(left, n, right_left), right_right)
+        }
+    }
+}
+
+/// In-order iterator over the table entries of a `PieceTree`
+#[derive(Clone)]
+pub(crate) struct Iter<'a> {
+    tree: &'a PieceTree,
+    /// Nodes whose left subtree is being visited
+    stack: Vec<usize>,
+    /// Next subtree to descend into
+    next: Option<usize>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a TableEntry;
+
+    fn next(&mut self) -> Option<&'a TableEntry> {
+        while let Some(n) = self.next {
+            self.stack.push(n);
+            self.next = self.tree.nodes[n].left;
+        }
+        let n = self.stack.pop()?;
+        self.next = self.tree.nodes[n].right;
+        Some(&self.tree.nodes[n].table_entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every table entry points to the add buffer, which is made up of only
+    /// newlines at indices divisible by 3
+    fn newlines(table_entry: &TableEntry) -> usize {
+        (table_entry.start_index..table_entry.end_index).filter(|i| i % 3 == 0).count()
+    }
+
+    /// Check the AVL and summary invariants of every node, returning the height
+    fn check(tree: &PieceTree, node: Option<usize>) -> usize {
+        let n = match node {
+            Some(n) => &tree.nodes[n],
+            None => return 0,
+        };
+        let left_height = check(tree, n.left);
+        let right_height = check(tree, n.right);
+        assert!(left_height <= right_height + 1 && right_height <= left_height + 1);
+        assert_eq!(n.height, 1 + max(left_height, right_height));
+        assert_eq!(n.len, tree.len_of(n.left) + tree.len_of(n.right) + n.table_entry.len());
+        assert_eq!(n.newlines, newlines(&n.table_entry));
+        assert_eq!(n.subtree_newlines, tree.newlines_of(n.left) + tree.newlines_of(n.right) + n.newlines);
+        n.height
+    }
+
+    #[test]
+    fn insert_remove() {
+        let mut tree = PieceTree::new();
+        // Mirror of the tree, as (start_index, end_index) of each byte
+        let mut want: Vec<usize> = Vec::new();
+        let mut add_buffer_len = 0;
+        let mut seed: usize = 7;
+        for i in 0..2000 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let index = if want.is_empty() {0} else {(seed >> 33) % (want.len() + 1)};
+            if i % 3 == 2 && index < want.len() {
+                let end = want.len().min(index + (seed >> 20) % 5 + 1);
+                let removed = tree.remove(index, end, &newlines);
+                let removed_bytes: Vec<usize> = removed.iter().flat_map(|e| e.start_index..e.end_index).collect();
+                assert_eq!(removed_bytes, want.drain(index..end).collect::<Vec<usize>>());
+            } else {
+                let len = (seed >> 40) % 4 + 1;
+                tree.insert(index, TableEntry::new(true, add_buffer_len, add_buffer_len + len), &newlines);
+                want.splice(index..index, add_buffer_len..add_buffer_len + len);
+                add_buffer_len += len;
+            }
+            let height = check(&tree, tree.root);
+            assert!(height <= 2 * (tree.num_entries() + 1).next_power_of_two().trailing_zeros() as usize + 1);
+        }
+        let bytes: Vec<usize> = tree.iter().flat_map(|e| e.start_index..e.end_index).collect();
+        assert_eq!(bytes, want);
+        assert_eq!(tree.len(), want.len());
+        assert_eq!(tree.newlines(), want.iter().filter(|i| *i % 3 == 0).count());
+        let newline_positions: Vec<usize> = want.iter().enumerate().filter(|(_, b)| *b % 3 == 0).map(|(i, _)| i).collect();
+        let find_newline = |table_entry: &TableEntry, n: usize| {
+            (table_entry.start_index..table_entry.end_index).filter(|i| i % 3 == 0).nth(n - 1).unwrap() - table_entry.start_index
+        };
+        for (n, position) in newline_positions.iter().enumerate() {
+            assert_eq!(tree.after_newline(n + 1, &find_newline), Some(position + 1));
+            assert_eq!(tree.newlines_before(*position, &newlines), n);
+            assert_eq!(tree.newlines_before(position + 1, &newlines), n + 1);
+        }
+        assert_eq!(tree.after_newline(newline_positions.len() + 1, &find_newline), None);
+        for index in (0..want.len()).step_by(7) {
+            let (iter, offset) = tree.iter_at(index);
+            let bytes: Vec<usize> = iter.flat_map(|e| e.start_index..e.end_index).skip(offset).collect();
+            assert_eq!(bytes, want[index..]);
+        }
+        let (table_entry, start) = tree.find(5).unwrap();
+        assert!(start <= 5 && 5 < start + table_entry.len());
+        assert!(tree.find(want.len()).is_none());
+    }
+
+    #[test]
+    fn insert_extends() {
+        let mut tree = PieceTree::new();
+        tree.insert(0, TableEntry::new(true, 0, 2), &newlines);
+        tree.insert(2, TableEntry::new(true, 2, 3), &newlines);
+        tree.insert(3, TableEntry::new(false, 3, 4), &newlines);
+        assert_eq!(tree.num_entries(), 2);
+        assert_eq!(tree.newlines(), 2);
+        tree.remove(1, 2, &newlines);
+        tree.insert(1, TableEntry::new(true, 1, 2), &newlines);
+        assert_eq!(tree.num_entries(), 3);
+        assert_eq!(tree.len(), 4);
+    }
+}
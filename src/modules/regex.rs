@@ -0,0 +1,779 @@
+use std::ops::Range;
+
+/// Maximum number of instructions a compiled pattern may have
+const MAX_INSTS: usize = 100_000;
+
+/// A compiled regular expression, using Vim's syntax (by default "magic", so
+/// `\(`, `\|`, `\+`, `\?`, `\=`, `\{n,m}`, `\<` and `\>` are special, while `.`,
+/// `*`, `[`, `^` and `$` are special without a backslash). `\v` makes all of them
+/// special without a backslash, `\V` only with one, and `\c` ignores case
+///
+/// Matching is done with a Pike VM over a stream of characters, so the text doesn't
+/// have to be in one piece and the time taken is linear in its length
+pub(crate) struct Regex {
+    insts: Vec<Inst>,
+    /// Number of capture positions, two for each group (including the whole match)
+    num_slots: usize,
+    ignore_case: bool,
+    /// Character every match must start with, if there is one
+    first: Option<char>,
+}
+
+/// Where the groups of a match start and end, as byte indexes
+#[derive(Debug, PartialEq)]
+pub(crate) struct Captures {
+    slots: Vec<Option<usize>>,
+}
+
+impl Captures {
+    /// Returns the range of the whole match
+    pub(crate) fn range(&self) -> Range<usize> {
+        self.get(0).unwrap()
+    }
+
+    /// Returns the range of `group` (0 being the whole match), if it took part in the match
+    pub(crate) fn get(&self, group: usize) -> Option<Range<usize>> {
+        match (self.slots.get(2 * group)?, self.slots.get(2 * group + 1)?) {
+            (Some(start), Some(end)) => Some(*start..*end),
+            _ => None,
+        }
+    }
+}
+
+impl Regex {
+    /// Compile `pattern`, returning a Vim style error message if it is invalid
+    pub(crate) fn new(pattern: &str) -> Result<Regex, String> {
+        let mut parser = Parser {
+            chars: pattern.chars().collect(),
+            pos: 0,
+            magic: Magic::On,
+            ignore_case: false,
+            groups: 0,
+        };
+        let node = parser.parse_alternation()?;
+        if parser.peek()? == Some(Token::Op(')')) {
+            return Err(format!("E55: Unmatched {}", parser.op_name(')')))
+        }
+        let mut compiler = Compiler {insts: vec![Inst::Save(0)]};
+        compiler.compile(&node)?;
+        compiler.push(Inst::Save(1))?;
+        compiler.push(Inst::Match)?;
+        let first = match compiler.insts[1] {
+            Inst::Char(c) => Some(c),
+            _ => None,
+        };
+        Ok(Regex {
+            insts: compiler.insts,
+            num_slots: 2 * (parser.groups + 1),
+            ignore_case: parser.ignore_case,
+            first,
+        })
+    }
+
+    /// Returns the number of groups, including the whole match
+    pub(crate) fn num_groups(&self) -> usize {
+        self.num_slots / 2
+    }
+
+    /// Returns the first match which starts in `range`, in the text `chars` which
+    /// starts at `range.start` and may go on past `range.end`. `prev` is the character
+    /// before `chars`, if there is one. Of the matches starting at the same place,
+    /// the one found by trying alternatives and repetitions in order is returned
+    pub(crate) fn find_at(&self, chars: impl Iterator<Item = char>, prev: Option<char>, range: Range<usize>) -> Option<Captures> {
+        self.run(chars, prev, range, false)
+    }
+
+    /// Returns where the last match which starts in `range` starts, with the text given as in `find_at`
+    pub(crate) fn find_last_start(&self, chars: impl Iterator<Item = char>, prev: Option<char>, range: Range<usize>) -> Option<usize> {
+        self.run(chars, prev, range, true).map(|captures| captures.range().start)
+    }
+
+    /// Run the VM over `chars`, starting a thread at each position in `range`. If
+    /// `last`, later threads take precedence over earlier ones and the VM keeps going
+    /// after finding a match, to find the match which starts last
+    fn run(&self, chars: impl Iterator<Item = char>, mut prev: Option<char>, range: Range<usize>, last: bool) -> Option<Captures> {
+        let mut chars = chars;
+        let mut cur = chars.next();
+        let mut pos = range.start;
+        let mut list = Threads::new(self.insts.len());
+        // Threads which consumed the previous character, to be continued at `pos`
+        let mut pending: Vec<(usize, Vec<Option<usize>>)> = Vec::new();
+        let mut matched: Option<Vec<Option<usize>>> = None;
+        loop {
+            list.clear();
+            let start = pos < range.end && (last || matched.is_none()) && match (self.first, cur) {
+                (Some(first), Some(c)) => chars_eq(first, c, self.ignore_case),
+                (Some(_), None) => false,
+                (None, _) => true,
+            };
+            if start && last {
+                self.add_thread(&mut list, 0, vec![None; self.num_slots], pos, prev, cur);
+            }
+            for (pc, slots) in pending.drain(..) {
+                self.add_thread(&mut list, pc, slots, pos, prev, cur);
+            }
+            if start && !last {
+                self.add_thread(&mut list, 0, vec![None; self.num_slots], pos, prev, cur);
+            }
+            if list.threads.is_empty() && (cur.is_none() || pos >= range.end || (matched.is_some() && !last)) {
+                break
+            }
+            for (pc, slots) in list.threads.drain(..) {
+                match &self.insts[pc] {
+                    Inst::Match => {
+                        let later = matched.as_ref().is_none_or(|matched| matched[0] < slots[0]);
+                        if !last || later {
+                            matched = Some(slots);
+                        }
+                        if !last {
+                            // Threads after this one have a lower priority
+                            break
+                        }
+                    },
+                    inst => if cur.is_some_and(|c| inst.matches(c, self.ignore_case)) {
+                        pending.push((pc + 1, slots));
+                    },
+                }
+            }
+            match cur {
+                Some(c) => {
+                    pos += c.len_utf8();
+                    prev = Some(c);
+                    cur = chars.next();
+                },
+                None => break,
+            }
+        }
+        matched.map(|slots| Captures {slots})
+    }
+
+    /// Add the thread at `pc` to `list`, following jumps and splits (in order of
+    /// priority) until instructions which consume a character (or `Match`) are reached
+    fn add_thread(&self, list: &mut Threads, pc: usize, slots: Vec<Option<usize>>, pos: usize, prev: Option<char>, cur: Option<char>) {
+        let mut stack = vec![(pc, slots)];
+        while let Some((mut pc, mut slots)) = stack.pop() {
+            while list.visit(pc) {
+                match &self.insts[pc] {
+                    Inst::Jump(to) => pc = *to,
+                    Inst::Split(first, second) => {
+                        stack.push((*second, slots.clone()));
+                        pc = *first;
+                    },
+                    Inst::Save(slot) => {
+                        slots[*slot] = Some(pos);
+                        pc += 1;
+                    },
+                    Inst::Assert(assertion) => if assertion.holds(prev, cur) {
+                        pc += 1;
+                    } else {
+                        break
+                    },
+                    _ => {
+                        list.threads.push((pc, slots));
+                        break
+                    },
+                }
+            }
+        }
+    }
+}
+
+/// Threads of the VM at one position, in order of priority
+struct Threads {
+    threads: Vec<(usize, Vec<Option<usize>>)>,
+    /// For each instruction, the generation it was last visited in
+    visited: Vec<usize>,
+    generation: usize,
+}
+
+impl Threads {
+    fn new(len: usize) -> Threads {
+        Threads {threads: Vec::new(), visited: vec![0; len], generation: 0}
+    }
+
+    fn clear(&mut self) {
+        self.threads.clear();
+        self.generation += 1;
+    }
+
+    /// Mark `pc` as visited, returning false if it already was
+    fn visit(&mut self, pc: usize) -> bool {
+        if self.visited[pc] == self.generation {
+            return false
+        }
+        self.visited[pc] = self.generation;
+        true
+    }
+}
+
+enum Inst {
+    Char(char),
+    /// Any character but a newline
+    Any,
+    /// Any character in (or not in, if negated) the items. A negated class doesn't match a newline
+    Class(Vec<ClassItem>, bool),
+    Assert(Assertion),
+    /// Save the current position in a capture slot
+    Save(usize),
+    /// Continue at both, with the first having priority
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+impl Inst {
+    /// Returns whether `c` can be consumed by this instruction
+    fn matches(&self, c: char, ignore_case: bool) -> bool {
+        match self {
+            Inst::Char(want) => chars_eq(*want, c, ignore_case),
+            Inst::Any => c != '\n',
+            Inst::Class(items, negated) => {
+                let found = items.iter().any(|item| item.matches(c) || (ignore_case && (item.matches(lowercase(c)) || item.matches(uppercase(c)))));
+                found != *negated && !(*negated && c == '\n')
+            },
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Assertion {
+    LineStart,
+    LineEnd,
+    WordStart,
+    WordEnd,
+}
+
+impl Assertion {
+    /// Returns whether the assertion holds between `prev` and `cur`
+    fn holds(self, prev: Option<char>, cur: Option<char>) -> bool {
+        let (prev_word, cur_word) = (prev.is_some_and(is_word), cur.is_some_and(is_word));
+        match self {
+            Assertion::LineStart => prev.is_none_or(|c| c == '\n'),
+            Assertion::LineEnd => cur.is_none_or(|c| c == '\n'),
+            Assertion::WordStart => cur_word && !prev_word,
+            Assertion::WordEnd => prev_word && !cur_word,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ClassItem {
+    Range(char, char),
+    Space,
+    Digit,
+    Hex,
+    Word,
+    Alpha,
+    Alnum,
+    Lower,
+    Upper,
+    Punct,
+}
+
+impl ClassItem {
+    fn matches(self, c: char) -> bool {
+        match self {
+            ClassItem::Range(first, last) => first <= c && c <= last,
+            ClassItem::Space => c == ' ' || c == '\t',
+            ClassItem::Digit => c.is_ascii_digit(),
+            ClassItem::Hex => c.is_ascii_hexdigit(),
+            ClassItem::Word => c.is_ascii_alphanumeric() || c == '_',
+            ClassItem::Alpha => c.is_ascii_alphabetic(),
+            ClassItem::Alnum => c.is_ascii_alphanumeric(),
+            ClassItem::Lower => c.is_lowercase(),
+            ClassItem::Upper => c.is_uppercase(),
+            ClassItem::Punct => c.is_ascii_punctuation(),
+        }
+    }
+}
+
+/// Returns whether `c` is part of a word, for `\<` and `\>`
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn lowercase(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+fn uppercase(c: char) -> char {
+    c.to_uppercase().next().unwrap_or(c)
+}
+
+fn chars_eq(a: char, b: char, ignore_case: bool) -> bool {
+    a == b || (ignore_case && lowercase(a) == lowercase(b))
+}
+
+/// Parsed pattern
+enum Node {
+    Char(char),
+    Any,
+    Class(Vec<ClassItem>, bool),
+    Assert(Assertion),
+    /// Group, capturing as the given group number if there is one
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alternation(Vec<Node>),
+    /// Node repeated at least `min` times and at most `max` (if given) times, as many
+    /// times as possible if greedy, otherwise as few
+    Repeat {node: Box<Node>, min: usize, max: Option<usize>, greedy: bool},
+}
+
+/// Which characters are special without a backslash
+#[derive(Clone, Copy, PartialEq)]
+enum Magic {
+    /// `\v`: everything but letters, digits and `_`
+    Very,
+    /// `\m` (the default): `.`, `*`, `[`, `^` and `$`
+    On,
+    /// `\V`: only `^` and `$`
+    VeryOff,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token {
+    /// Character to be matched literally
+    Literal(char),
+    /// Special character, like `*` or `(` (written as `\(` in magic mode)
+    Op(char),
+    /// Character class like `\s`, negated if the bool is true
+    Class(ClassItem, bool),
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    magic: Magic,
+    ignore_case: bool,
+    /// Number of capturing groups so far
+    groups: usize,
+}
+
+impl Parser {
+    /// Returns the next token and moves past it
+    fn next(&mut self) -> Result<Option<Token>, String> {
+        loop {
+            let c = match self.chars.get(self.pos) {
+                Some(c) => *c,
+                None => return Ok(None),
+            };
+            self.pos += 1;
+            if c != '\\' {
+                let special = match self.magic {
+                    Magic::Very => "()|+?={<>%.*[^$".contains(c),
+                    Magic::On => ".*[^$".contains(c),
+                    Magic::VeryOff => "^$".contains(c),
+                };
+                return Ok(Some(if special {Token::Op(c)} else {Token::Literal(c)}))
+            }
+            let c = match self.chars.get(self.pos) {
+                Some(c) => *c,
+                None => return Ok(Some(Token::Literal('\\'))),
+            };
+            self.pos += 1;
+            let class = match c.to_ascii_lowercase() {
+                's' => Some(ClassItem::Space),
+                'd' => Some(ClassItem::Digit),
+                'x' => Some(ClassItem::Hex),
+                'w' => Some(ClassItem::Word),
+                'a' => Some(ClassItem::Alpha),
+                'l' => Some(ClassItem::Lower),
+                'u' => Some(ClassItem::Upper),
+                _ => None,
+            };
+            if let Some(class) = class {
+                return Ok(Some(Token::Class(class, c.is_ascii_uppercase())))
+            }
+            return Ok(Some(match c {
+                'c' | 'C' => {
+                    self.ignore_case |= c == 'c';
+                    continue
+                },
+                'v' | 'm' | 'V' | 'M' => {
+                    self.magic = match c {
+                        'v' => Magic::Very,
+                        'V' => Magic::VeryOff,
+                        _ => Magic::On,
+                    };
+                    continue
+                },
+                'n' => Token::Literal('\n'),
+                't' => Token::Literal('\t'),
+                'r' => Token::Literal('\r'),
+                'e' => Token::Literal('\u{1b}'),
+                '1'..='9' => return Err("E65: Back references are not supported".to_string()),
+                '(' | ')' | '|' | '+' | '?' | '=' | '{' | '<' | '>' | '%' if self.magic != Magic::Very => Token::Op(c),
+                '.' | '*' | '[' if self.magic == Magic::VeryOff => Token::Op(c),
+                _ => Token::Literal(c),
+            }))
+        }
+    }
+
+    /// Returns the next token without moving past it. Since `\c` applies to the
+    /// whole pattern, `ignore_case` is kept even if it was set by the peeked `\c`
+    fn peek(&mut self) -> Result<Option<Token>, String> {
+        let (pos, magic) = (self.pos, self.magic);
+        let token = self.next();
+        self.pos = pos;
+        self.magic = magic;
+        token
+    }
+
+    /// Returns how the special character `c` is written in the current mode
+    fn op_name(&self, c: char) -> String {
+        let plain = match self.magic {
+            Magic::Very => true,
+            Magic::On => ".*[^$".contains(c),
+            Magic::VeryOff => "^$".contains(c),
+        };
+        if plain {c.to_string()} else {format!("\\{}", c)}
+    }
+
+    /// Parse branches separated by `\|`
+    fn parse_alternation(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.parse_concat()?];
+        while self.peek()? == Some(Token::Op('|')) {
+            self.next()?;
+            branches.push(self.parse_concat()?);
+        }
+        Ok(if branches.len() == 1 {branches.pop().unwrap()} else {Node::Alternation(branches)})
+    }
+
+    /// Parse a sequence of (possibly repeated) atoms, up to the end of the branch
+    fn parse_concat(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        loop {
+            let token = match self.peek()? {
+                None | Some(Token::Op('|')) | Some(Token::Op(')')) => break,
+                Some(token) => token,
+            };
+            self.next()?;
+            let at_end = matches!(self.peek()?, None | Some(Token::Op('|')) | Some(Token::Op(')')));
+            let atom = match token {
+                Token::Literal(c) => Node::Char(c),
+                Token::Class(item, negated) => Node::Class(vec![item], negated),
+                Token::Op('^') if nodes.is_empty() => Node::Assert(Assertion::LineStart),
+                Token::Op('$') if at_end => Node::Assert(Assertion::LineEnd),
+                Token::Op('.') => Node::Any,
+                Token::Op('[') => self.parse_class(),
+                Token::Op('<') => Node::Assert(Assertion::WordStart),
+                Token::Op('>') => Node::Assert(Assertion::WordEnd),
+                Token::Op('(') => self.parse_group(true)?,
+                Token::Op('%') if self.chars.get(self.pos) == Some(&'(') => {
+                    self.pos += 1;
+                    self.parse_group(false)?
+                },
+                Token::Op('%') => return Err(format!("E71: Invalid character after {}", self.op_name('%'))),
+                Token::Op('*') if nodes.is_empty() => Node::Char('*'),
+                Token::Op(c) if "*+?={".contains(c) => return Err(format!("E64: {} follows nothing", self.op_name(c))),
+                Token::Op(c) => Node::Char(c),
+            };
+            nodes.push(self.parse_quantifier(atom)?);
+        }
+        Ok(if nodes.len() == 1 {nodes.pop().unwrap()} else {Node::Concat(nodes)})
+    }
+
+    /// Parse the rest of a group, after its opening parenthesis
+    fn parse_group(&mut self, capture: bool) -> Result<Node, String> {
+        let group = if capture {
+            self.groups += 1;
+            Some(self.groups)
+        } else {
+            None
+        };
+        let node = self.parse_alternation()?;
+        match self.next()? {
+            Some(Token::Op(')')) => Ok(Node::Group(Box::new(node), group)),
+            _ => Err(format!("E54: Unmatched {}", if capture {self.op_name('(')} else {self.op_name('%') + "("})),
+        }
+    }
+
+    /// Parse any quantifier after `atom`
+    fn parse_quantifier(&mut self, atom: Node) -> Result<Node, String> {
+        let quantifier = match self.peek()? {
+            Some(Token::Op(c)) if "*+?={".contains(c) => c,
+            _ => return Ok(atom),
+        };
+        self.next()?;
+        let (min, max, greedy) = match quantifier {
+            '*' => (0, None, true),
+            '+' => (1, None, true),
+            '{' => self.parse_braces()?,
+            _ => (0, Some(1), true),
+        };
+        if let Some(Token::Op(c)) = self.peek()? {
+            if "*+?={".contains(c) {
+                return Err(format!("E62: Nested {}", self.op_name(c)))
+            }
+        }
+        Ok(Node::Repeat {node: Box::new(atom), min, max, greedy})
+    }
+
+    /// Parse the rest of `\{n,m}`, `\{-n,m}` (as few as possible) or one of their
+    /// shorter forms, after the opening brace
+    fn parse_braces(&mut self) -> Result<(usize, Option<usize>, bool), String> {
+        let start = self.pos;
+        let end = match self.chars[start..].iter().position(|c| *c == '}') {
+            Some(end) => start + end,
+            None => return Err(format!("E554: Syntax error in {}...}}", self.op_name('{'))),
+        };
+        let mut inside: String = self.chars[start..end].iter().collect();
+        self.pos = end + 1;
+        if inside.ends_with('\\') {
+            inside.pop();
+        }
+        let greedy = !inside.starts_with('-');
+        let inside = inside.trim_start_matches('-');
+        let number = |text: &str| if text.is_empty() {Ok(None)} else {text.parse().map(Some)};
+        let bounds = match inside.find(',') {
+            Some(comma) => number(&inside[..comma]).and_then(|min| Ok((min.unwrap_or(0), number(&inside[comma + 1..])?))),
+            None => number(inside).map(|n| match n {
+                Some(n) => (n, Some(n)),
+                None => (0, None),
+            }),
+        };
+        match bounds {
+            Ok((min, max)) if max.is_none_or(|max| min <= max) => Ok((min, max, greedy)),
+            _ => Err(format!("E554: Syntax error in {}...}}", self.op_name('{'))),
+        }
+    }
+
+    /// Parse the rest of a `[]` collection, after the `[`. If there is no closing
+    /// `]`, the `[` is taken literally
+    fn parse_class(&mut self) -> Node {
+        let start = self.pos;
+        let negated = self.chars.get(self.pos) == Some(&'^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut items = Vec::new();
+        let mut first = true;
+        loop {
+            let mut c = match self.chars.get(self.pos) {
+                Some(']') if !first => break,
+                Some(c) => *c,
+                None => {
+                    self.pos = start;
+                    return Node::Char('[')
+                },
+            };
+            first = false;
+            self.pos += 1;
+            if c == '[' && self.chars.get(self.pos) == Some(&':') {
+                let rest: String = self.chars[self.pos..].iter().collect();
+                let named = [
+                    (":alpha:]", ClassItem::Alpha), (":digit:]", ClassItem::Digit), (":alnum:]", ClassItem::Alnum),
+                    (":space:]", ClassItem::Space), (":blank:]", ClassItem::Space), (":upper:]", ClassItem::Upper),
+                    (":lower:]", ClassItem::Lower), (":punct:]", ClassItem::Punct), (":xdigit:]", ClassItem::Hex),
+                ];
+                if let Some((name, item)) = named.iter().find(|(name, _)| rest.starts_with(name)) {
+                    self.pos += name.len();
+                    items.push(*item);
+                    continue
+                }
+            }
+            if c == '\\' {
+                let escaped = match self.chars.get(self.pos) {
+                    Some('n') => Some('\n'),
+                    Some('t') => Some('\t'),
+                    Some('r') => Some('\r'),
+                    Some('e') => Some('\u{1b}'),
+                    Some(c) if "\\]^-".contains(*c) => Some(*c),
+                    _ => None,
+                };
+                if let Some(escaped) = escaped {
+                    self.pos += 1;
+                    c = escaped;
+                }
+            }
+            match (self.chars.get(self.pos), self.chars.get(self.pos + 1)) {
+                (Some('-'), Some(last)) if *last != ']' => {
+                    items.push(ClassItem::Range(c, *last));
+                    self.pos += 2;
+                },
+                _ => items.push(ClassItem::Range(c, c)),
+            }
+        }
+        self.pos += 1;
+        Node::Class(items, negated)
+    }
+}
+
+struct Compiler {
+    insts: Vec<Inst>,
+}
+
+impl Compiler {
+    fn push(&mut self, inst: Inst) -> Result<usize, String> {
+        if self.insts.len() >= MAX_INSTS {
+            return Err("E363: Pattern uses too much memory".to_string())
+        }
+        self.insts.push(inst);
+        Ok(self.insts.len() - 1)
+    }
+
+    fn compile(&mut self, node: &Node) -> Result<(), String> {
+        match node {
+            Node::Char(c) => {
+                self.push(Inst::Char(*c))?;
+            },
+            Node::Any => {
+                self.push(Inst::Any)?;
+            },
+            Node::Class(items, negated) => {
+                self.push(Inst::Class(items.clone(), *negated))?;
+            },
+            Node::Assert(assertion) => {
+                self.push(Inst::Assert(*assertion))?;
+            },
+            Node::Group(node, group) => {
+                if let Some(group) = group {
+                    self.push(Inst::Save(2 * group))?;
+                }
+                self.compile(node)?;
+                if let Some(group) = group {
+                    self.push(Inst::Save(2 * group + 1))?;
+                }
+            },
+            Node::Concat(nodes) => for node in nodes {
+                self.compile(node)?;
+            },
+            Node::Alternation(branches) => {
+                let mut jumps = Vec::new();
+                for (i, branch) in branches.iter().enumerate() {
+                    if i == branches.len() - 1 {
+                        self.compile(branch)?;
+                    } else {
+                        let split = self.push(Inst::Split(0, 0))?;
+                        self.compile(branch)?;
+                        jumps.push(self.push(Inst::Jump(0))?);
+                        self.insts[split] = Inst::Split(split + 1, self.insts.len());
+                    }
+                }
+                let end = self.insts.len();
+                for jump in jumps {
+                    self.insts[jump] = Inst::Jump(end);
+                }
+            },
+            Node::Repeat {node, min, max, greedy} => {
+                for _ in 0..*min {
+                    self.compile(node)?;
+                }
+                match max {
+                    None => {
+                        let split = self.push(Inst::Split(0, 0))?;
+                        self.compile(node)?;
+                        self.push(Inst::Jump(split))?;
+                        self.insts[split] = self.split(split + 1, self.insts.len(), *greedy);
+                    },
+                    Some(max) => {
+                        let mut splits = Vec::new();
+                        for _ in *min..*max {
+                            splits.push(self.push(Inst::Split(0, 0))?);
+                            self.compile(node)?;
+                        }
+                        let end = self.insts.len();
+                        for split in splits {
+                            self.insts[split] = self.split(split + 1, end, *greedy);
+                        }
+                    },
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Returns a split to `more` (another repetition) and `done`, preferring `more` if `greedy`
+    fn split(&self, more: usize, done: usize, greedy: bool) -> Inst {
+        if greedy {Inst::Split(more, done)} else {Inst::Split(done, more)}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the text of each group of the first match of `pattern` in `text`
+    fn captures(pattern: &str, text: &str) -> Option<Vec<Option<String>>> {
+        let regex = Regex::new(pattern).unwrap();
+        let captures = regex.find_at(text.chars(), None, 0..text.len())?;
+        Some((0..regex.num_groups()).map(|group| captures.get(group).map(|range| text[range].to_string())).collect())
+    }
+
+    fn find(pattern: &str, text: &str) -> Option<String> {
+        captures(pattern, text).map(|captures| captures[0].clone().unwrap())
+    }
+
+    #[test]
+    fn matching() {
+        assert_eq!(find("b", "abc"), Some("b".to_string()));
+        assert_eq!(find("x", "abc"), None);
+        assert_eq!(find("a.c", "xabcx"), Some("abc".to_string()));
+        assert_eq!(find("a.c", "a\nc"), None);
+        assert_eq!(find("ab*", "abbbc"), Some("abbb".to_string()));
+        assert_eq!(find("ab\\+", "ac abb"), Some("abb".to_string()));
+        assert_eq!(find("ab\\?c", "ac"), Some("ac".to_string()));
+        assert_eq!(find("ab\\=c", "abc"), Some("abc".to_string()));
+        assert_eq!(find("a\\{2,3}", "a aaaa"), Some("aaa".to_string()));
+        assert_eq!(find("a\\{-1,}", "aaa"), Some("a".to_string()));
+        assert_eq!(find("a\\{2}", "aaa"), Some("aa".to_string()));
+        assert_eq!(find("x\\|b\\|a", "ab"), Some("a".to_string()));
+        assert_eq!(find("a\\|ab", "ab"), Some("a".to_string()));
+        assert_eq!(find("[b-d]\\+", "abcde"), Some("bcd".to_string()));
+        assert_eq!(find("[^a]", "a\nb"), Some("b".to_string()));
+        assert_eq!(find("[]x]", "a]"), Some("]".to_string()));
+        assert_eq!(find("[[:digit:]]\\+", "ab12"), Some("12".to_string()));
+        assert_eq!(find("a[", "a["), Some("a[".to_string()));
+        assert_eq!(find("\\d\\+\\s\\w", "a 12 b"), Some("12 b".to_string()));
+        assert_eq!(find("*a", "b*a"), Some("*a".to_string()));
+        assert_eq!(find("a$", "ab\nba\n"), Some("a".to_string()));
+        assert_eq!(find("^b", "ab\nba"), Some("b".to_string()));
+        assert_eq!(find("a$b", "a$b"), Some("a$b".to_string()));
+        assert_eq!(find("b\\nb", "ab\nba"), Some("b\nb".to_string()));
+        assert_eq!(find("\\<is\\>", "this is"), Some("is".to_string()));
+        assert_eq!(find("\\<th", "bath this"), Some("th".to_string()));
+        assert_eq!(find("\\cHELLO", "Say hello"), Some("hello".to_string()));
+        assert_eq!(find("[A-Z]\\c", "a"), Some("a".to_string()));
+        assert_eq!(find("\\v(ab)+c", "ababc"), Some("ababc".to_string()));
+        assert_eq!(find("\\v<a{2}>", "aaa aa"), Some("aa".to_string()));
+        assert_eq!(find("\\Va.b*", "axb a.b*"), Some("a.b*".to_string()));
+        assert_eq!(find("é\\+", "aéé"), Some("éé".to_string()));
+        assert_eq!(find("", "ab"), Some(String::new()));
+    }
+
+    #[test]
+    fn groups() {
+        let some = |groups: &[Option<&str>]| Some(groups.iter().map(|group| group.map(str::to_string)).collect::<Vec<_>>());
+        assert_eq!(captures("\\(a\\)\\(b\\)", "ab"), some(&[Some("ab"), Some("a"), Some("b")]));
+        assert_eq!(captures("\\(a\\)\\|\\(b\\)", "b"), some(&[Some("b"), None, Some("b")]));
+        assert_eq!(captures("\\%(a\\)\\(b\\)", "ab"), some(&[Some("ab"), Some("b")]));
+        assert_eq!(captures("\\(a*\\)*b", "aab"), some(&[Some("aab"), Some("aa")]));
+        assert_eq!(captures("\\(\\w\\+\\)=\\(\\w*\\)", "x key=value"), some(&[Some("key=value"), Some("key"), Some("value")]));
+    }
+
+    #[test]
+    fn find_last_start() {
+        let regex = Regex::new("ab\\|b").unwrap();
+        assert_eq!(regex.find_last_start("abab".chars(), None, 0..4), Some(3));
+        assert_eq!(regex.find_last_start("abab".chars(), None, 0..3), Some(2));
+        let regex = Regex::new("a.*c").unwrap();
+        assert_eq!(regex.find_last_start("aac ac".chars(), None, 0..6), Some(4));
+        assert_eq!(regex.find_last_start("aac ab".chars(), None, 0..6), Some(1));
+        assert_eq!(regex.find_last_start("b".chars(), None, 0..1), None);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Regex::new("\\(a").err(), Some("E54: Unmatched \\(".to_string()));
+        assert_eq!(Regex::new("\\v(a").err(), Some("E54: Unmatched (".to_string()));
+        assert_eq!(Regex::new("a\\)").err(), Some("E55: Unmatched \\)".to_string()));
+        assert_eq!(Regex::new("\\+").err(), Some("E64: \\+ follows nothing".to_string()));
+        assert_eq!(Regex::new("a**").err(), Some("E62: Nested *".to_string()));
+        assert_eq!(Regex::new("a\\{2").err(), Some("E554: Syntax error in \\{...}".to_string()));
+        assert_eq!(Regex::new("a\\{3,2}").err(), Some("E554: Syntax error in \\{...}".to_string()));
+        assert_eq!(Regex::new("\\%a").err(), Some("E71: Invalid character after \\%".to_string()));
+        assert_eq!(Regex::new("a\\1").err(), Some("E65: Back references are not supported".to_string()));
+        assert_eq!(Regex::new("\\(a\\{1000}\\)\\{1000}").err(), Some("E363: Pattern uses too much memory".to_string()));
+    }
+}
@@ -0,0 +1,113 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{fchown, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Replace the file at `path` with what `write` writes, so that the file is never left
+/// partly written: the text is written to a temporary file in the same directory, which
+/// is synced and then renamed over the file. Symbolic links are followed, and the file
+/// keeps its permissions (unless `mode` gives the ones it gets), and its owner and group
+/// if they can be set. If `backup`, the file is first copied to `path~`
+pub(crate) fn save(path: &str, backup: bool, mode: Option<u32>, write: impl FnOnce(&mut File) -> io::Result<()>) -> Result<(), String> {
+    // Replace what a link points to rather than the link
+    let path = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+    let metadata = fs::metadata(&path).ok();
+    if backup && metadata.is_some() {
+        let mut backup_path = path.clone().into_os_string();
+        backup_path.push("~");
+        fs::copy(&path, &backup_path).map_err(|e| format!("E510: Can't make backup file: {}", e))?;
+    }
+    let temp_path = temp_path(&path)?;
+    let mut file = OpenOptions::new().write(true).create_new(true).mode(mode.unwrap_or(0o666)).open(&temp_path)
+        .map_err(|e| e.to_string())?;
+    let result = write(&mut file).and_then(|_| {
+        if let Some(metadata) = &metadata {
+            if mode.is_none() {
+                file.set_permissions(metadata.permissions())?;
+            }
+            // Only root can give a file away, but the group may still be kept
+            if fchown(&file, Some(metadata.uid()), Some(metadata.gid())).is_err() {
+                let _ = fchown(&file, None, Some(metadata.gid()));
+            }
+        }
+        file.sync_all()
+    }).and_then(|_| fs::rename(&temp_path, &path));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e.to_string())
+    }
+    // Make sure the rename itself is on disk
+    if let Some(dir) = path.parent() {
+        let _ = File::open(dir).and_then(|dir| dir.sync_all());
+    }
+    Ok(())
+}
+
+/// Returns the path of a temporary file to write `path` to, next to it so that it can
+/// be renamed to `path`
+fn temp_path(path: &Path) -> Result<PathBuf, String> {
+    let name = path.file_name().ok_or_else(|| format!("\"{}\" is not a file name", path.display()))?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(format!(".{}.via-tmp", process::id()));
+    Ok(path.with_file_name(temp_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    /// Returns a new empty directory for a test called `name`
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("via-save-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn save_file() {
+        let dir = test_dir("save");
+        let path = dir.join("file");
+        let path_str = path.to_str().unwrap();
+        save(path_str, false, None, |file| file.write_all(b"new")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        save(path_str, true, None, |file| file.write_all(b"newer")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "newer");
+        assert_eq!(fs::read_to_string(dir.join("file~")).unwrap(), "new");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+        save(path_str, false, Some(0o600), |file| file.write_all(b"private")).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let link = dir.join("link");
+        symlink(&path, &link).unwrap();
+        save(link.to_str().unwrap(), false, None, |file| file.write_all(b"linked")).unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "linked");
+
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_failure() {
+        let dir = test_dir("failure");
+        let path = dir.join("file");
+        fs::write(&path, "original").unwrap();
+        // Fail after writing part of the text, as if the disk were full
+        let result = save(path.to_str().unwrap(), false, None, |file| {
+            file.write_all(b"partly")?;
+            Err(io::Error::other("No space left on device"))
+        });
+        assert_eq!(result, Err("No space left on device".to_string()));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "original");
+        // The temporary file is removed
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+use super::editor::Editor;
+use super::ex::{split_at, Registry, RANGE};
+use super::regex::{Captures, Regex};
+
+/// A `:substitute` being carried out, one match at a time so that each can be confirmed
+pub(crate) struct Substitution {
+    /// Pattern as typed, to be searched for again with `n`
+    pattern: String,
+    regex: Regex,
+    /// Replacement, where `&` and `\0` stand for the whole match, `\1` to `\9` for its
+    /// groups and `\r` (or `\n`) for a line break
+    replacement: String,
+    /// Whether to replace every match in a row, rather than only the first (`g`)
+    global: bool,
+    /// Whether to ask before replacing each match (`c`)
+    confirm: bool,
+    /// Whether not to complain if there are no matches (`e`)
+    quiet: bool,
+    /// Rows to substitute in. The last one moves as replacements add or remove lines
+    first_row: usize,
+    last_row: usize,
+    /// Where the cursor was before the substitution, to go back to if nothing changes
+    cursor: (usize, usize),
+    /// Index to look for the next match from, or `None` once past the last row
+    from: Option<usize>,
+    /// Match the cursor is at, waiting to be replaced or skipped
+    current: Option<Captures>,
+    /// Whether any match was found
+    found: bool,
+    /// Number of substitutions made
+    count: usize,
+    /// Number of rows substitutions were made on
+    rows: usize,
+    /// Row the last substitution was made on
+    last_changed: Option<usize>,
+}
+
+impl Substitution {
+    /// Parse `args` (such as `/pattern/replacement/flags`) to substitute in the rows
+    /// `first` to `last`. An empty pattern means `last_pattern`. The flags are `g`,
+    /// `c`, `e`, `i` (ignore case) and `I` (don't)
+    pub(crate) fn new(args: &str, last_pattern: Option<&str>, first: usize, last: usize) -> Result<Substitution, String> {
+        let delimiter = match args.chars().next() {
+            None => return Err("E35: No previous regular expression".to_string()),
+            Some(c) if c.is_alphanumeric() || " \\\"|".contains(c) => {
+                return Err("E146: Regular expressions can't be delimited by letters".to_string())
+            },
+            Some(c) => c,
+        };
+        let (pattern, rest) = split_at(&args[delimiter.len_utf8()..], delimiter);
+        let (replacement, flags) = split_at(rest.unwrap_or(""), delimiter);
+        let (mut global, mut confirm, mut quiet, mut ignore_case) = (false, false, false, false);
+        for flag in flags.unwrap_or("").trim().chars() {
+            match flag {
+                'g' => global = true,
+                'c' => confirm = true,
+                'e' => quiet = true,
+                'i' => ignore_case = true,
+                'I' => ignore_case = false,
+                _ => return Err(format!("E488: Trailing characters: {}", flags.unwrap_or("").trim())),
+            }
+        }
+        let pattern = match (pattern, last_pattern) {
+            ("", Some(last_pattern)) => last_pattern,
+            ("", None) => return Err("E35: No previous regular expression".to_string()),
+            (pattern, _) => pattern,
+        };
+        let regex = Regex::new(&if ignore_case {format!("\\c{}", pattern)} else {pattern.to_string()})?;
+        Ok(Substitution {
+            pattern: pattern.to_string(),
+            regex,
+            replacement: replacement.to_string(),
+            global,
+            confirm,
+            quiet,
+            first_row: first,
+            last_row: last,
+            cursor: (1, 1),
+            from: None,
+            current: None,
+            found: false,
+            count: 0,
+            rows: 0,
+            last_changed: None,
+        })
+    }
+
+    /// Returns the pattern searched for
+    pub(crate) fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Returns whether each match should be confirmed before it is replaced
+    pub(crate) fn confirm(&self) -> bool {
+        self.confirm
+    }
+
+    /// Returns the question to ask before replacing the current match
+    pub(crate) fn prompt(&self) -> String {
+        format!("replace with {} (y/n/a/q/l)?", self.replacement)
+    }
+
+    /// Returns the start (inclusive) and end (exclusive) of the current match, as (row, col)
+    pub(crate) fn current(&self, editor: &Editor) -> Option<((usize, usize), (usize, usize))> {
+        let range = self.current.as_ref()?.range();
+        Some((editor.index_to_position(range.start), editor.index_to_position(range.end)))
+    }
+
+    /// Start substituting, as a single undoable action, and move the cursor to the
+    /// first match. Returns false if there is none
+    pub(crate) fn start(&mut self, editor: &mut Editor) -> bool {
+        self.cursor = (editor.row(), editor.col());
+        editor.goto(self.first_row, 1);
+        self.from = Some(editor.index());
+        editor.begin_group();
+        self.next(editor)
+    }
+
+    /// Replace the current match and move to the next. Returns false if there is none
+    pub(crate) fn replace(&mut self, editor: &mut Editor) -> bool {
+        let captures = match self.current.take() {
+            Some(captures) => captures,
+            None => return false,
+        };
+        let range = captures.range();
+        let text = self.expand(&captures, editor);
+        let newlines = |text: &str| text.matches('\n').count();
+        let row = editor.index_to_position(range.start).0;
+        let matched = editor.text_between(range.clone());
+        self.last_row = (self.last_row + newlines(&text)).saturating_sub(newlines(&matched)).max(row);
+        if self.last_changed != Some(row) {
+            self.rows += 1;
+        }
+        self.count += 1;
+        let end = range.start + text.len();
+        editor.replace_text(range.clone(), text);
+        self.last_changed = Some(editor.index_to_position(end).0);
+        self.advance(editor, range.start, end, &matched);
+        self.next(editor)
+    }
+
+    /// Leave the current match as it is and move to the next. Returns false if there is none
+    pub(crate) fn skip(&mut self, editor: &mut Editor) -> bool {
+        if let Some(captures) = self.current.take() {
+            let range = captures.range();
+            self.advance(editor, range.start, range.end, &editor.text_between(range.clone()));
+        }
+        self.next(editor)
+    }
+
+    /// Replace the current match and all the ones after it
+    pub(crate) fn replace_all(&mut self, editor: &mut Editor) {
+        while self.replace(editor) {}
+    }
+
+    /// Finish substituting, moving the cursor to the start of the last row changed.
+    /// Returns the message to show, or an error if nothing was found
+    pub(crate) fn finish(&mut self, editor: &mut Editor) -> Result<String, String> {
+        editor.commit_group();
+        match self.last_changed {
+            Some(row) => editor.goto(row, 1),
+            None => editor.goto(self.cursor.0, self.cursor.1),
+        }
+        if !self.found && !self.quiet {
+            Err(format!("E486: Pattern not found: {}", self.pattern))
+        } else if self.count > 2 {
+            let plural = |n: usize| if n == 1 {""} else {"s"};
+            Ok(format!("{} substitution{} on {} line{}", self.count, plural(self.count), self.rows, plural(self.rows)))
+        } else {
+            Ok(String::new())
+        }
+    }
+
+    /// Move the cursor to the next match, if there is one
+    fn next(&mut self, editor: &mut Editor) -> bool {
+        self.current = self.from.and_then(|from| editor.find_before_row(&self.regex, from, self.last_row));
+        match &self.current {
+            Some(captures) => {
+                editor.goto_index(captures.range().start);
+                self.found = true;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Set where to look for the next match, after the text `matched` (or its
+    /// replacement) which is now from `start` to `end`
+    fn advance(&mut self, editor: &Editor, start: usize, end: usize, matched: &str) {
+        self.from = if !self.global && !matched.ends_with('\n') {
+            // Only the first match in each row is replaced. If the match ended a row, the
+            // row after it is what's left at `end`
+            let row = editor.index_to_position(if end > start {end - 1} else {start}).0;
+            (row < editor.num_lines()).then(|| editor.line_start(row + 1))
+        } else if matched.is_empty() {
+            // Don't find an empty match at the same place again
+            editor.char_at(end).map(|c| end + c.len_utf8())
+        } else {
+            Some(end)
+        };
+    }
+
+    /// Returns the replacement for the match `captures`
+    fn expand(&self, captures: &Captures, editor: &Editor) -> String {
+        let group = |group: usize| captures.get(group).map_or(String::new(), |range| editor.text_between(range));
+        let mut text = String::new();
+        let mut chars = self.replacement.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '&' => text.push_str(&group(0)),
+                '\\' => match chars.next() {
+                    Some(digit @ '0'..='9') => text.push_str(&group(digit.to_digit(10).unwrap() as usize)),
+                    Some('r') | Some('n') => text.push('\n'),
+                    Some('t') => text.push('\t'),
+                    Some(c) => text.push(c),
+                    None => text.push('\\'),
+                },
+                c => text.push(c),
+            }
+        }
+        text
+    }
+}
+
+/// Add `:substitute` to the commands in `registry`
+pub(crate) fn register(registry: &mut Registry) {
+    registry.add("s[ubstitute]", RANGE, |context, args| {
+        let last_pattern = context.last_search.as_ref().map(|(pattern, _)| pattern.as_str());
+        let mut substitution = Substitution::new(args.arg, last_pattern, args.rows.0, args.rows.1)?;
+        let forward = context.last_search.as_ref().is_none_or(|(_, forward)| *forward);
+        *context.last_search = Some((substitution.pattern().to_string(), forward));
+        if substitution.start(context.editor) && substitution.confirm() {
+            context.messages.print(substitution.prompt());
+            context.substitution = Some(substitution);
+        } else {
+            substitution.replace_all(context.editor);
+            let message = substitution.finish(context.editor)?;
+            context.messages.info(message);
+        }
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the text of an editor with `text` after running `command` on rows `first` to `last`
+    fn substitute(text: &str, command: &str, first: usize, last: usize) -> (String, String) {
+        let mut editor = Editor::new(String::new());
+        editor.add_text(text.to_string());
+        let mut substitution = Substitution::new(command, Some("last"), first, last).unwrap();
+        if substitution.start(&mut editor) {
+            substitution.replace_all(&mut editor);
+        }
+        let message = substitution.finish(&mut editor).unwrap_or_else(|err| err);
+        (editor.text(), message)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Substitution::new("", None, 1, 1).err(), Some("E35: No previous regular expression".to_string()));
+        assert_eq!(Substitution::new("//x/", None, 1, 1).err(), Some("E35: No previous regular expression".to_string()));
+        assert_eq!(Substitution::new("a b ", None, 1, 1).err(), Some("E146: Regular expressions can't be delimited by letters".to_string()));
+        assert_eq!(Substitution::new("/a/b/gx", None, 1, 1).err(), Some("E488: Trailing characters: gx".to_string()));
+        assert_eq!(Substitution::new("/\\(/b/", None, 1, 1).err(), Some("E54: Unmatched \\(".to_string()));
+        let substitution = Substitution::new("/a\\/b/c", None, 1, 1).unwrap();
+        assert_eq!((substitution.pattern(), substitution.replacement.as_str()), ("a\\/b", "c"));
+        let substitution = Substitution::new("//c/gc", Some("last"), 1, 1).unwrap();
+        assert_eq!((substitution.pattern(), substitution.global, substitution.confirm()), ("last", true, true));
+    }
+
+    #[test]
+    fn substitute_rows() {
+        assert_eq!(substitute("a a\na a\na a", "/a/b/", 1, 2), ("b a\nb a\na a".to_string(), String::new()));
+        assert_eq!(substitute("a a\na a\na a", "/a/b/g", 2, 3), ("a a\nb b\nb b".to_string(), "4 substitutions on 2 lines".to_string()));
+        assert_eq!(substitute("ab\nab", "/\\(a\\)\\(b\\)/\\2\\1&/", 1, 2), ("baab\nbaab".to_string(), String::new()));
+        assert_eq!(substitute("a,b\nc", "/,/\\r/", 1, 2), ("a\nb\nc".to_string(), String::new()));
+        assert_eq!(substitute("a\nb\nc", "/\\n/-/", 1, 2), ("a-b-c".to_string(), String::new()));
+        assert_eq!(substitute("abc", "/x*/-/g", 1, 1), ("-a-b-c-".to_string(), "4 substitutions on 1 line".to_string()));
+        assert_eq!(substitute("Last last", "//x/gi", 1, 1), ("x x".to_string(), String::new()));
+        assert_eq!(substitute("a", "/b/c/", 1, 1), ("a".to_string(), "E486: Pattern not found: b".to_string()));
+        assert_eq!(substitute("a", "/b/c/e", 1, 1), ("a".to_string(), String::new()));
+    }
+
+    #[test]
+    fn confirm() {
+        let mut editor = Editor::new(String::new());
+        editor.add_text("a a\na a".to_string());
+        editor.goto(1, 1);
+        let mut substitution = Substitution::new("/a/b/gc", None, 1, 2).unwrap();
+        assert!(substitution.start(&mut editor));
+        assert_eq!(substitution.current(&editor), Some(((1, 1), (1, 2))));
+        assert!(substitution.skip(&mut editor));
+        assert_eq!(substitution.current(&editor), Some(((1, 3), (1, 4))));
+        assert!(substitution.replace(&mut editor));
+        assert_eq!((editor.row(), editor.col()), (2, 1));
+        substitution.replace_all(&mut editor);
+        assert_eq!(substitution.finish(&mut editor), Ok("3 substitutions on 2 lines".to_string()));
+        assert_eq!(editor.text(), "a b\nb b");
+        editor.undo().unwrap();
+        assert_eq!(editor.text(), "a a\na a");
+    }
+}
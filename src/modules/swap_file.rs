@@ -0,0 +1,207 @@
+use std::convert::{TryFrom, TryInto};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::UNIX_EPOCH;
+
+use super::undo_file::{self, Decoder, write_u64, write_bytes};
+
+/// Identifies a file as a Via swap file
+const MAGIC: &[u8; 8] = b"VIASWAP\0";
+/// Version of the swap file format. Increase whenever the format changes
+const VERSION: u64 = 1;
+/// Error for swap files which can't be replayed
+pub(crate) const DAMAGED: &str = "Swap file is damaged";
+
+/// Returns where the swap file for `file_path` is stored, which is `swap/` in
+/// `state_dir` followed by the escaped path of the file (see `undo_file::path`)
+pub(crate) fn path(state_dir: &Path, file_path: &str) -> Option<PathBuf> {
+    undo_file::state_path(state_dir, "swap", file_path)
+}
+
+/// Returns the length and modification time (in nanoseconds) of the file at
+/// `file_path`, or zeros if there is none, to tell whether it changed
+pub(crate) fn stamp(file_path: &str) -> (u64, u64) {
+    match fs::metadata(file_path) {
+        Ok(metadata) => {
+            let modified = metadata.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+            (metadata.len(), modified.map_or(0, |duration| duration.as_nanos() as u64))
+        },
+        Err(_) => (0, 0),
+    }
+}
+
+/// `EPERM` of `errno.h`: the process exists, but can't be signalled by this user
+const EPERM: i32 = 1;
+
+extern "C" {
+    /// `kill(2)` of the C library
+    fn kill(pid: i32, sig: i32) -> i32;
+}
+
+/// Returns whether the process `pid` is still running
+pub(crate) fn is_running(pid: u32) -> bool {
+    let pid = match i32::try_from(pid) {
+        Ok(pid) if pid > 0 => pid,
+        _ => return false,
+    };
+    // Signal 0 isn't sent, it only checks whether the process exists
+    let result = unsafe {kill(pid, 0)};
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(EPERM)
+}
+
+/// A swap file being written: the pid of the process editing the file and the stamp
+/// of the file, followed by each change made to its text since, so that the changes
+/// can be recovered if the editor stops without writing them
+pub(crate) struct SwapFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl SwapFile {
+    /// Open the swap file at `path`, which is empty until `reset`
+    pub(crate) fn create(path: PathBuf) -> Result<SwapFile, String> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        // Only readable by the user, since it has the text being edited
+        let file = OpenOptions::new().append(true).create(true).mode(0o600).open(&path).map_err(|e| e.to_string())?;
+        Ok(SwapFile {path, file})
+    }
+
+    /// Start the swap file over, for the file as it is now, with the `stamp` given
+    pub(crate) fn reset(&mut self, stamp: (u64, u64)) -> Result<(), String> {
+        self.file.set_len(0).map_err(|e| e.to_string())?;
+        let mut bytes = MAGIC.to_vec();
+        write_u64(&mut bytes, VERSION);
+        write_u64(&mut bytes, process::id() as u64);
+        write_u64(&mut bytes, stamp.0);
+        write_u64(&mut bytes, stamp.1);
+        self.append(&bytes)
+    }
+
+    /// Append changes as written by `PieceTable::take_journal`
+    pub(crate) fn append(&mut self, journal: &[u8]) -> Result<(), String> {
+        self.file.write_all(journal).map_err(|e| e.to_string())
+    }
+
+    /// Append the change of `removed` bytes at `index` to `inserted`
+    pub(crate) fn append_change(&mut self, index: usize, removed: usize, inserted: &str) -> Result<(), String> {
+        let mut bytes = Vec::new();
+        write_u64(&mut bytes, index as u64);
+        write_u64(&mut bytes, removed as u64);
+        write_bytes(&mut bytes, inserted.as_bytes());
+        self.append(&bytes)
+    }
+
+    /// Returns where the swap file is
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Remove the swap file, once the changes in it are no longer needed
+    pub(crate) fn remove(self) -> Result<(), String> {
+        fs::remove_file(&self.path).map_err(|e| e.to_string())
+    }
+}
+
+/// A change recorded in a swap file: `removed` bytes at `index` replaced by `inserted`
+#[derive(Debug, PartialEq)]
+pub(crate) struct Change {
+    pub(crate) index: usize,
+    pub(crate) removed: usize,
+    pub(crate) inserted: String,
+}
+
+/// What is in a swap file
+#[derive(Debug, PartialEq)]
+pub(crate) struct Swap {
+    /// Process which wrote it
+    pub(crate) pid: u32,
+    /// Stamp of the file the changes were made to
+    pub(crate) stamp: (u64, u64),
+    pub(crate) changes: Vec<Change>,
+}
+
+/// Read the swap file at `path`. A change which was only partly written, since the
+/// editor stopped while writing it, is left out
+pub(crate) fn read(path: &Path) -> Result<Swap, String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    let rest = bytes.strip_prefix(MAGIC.as_ref()).ok_or("Not a swap file")?;
+    let mut decoder = Decoder::new(rest);
+    let damaged = |_| DAMAGED.to_string();
+    let version = decoder.read_u64().map_err(damaged)?;
+    if version != VERSION {
+        return Err(format!("Unsupported swap file version {}", version));
+    }
+    let pid = decoder.read_u64().map_err(damaged)?.try_into().map_err(|_| DAMAGED)?;
+    let stamp = (decoder.read_u64().map_err(damaged)?, decoder.read_u64().map_err(damaged)?);
+    let mut changes = Vec::new();
+    while !decoder.is_empty() {
+        let (index, removed, inserted) = match read_change(&mut decoder) {
+            Ok(change) => change,
+            Err(_) => break,
+        };
+        let inserted = String::from_utf8(inserted.to_vec()).map_err(|_| DAMAGED)?;
+        changes.push(Change {index, removed, inserted});
+    }
+    Ok(Swap {pid, stamp, changes})
+}
+
+/// Read the index, the number of bytes removed and the text inserted of a change
+fn read_change<'a>(decoder: &mut Decoder<'a>) -> Result<(usize, usize, &'a [u8]), String> {
+    Ok((decoder.read_usize(usize::MAX)?, decoder.read_usize(usize::MAX)?, decoder.read_bytes()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn write_read() {
+        let path = env::temp_dir().join(format!("via-swap-test-{}", process::id()));
+        let mut swap_file = SwapFile::create(path.clone()).unwrap();
+        swap_file.reset((1, 2)).unwrap();
+        swap_file.append_change(0, 0, "abc").unwrap();
+        swap_file.append_change(1, 1, "").unwrap();
+        let swap = read(&path).unwrap();
+        assert_eq!(swap.pid, process::id());
+        assert_eq!(swap.stamp, (1, 2));
+        assert_eq!(swap.changes, vec![Change {index: 0, removed: 0, inserted: "abc".to_string()}, Change {index: 1, removed: 1, inserted: String::new()}]);
+
+        // The last change was only partly written
+        let mut bytes = fs::read(&path).unwrap();
+        bytes.truncate(bytes.len() - 3);
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(read(&path).unwrap().changes, vec![Change {index: 0, removed: 0, inserted: "abc".to_string()}]);
+
+        swap_file.reset((3, 4)).unwrap();
+        assert_eq!(read(&path).unwrap(), Swap {pid: process::id(), stamp: (3, 4), changes: Vec::new()});
+        swap_file.remove().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn running() {
+        assert!(is_running(process::id()));
+        assert!(!is_running(0));
+        assert!(!is_running(u32::MAX));
+        let mut child = process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        assert!(!is_running(pid));
+    }
+
+    #[test]
+    fn damaged() {
+        let path = env::temp_dir().join(format!("via-swap-damaged-test-{}", process::id()));
+        fs::write(&path, b"VIAUNDO\0").unwrap();
+        assert_eq!(read(&path), Err("Not a swap file".to_string()));
+        fs::write(&path, b"VIASWAP\0\x01\0\0").unwrap();
+        assert_eq!(read(&path), Err(DAMAGED.to_string()));
+        fs::remove_file(&path).unwrap();
+    }
+}
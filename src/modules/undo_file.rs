@@ -0,0 +1,202 @@
+use std::convert::TryInto;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::original_buffer::OriginalBuffer;
+use super::piece_table::PieceTable;
+use super::save;
+
+/// Identifies a file as a Via undo file
+const MAGIC: &[u8; 8] = b"VIAUNDO\0";
+/// Version of the undo file format. Increase whenever the format changes
+const VERSION: u32 = 5;
+
+/// Returns the directory via keeps its state in, such as undo files, which is
+/// `$XDG_STATE_HOME/via/` (or `~/.local/state/via/`)
+pub(crate) fn state_dir() -> Option<PathBuf> {
+    let state_home = match env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(state_home.join("via"))
+}
+
+/// Returns where the undo file for `file_path` is stored, which is `undo/` in
+/// `state_dir` followed by the absolute path of the file with `%` escaped as `%25`
+/// and `/` as `%2F`, so that different paths never share an undo file
+pub(crate) fn path(state_dir: &Path, file_path: &str) -> Option<PathBuf> {
+    state_path(state_dir, "undo", file_path)
+}
+
+/// Returns where a file of kind `kind` (such as `undo`) for `file_path` is stored in
+/// `state_dir`, the same way as `path` does for undo files
+pub(crate) fn state_path(state_dir: &Path, kind: &str, file_path: &str) -> Option<PathBuf> {
+    let absolute_path = match fs::canonicalize(file_path) {
+        Ok(path) => path,
+        Err(_) => env::current_dir().ok()?.join(file_path),
+    };
+    let escaped = absolute_path.to_str()?.replace('%', "%25").replace('/', "%2F");
+    Some(state_dir.join(kind).join(escaped))
+}
+
+/// Write the undo history of `piece_table` to `path`. Its text (along with the bytes of
+/// the original buffer which aren't part of it yet) has to be what was just written to
+/// the file the history belongs to, which has the stamp `stamp` (see `swap_file::stamp`)
+pub(crate) fn write(path: &Path, stamp: (u64, u64), piece_table: &PieceTable) -> Result<(), String> {
+    let text_hash = piece_table.chunks().fold(HASH_START, |text_hash, chunk| hash(text_hash, chunk.as_bytes()));
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    write_u64(&mut bytes, stamp.0);
+    write_u64(&mut bytes, stamp.1);
+    write_u64(&mut bytes, hash(text_hash, piece_table.unloaded_original()));
+    piece_table.encode(&mut bytes);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    // Only readable by the user, since it has the text being edited
+    let path = path.to_str().ok_or("Invalid undo file path")?;
+    save::save(path, false, Some(0o600), |file| file.write_all(&bytes))
+}
+
+/// Read a piece table and its undo history from `path`, for the file with the stamp
+/// `stamp` and the text of `original_buffer` (which must not have been loaded yet).
+/// Errors if the undo file is invalid or doesn't belong to that text. The text is only
+/// hashed to make sure of that if the stamp is the one the undo file was written with
+pub(crate) fn read(path: &Path, stamp: (u64, u64), original_buffer: OriginalBuffer) -> Result<PieceTable, String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    let mut decoder = Decoder::new(&bytes);
+    if decoder.read_slice(MAGIC.len())? != MAGIC {
+        return Err("Not an undo file".to_string());
+    }
+    let version = u32::from_le_bytes(decoder.read_slice(4)?.try_into().unwrap());
+    if version != VERSION {
+        return Err(format!("Unsupported undo file version {}", version));
+    }
+    if (decoder.read_u64()?, decoder.read_u64()?) != stamp || decoder.read_u64()? != hash(HASH_START, original_buffer.unloaded()) {
+        return Err("File contents changed, cannot use undo info".to_string());
+    }
+    let piece_table = PieceTable::decode(&mut decoder, original_buffer)?;
+    if !decoder.is_empty() {
+        return Err("Trailing data in undo file".to_string());
+    }
+    Ok(piece_table)
+}
+
+/// Returns the FNV-1a hash of `bytes`, continuing from `hash`.
+/// Start with `HASH_START`
+fn hash(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Initial value for `hash`
+const HASH_START: u64 = 0xcbf29ce484222325;
+
+/// Append `num` to `bytes` (little endian)
+pub(crate) fn write_u64(bytes: &mut Vec<u8>, num: u64) {
+    bytes.extend_from_slice(&num.to_le_bytes());
+}
+
+/// Append the length of `data` followed by `data` to `bytes`
+pub(crate) fn write_bytes(bytes: &mut Vec<u8>, data: &[u8]) {
+    write_u64(bytes, data.len() as u64);
+    bytes.extend_from_slice(data);
+}
+
+/// Reads values written by `write_u64` and `write_bytes`
+pub(crate) struct Decoder<'a> {
+    /// Bytes left to read
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    /// Initialize a decoder
+    pub(crate) fn new(bytes: &'a [u8]) -> Decoder<'a> {
+        Decoder {bytes}
+    }
+
+    /// Returns whether everything has been read
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Read the next `len` bytes
+    fn read_slice(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if len > self.bytes.len() {
+            return Err("Unexpected end of undo file".to_string());
+        }
+        let (slice, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(slice)
+    }
+
+    /// Read a number written by `write_u64`
+    pub(crate) fn read_u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.read_slice(8)?.try_into().unwrap()))
+    }
+
+    /// Read a number written by `write_u64` which is at most `max`
+    pub(crate) fn read_usize(&mut self, max: usize) -> Result<usize, String> {
+        match self.read_u64()?.try_into() {
+            Ok(num) if num <= max => Ok(num),
+            _ => Err("Invalid number in undo file".to_string()),
+        }
+    }
+
+    /// Read data written by `write_bytes`
+    pub(crate) fn read_bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.read_usize(self.bytes.len())?;
+        self.read_slice(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_read() {
+        let path = env::temp_dir().join(format!("via-undo-test-{}", std::process::id()));
+        let original = |text: &str| {
+            let mut original_buffer = OriginalBuffer::new();
+            original_buffer.push_str(text);
+            original_buffer
+        };
+        let mut piece_table = PieceTable::new();
+        piece_table.add_text("abc".to_string(), 0);
+        piece_table.delete_text(0, 1);
+        write(&path, (2, 1), &piece_table).unwrap();
+
+        assert!(read(&path, (2, 2), original("bc")).is_err());
+        assert!(read(&path, (2, 1), original("bd")).is_err());
+        let mut read_piece_table = read(&path, (2, 1), original("bc")).unwrap();
+        assert_eq!(read_piece_table.text(), "bc");
+        read_piece_table.undo().unwrap();
+        assert_eq!(read_piece_table.text(), "abc");
+        read_piece_table.undo().unwrap();
+        assert_eq!(read_piece_table.text(), "");
+        read_piece_table.redo().unwrap();
+        read_piece_table.redo().unwrap();
+        assert_eq!(read_piece_table.text(), "bc");
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes.truncate(bytes.len() - 1);
+        fs::write(&path, &bytes).unwrap();
+        assert!(read(&path, (2, 1), original("bc")).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn escaped_path() {
+        let path = path(Path::new("/state/via"), "/tmp/a%b/c").unwrap();
+        assert_eq!(path, Path::new("/state/via/undo/%2Ftmp%2Fa%25b%2Fc"));
+        assert_ne!(super::path(Path::new("/state"), "/a/%b"), super::path(Path::new("/state"), "/a%/b"));
+    }
+}
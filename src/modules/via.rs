@@ -4,13 +4,32 @@ use termion::event::{Key, Event, MouseEvent};
 use termion::input::{TermRead, MouseTerminal};
 use termion::raw::IntoRawMode;
 
-use std::cmp::min;
+use std::cmp::{min, max};
 use std::io::{Write, stdout, stdin};
+use std::iter;
+use std::path::Path;
+use std::process::{self, Stdio};
+use std::thread;
 use std::convert::TryInto;
-use std::fs::File;
+use std::time::Duration;
+
+use unicode_segmentation::UnicodeSegmentation;
 
 use super::editor::Editor;
+use super::ex;
+use super::message::{Message, Messages, Severity};
+use super::motion::{Command, Motion, Parser, Range};
 use super::piece_table::PieceTable;
+use super::regex::Regex;
+use super::substitute::{self, Substitution};
+use super::swap_file;
+
+/// Kind of visual mode started with Ctrl-V, which selects a block of columns
+const VISUAL_BLOCK: char = '\u{16}';
+/// Error for commands which would lose changes, unless forced with `!`
+const NOT_WRITTEN: &str = "E37: No write since last change (add ! to override)";
+/// Error for commands which would overwrite another file, unless forced with `!`
+const FILE_EXISTS: &str = "E13: File exists (add ! to override)";
 
 /// Via main class, comprised of `Editor`'s
 pub struct Via {
@@ -22,29 +41,86 @@ pub struct Via {
     options: ViaOptions,
     /** Mode Via is currently in
      * 0: normal
-     * 1: visual (not implemented)
+     * 1: visual
      * 2: select (not implemented)
      * 3: insert
      * 4: command line
-     * 5: ex (not implemented)
+     * 5: ex, entered with `Q`, which runs commands until `:visual`
     */
     mode: usize,
     /// Piece table of the command line
     cmd_piece_table: PieceTable,
+    /// Text last yanked or deleted, to be put with `p` and `P`
+    register: String,
+    /// How `register` was yanked: 'v' for characters, 'V' for lines or `VISUAL_BLOCK` for a block
+    register_kind: char,
+    /// Keys typed in normal and visual mode which aren't a complete command yet
+    parser: Parser,
+    /// Messages shown on the last rows of the terminal, and their history
+    messages: Messages,
+    /// Pattern last searched for and whether it was searched forward (with `/`)
+    last_search: Option<(String, bool)>,
+    /// Commands which can be run from the command line
+    commands: ex::Registry,
+    /// Commands run in ex mode and their output
+    ex_lines: Vec<String>,
+    /// Pid of the via whose swap file was found when the file was opened and whether it
+    /// is still running, while asking what to do with it
+    swap_prompt: Option<(u32, bool)>,
 }
 
 impl Via {
     /// Initialize a new instance of Via from arguments
     pub(crate) fn new(file_path: String, options: ViaOptions) -> Via {
+        let mut editor = Editor::new(file_path);
+        editor.set_backup(options.backup);
+        let mut messages = Messages::new();
+        let mut swap_prompt = None;
+        if options.recover {
+            let running = editor.find_swap().is_some_and(swap_file::is_running);
+            match editor.recover(!running) {
+                Ok(changes) => messages.info(Via::recovered(changes, running)),
+                Err(err) => messages.error(err),
+            }
+        } else if let Some(pid) = editor.find_swap() {
+            swap_prompt = Some((pid, swap_file::is_running(pid)));
+            Via::prompt_swap(&mut messages, editor.file_path(), swap_prompt.unwrap());
+        } else if let Err(err) = editor.open_swap() {
+            messages.error(err);
+        }
         Via {
-            editors: vec![Editor::new(file_path)],
+            editors: vec![editor],
             cmd_editor: Editor::new("".to_string()),
-            options: options,
+            options,
             mode: 0,
             cmd_piece_table: PieceTable::new(),
+            register: String::new(),
+            register_kind: 'v',
+            parser: Parser::new(),
+            messages,
+            last_search: None,
+            commands: Via::commands(),
+            ex_lines: Vec::new(),
+            swap_prompt,
         }
     }
 
+    /// Show what was found when a swap file of `file_path` from the via with `pid`
+    /// was found, and what can be done about it. It is only deleted if that via isn't
+    /// still `running`, since it may still be writing to it
+    fn prompt_swap(messages: &mut Messages, file_path: &str, (pid, running): (u32, bool)) {
+        messages.warn(format!("E325: ATTENTION: Found a swap file of \"{}\" by via process {} ({})",
+            file_path, pid, if running {"still running"} else {"not running"}));
+        messages.print(if running {"(R)ecover, (E)dit anyway, (Q)uit?"} else {"(R)ecover, (D)elete it, (E)dit anyway, (Q)uit?"}.to_string());
+    }
+
+    /// Returns the message for when `changes` were recovered from a swap file, which
+    /// is left to the via which wrote it if that is still `running`
+    fn recovered(changes: usize, running: bool) -> String {
+        format!("Recovered {} change{}{}. Check the text and write it to keep it", changes, if changes == 1 {""} else {"s"},
+            if running {" without taking over the swap file, which is still in use"} else {""})
+    }
+
     /// Initialize Via and start editing
     pub fn init(&mut self) {
         // let stdin = termion::async_stdin();
@@ -62,103 +138,317 @@ impl Via {
         editor.goto_last_row();
         editor.goto_last_col();
 
-        let mut full_render = false;
-        for c in stdin.events() {
+        let mut full_render = true;
+        // Where the selection started in visual mode (row, col)
+        let mut visual_anchor = (1, 1);
+        // Kind of visual mode: 'v' for characters, 'V' for lines or `VISUAL_BLOCK`
+        let mut visual_kind = 'v';
+        // Rows (first, last), column and whether to pad short rows with spaces, for
+        // text typed in insert mode which is to be inserted on every row of a block
+        let mut block_insert: Option<(usize, usize, usize, bool)> = None;
+        // Where the cursor was when `/` or `?` was typed, to go back to if the search is
+        // cancelled (since incremental search moves the cursor while the pattern is typed)
+        let mut search_start: Option<(usize, usize)> = None;
+        // Substitution waiting for each match to be confirmed, with `:s///c`
+        let mut substitution: Option<Substitution> = None;
+        // Error reading the file last shown, so that it is shown once
+        let mut shown_error: Option<String> = None;
+        // Error writing to the swap file last shown, so that it is shown once
+        let mut swap_error: Option<String> = None;
+        // Start with an event which isn't a key, to show the file and messages before a key is typed
+        for c in iter::once(Ok(Event::Unsupported(Vec::new()))).chain(stdin.events()) {
+            // Result of moving through the undo tree, if we did
+            let mut undo_result: Option<Result<(), String>> = None;
             let (term_rows_u16, term_cols_u16) = termion::terminal_size().unwrap();
-            let term_rows: usize = term_rows_u16.try_into().unwrap();
-            let _term_cols: usize = term_cols_u16.try_into().unwrap();
+            let term_rows: usize = term_rows_u16.into();
+            // Rows for the text, leaving the last one for the command line and messages
+            let text_rows = max(term_rows, 2) - 1;
+            let _term_cols: usize = term_cols_u16.into();
             let evt = c.unwrap();
+            if let Event::Key(_) = evt {
+                // Messages are shown until the next key is typed
+                if self.messages.current().len() > 1 {
+                    full_render = true;
+                }
+                self.messages.clear();
+            }
             match evt {
+                Event::Key(key) if self.swap_prompt.is_some() => {
+                    let (pid, running) = self.swap_prompt.take().unwrap();
+                    match key {
+                        Key::Char('r') | Key::Char('R') => match editor.recover(!running) {
+                            Ok(changes) => self.messages.info(Via::recovered(changes, running)),
+                            Err(err) => {
+                                self.messages.error(err);
+                                self.swap_prompt = Some((pid, running));
+                            },
+                        },
+                        Key::Char('d') | Key::Char('D') if !running => {
+                            if let Err(err) = editor.open_swap() {
+                                self.messages.error(err);
+                            }
+                        },
+                        Key::Char('e') | Key::Char('E') => {},
+                        Key::Char('q') | Key::Char('Q') => break,
+                        _ => self.swap_prompt = Some((pid, running)),
+                    }
+                    if let Some(swap_prompt) = self.swap_prompt {
+                        Via::prompt_swap(&mut self.messages, editor.file_path(), swap_prompt);
+                    }
+                    full_render = true;
+                },
+                Event::Key(key) if substitution.is_some() => {
+                    let current = substitution.as_mut().unwrap();
+                    let more = match key {
+                        Key::Char('y') => current.replace(editor),
+                        Key::Char('n') => current.skip(editor),
+                        Key::Char('a') => {
+                            current.replace_all(editor);
+                            false
+                        },
+                        Key::Char('l') => {
+                            current.replace(editor);
+                            false
+                        },
+                        Key::Char('q') | Key::Esc | Key::Ctrl('c') => false,
+                        _ => true,
+                    };
+                    if more {
+                        self.messages.print(current.prompt());
+                    } else {
+                        match current.finish(editor) {
+                            Ok(message) => self.messages.info(message),
+                            Err(err) => self.messages.error(err),
+                        }
+                        substitution = None;
+                    }
+                    full_render = true;
+                },
                 Event::Key(key) => {
                     if key == Key::Esc {
+                        self.parser.clear();
                         if self.mode == 4 {
+                            if let Some((row, col)) = search_start.take() {
+                                editor.goto(row, col);
+                                full_render = true;
+                            }
                             write!(stdout, "{}", termion::cursor::Restore).unwrap();
                         } else if self.mode == 3 {
+                            if let Some((first_row, last_row, col, pad)) = block_insert.take() {
+                                Via::repeat_block_insert(editor, first_row, last_row, col, pad);
+                                full_render = true;
+                            }
+                            editor.commit_group();
                             editor.left(1);
+                        } else if self.mode == 1 {
+                            full_render = true;
                         }
                         self.mode = 0;
-                    } else if self.mode == 0 {
-                        // Normal mode
-                        match key {
-                            Key::Char('h') | Key::Left | Key::Backspace => editor.left(1),
-                            Key::Char('j') | Key::Down => {
-                                if visual_first_row + term_rows == editor.row() {
-                                    visual_first_row += 1;
-                                }
-                                editor.down(1);
-                                if editor.col() - 1 == editor.line_len(editor.row()) {
-                                    editor.left(1);
-                                }
+                    } else if self.mode == 0 || self.mode == 1 {
+                        // Normal and visual mode
+                        if self.mode == 1 {
+                            full_render = true;
+                        }
+                        let c = match key {
+                            Key::Char(c) => Some(c),
+                            Key::Left | Key::Backspace => Some('h'),
+                            Key::Down => Some('j'),
+                            Key::Up => Some('k'),
+                            Key::Right => Some('l'),
+                            Key::Home => Some('0'),
+                            Key::End => Some('$'),
+                            Key::Ctrl('v') => Some(VISUAL_BLOCK),
+                            _ => None,
+                        };
+                        let command = match c {
+                            Some(c) => self.parser.push(c, self.mode == 0),
+                            None => {
+                                self.parser.clear();
+                                None
                             },
-                            Key::Char('\n') => {
-                                if visual_first_row + term_rows == editor.row() {
-                                    visual_first_row += 1;
-                                }
-                                if editor.row() != editor.num_lines() {
-                                    editor.goto(editor.row() + 1, 1);
+                        };
+                        match command {
+                            Some(Command::Move(motion @ Motion::Object(..), count)) if self.mode == 1 => {
+                                // Select the text object
+                                if let Some(range) = motion.range(editor, count, 'v') {
+                                    if range.linewise {
+                                        visual_kind = 'V';
+                                        visual_anchor = (range.start.0, 1);
+                                        editor.goto(range.end.0, 1);
+                                    } else {
+                                        if visual_kind == 'V' {
+                                            visual_kind = 'v';
+                                        }
+                                        visual_anchor = range.start;
+                                        if range.end.1 > 1 {
+                                            editor.goto(range.end.0, range.end.1 - 1);
+                                        } else {
+                                            editor.goto(range.end.0 - 1, editor.line_len(range.end.0 - 1) + 1);
+                                        }
+                                    }
                                 }
                             },
-                            Key::Char('k') | Key::Up => {
-                                editor.up(1);
-                                if editor.col() - 1 == editor.line_len(editor.row()) {
+                            Some(Command::Move(motion, count)) => {
+                                motion.apply(editor, count);
+                                if editor.col() > 1 && editor.col() - 1 == editor.line_len(editor.row()) {
                                     editor.left(1);
                                 }
                             },
-                            Key::Char('l') | Key:: Right => {
-                                if editor.col() < editor.line_len(editor.row()) {
-                                    editor.right(1)
+                            Some(Command::Operate(operator, motion, count)) => {
+                                if let Some(range) = motion.range(editor, count, operator) {
+                                    if Via::operate(editor, &mut self.register, &mut self.register_kind, operator, range) {
+                                        self.mode = 3;
+                                    }
+                                    full_render = true;
                                 }
                             },
-                            Key::Char('i') => self.mode = 3,
-                            Key::Char('a') => {
-                                editor.right(1); 
-                                self.mode = 3
-                            },
-                            Key::Char('o') => {
-                                editor.goto_last_col();
-                                editor.add_text("\n".to_string());
-                                self.mode = 3;
-                                full_render = true;
-                            },
-                            Key::Char('O') => {
-                                editor.goto_col(0);
-                                editor.add_text("\n".to_string());
-                                editor.up(1);
-                                self.mode = 3;
-                                full_render = true;
-                            },
-                            Key::Char(':') => {
-                                write!(stdout, "{}", termion::cursor::Save).unwrap();
-                                self.cmd_editor.delete_all();
-                                self.cmd_editor.add_text(":".to_string());
-                                self.mode = 4
-                            },
-                            Key::Char('$') => {
-                                editor.goto_last_col();
-                                editor.left(1);
-                            },
-                            Key::Char('0') => {
-                                editor.goto_col(0);
-                            },
-                            Key::Char('A') => {
-                                editor.goto_last_col();
-                                self.mode = 3;
-                            }
-                            Key::Delete => {
-                                let at_line_end = editor.col() == editor.num_cols(editor.row());
-                                if at_line_end && editor.row() == editor.num_lines() {
-                                    break
-                                } else if at_line_end {
-                                    editor.delete_text(editor.row() + 1, 1).unwrap();
+                            Some(Command::Other(keys, count)) => match keys.as_str() {
+                                // `\u{16}` is Ctrl-V (`VISUAL_BLOCK`)
+                                "v" | "V" | "\u{16}" => {
+                                    let kind = keys.chars().next().unwrap();
+                                    if self.mode == 1 && visual_kind == kind {
+                                        self.mode = 0;
+                                    } else {
+                                        if self.mode == 0 {
+                                            visual_anchor = (editor.row(), editor.col());
+                                        }
+                                        visual_kind = kind;
+                                        self.mode = 1;
+                                    }
                                     full_render = true;
-                                } else {
-                                    editor.delete_text(editor.row(), editor.col() + 1).unwrap();
-                                    write!(stdout, "{}{}", termion::clear::CurrentLine, editor.text_line(editor.row())).unwrap();
-                                }
+                                },
+                                "o" if self.mode == 1 => {
+                                    let cursor = (editor.row(), editor.col());
+                                    editor.goto(visual_anchor.0, visual_anchor.1);
+                                    visual_anchor = cursor;
+                                },
+                                ":" if self.mode == 1 => {
+                                    Via::set_visual_marks(editor, visual_anchor);
+                                    write!(stdout, "{}", termion::cursor::Save).unwrap();
+                                    self.cmd_editor.delete_all();
+                                    self.cmd_editor.add_text(":'<,'>".to_string());
+                                    self.mode = 4;
+                                },
+                                _ if self.mode == 1 && visual_kind == VISUAL_BLOCK => {
+                                    let (rows, cols) = Via::visual_block(editor, visual_anchor);
+                                    Via::set_visual_marks(editor, visual_anchor);
+                                    let c = keys.chars().next().unwrap();
+                                    let done = match c {
+                                        'd' | 'x' | 'c' | 'y' | '~' | 'I' | 'A' => {
+                                            match Via::operate_block(editor, &mut self.register, &mut self.register_kind, c, rows, cols) {
+                                                Ok(Some(insert)) => {
+                                                    block_insert = Some(insert);
+                                                    self.mode = 3;
+                                                },
+                                                Ok(None) => {},
+                                                Err(err) => self.messages.error(err),
+                                            }
+                                            true
+                                        },
+                                        '>' | '<' => {
+                                            Via::shift_lines(editor, rows.0, rows.1, c == '>');
+                                            true
+                                        },
+                                        _ => false,
+                                    };
+                                    if done && self.mode == 1 {
+                                        self.mode = 0;
+                                    }
+                                },
+                                "d" | "x" | "c" | "y" | "~" | ">" | "<" if self.mode == 1 => {
+                                    let linewise = visual_kind == 'V';
+                                    let (start, end) = Via::visual_range(editor, visual_anchor, linewise);
+                                    Via::set_visual_marks(editor, visual_anchor);
+                                    let range = if linewise {
+                                        Range {start: min(visual_anchor, (editor.row(), editor.col())), end: (max(visual_anchor.0, editor.row()), 1), linewise}
+                                    } else {
+                                        Range {start, end, linewise}
+                                    };
+                                    let operator = if keys == "x" {'d'} else {keys.chars().next().unwrap()};
+                                    let insert = Via::operate(editor, &mut self.register, &mut self.register_kind, operator, range);
+                                    self.mode = if insert {3} else {0};
+                                },
+                                _ if self.mode == 1 => {},
+                                "i" => {
+                                    editor.begin_group();
+                                    self.mode = 3
+                                },
+                                "a" => {
+                                    editor.begin_group();
+                                    editor.right(1);
+                                    self.mode = 3
+                                },
+                                "o" => {
+                                    editor.begin_group();
+                                    editor.goto_last_col();
+                                    editor.add_text("\n".to_string());
+                                    self.mode = 3;
+                                    full_render = true;
+                                },
+                                "O" => {
+                                    editor.begin_group();
+                                    editor.goto_col(0);
+                                    editor.add_text("\n".to_string());
+                                    editor.up(1);
+                                    self.mode = 3;
+                                    full_render = true;
+                                },
+                                ":" | "/" | "?" => {
+                                    write!(stdout, "{}", termion::cursor::Save).unwrap();
+                                    self.cmd_editor.delete_all();
+                                    self.cmd_editor.add_text(keys.clone());
+                                    if keys != ":" {
+                                        search_start = Some((editor.row(), editor.col()));
+                                    }
+                                    self.mode = 4
+                                },
+                                "Q" if self.mode == 0 => {
+                                    self.cmd_editor.delete_all();
+                                    self.cmd_editor.add_text(":".to_string());
+                                    self.ex_lines = vec!["Entering Ex mode.  Type \"visual\" to go to Normal mode.".to_string()];
+                                    self.mode = 5;
+                                },
+                                "n" | "N" => match self.last_search.clone() {
+                                    Some((pattern, forward)) => {
+                                        Via::search(editor, &mut self.messages, &pattern, forward == (keys == "n"), count.unwrap_or(1));
+                                        full_render = true;
+                                    },
+                                    None => self.messages.error("E35: No previous regular expression".to_string()),
+                                },
+                                "p" | "P" if !self.register.is_empty() => {
+                                    let text = if self.register_kind == VISUAL_BLOCK {
+                                        self.register.clone()
+                                    } else {
+                                        self.register.repeat(count.unwrap_or(1))
+                                    };
+                                    Via::put(editor, &text, self.register_kind, keys == "p");
+                                    full_render = true;
+                                },
+                                "A" => {
+                                    editor.begin_group();
+                                    editor.goto_last_col();
+                                    self.mode = 3;
+                                },
+                                "u" => {
+                                    for _ in 0..count.unwrap_or(1) {
+                                        undo_result = Some(editor.undo());
+                                    }
+                                },
+                                "g-" => undo_result = Some(editor.earlier(count.unwrap_or(1))),
+                                "g+" => undo_result = Some(editor.later(count.unwrap_or(1))),
+                                _ => {},
+                            },
+                            None => match key {
+                                // Like `x`
+                                Key::Delete if self.mode == 0 => {
+                                    if let Some(range) = Motion::Right.range(editor, None, 'd') {
+                                        Via::operate(editor, &mut self.register, &mut self.register_kind, 'd', range);
+                                        full_render = true;
+                                    }
+                                },
+                                Key::Ctrl('r') if self.mode == 0 => undo_result = Some(editor.redo()),
+                                _ => {},
                             },
-                            Key::Home => editor.goto_col(0),
-                            Key::End => editor.goto_last_col(),
-                            _ => {},
                         }
                     } else if self.mode == 3 {
                         // Insert mode
@@ -169,7 +459,7 @@ impl Via {
                                     full_render = true;
                                 } else {
                                     editor.add_text(c.to_string());
-                                    write!(stdout, "{}{}", "\r", editor.text_line(editor.row())).unwrap();
+                                    write!(stdout, "\r{}", editor.text_line(editor.row())).unwrap();
                                 }
                             },
                             Key::Left => editor.left(1),
@@ -182,81 +472,118 @@ impl Via {
                                 } else if editor.col() == 1 {
                                     editor.up(1);
                                     editor.goto_last_col();
-                                    editor.delete_text(editor.row() + 1, 1).unwrap();
+                                    if let Err(err) = editor.delete_text(editor.row() + 1, 1) {
+                                        self.messages.error(err);
+                                    }
                                     full_render = true;
                                 } else {
                                     editor.left(1);
-                                    editor.delete_text(editor.row(), editor.col() + 1).unwrap();
+                                    if let Err(err) = editor.delete_text(editor.row(), editor.col() + 1) {
+                                        self.messages.error(err);
+                                    }
                                     write!(stdout, "{}{}{}", termion::clear::CurrentLine, termion::cursor::Goto(1, (editor.row() - visual_first_row + 1).try_into().unwrap()), editor.text_line(editor.row())).unwrap();
                                 }
                             },
                             Key::Delete => {
-                                let at_line_end = editor.col() == editor.num_cols(editor.row());
-                                if at_line_end && editor.row() == editor.num_lines() {
-                                    break
-                                } else if at_line_end {
-                                    editor.delete_text(editor.row() + 1, 1).unwrap();
-                                    full_render = true;
+                                // At the end of a line (`line_len + 1`), the next line is joined to it
+                                let result = if editor.col() <= editor.line_len(editor.row()) {
+                                    editor.delete_text(editor.row(), editor.col() + 1)
+                                } else if editor.has_line(editor.row() + 1) {
+                                    editor.delete_text(editor.row() + 1, 1)
                                 } else {
-                                    editor.delete_text(editor.row(), editor.col() + 1).unwrap();
-                                    write!(stdout, "{}{}", termion::clear::CurrentLine, editor.text_line(editor.row())).unwrap();
+                                    Ok(())
+                                };
+                                if let Err(err) = result {
+                                    self.messages.error(err);
                                 }
+                                full_render = true;
                             },
                             Key::Home => editor.goto_col(0),
                             Key::End => editor.goto_last_col(),
                             _ => {}
                         }
-                    } else if self.mode == 4 {
-                        // Command line mode
+                    } else if self.mode == 4 || self.mode == 5 {
+                        // Command line and ex mode
+                        let text = self.cmd_editor.text();
                         match key {
-                            Key::Char('\n') => {
-                                let mut write = false;
-                                let mut skip_write = false;
-                                let mut quit = false;
-                                match self.cmd_editor.text() {
-                                    ":wq" | "x" => {write = true; quit = true;},
-                                    ":q" => quit = true,
-                                    ":q!" => {skip_write = true; quit = true}
-                                    text => {
-                                        // Don't panic, but output an error message
-                                        panic!(text.to_string())
-                                    },
+                            Key::Char('\n') if text.starts_with('/') || text.starts_with('?') => {
+                                let forward = text.starts_with('/');
+                                if let Some((row, col)) = search_start.take() {
+                                    editor.goto(row, col);
                                 }
-                                if write {
-                                    if editor.file_path() == "" {
-                                        // Don't panic, but output an error message
-                                        panic!("FIXME")
-                                    } else {
-                                        File::create(editor.file_path()).unwrap().write_all(editor.text().as_bytes()).unwrap();
-                                    }
+                                // An empty pattern searches for the last one again
+                                if text.len() > 1 {
+                                    self.last_search = Some((text[1..].to_string(), forward));
                                 }
+                                match self.last_search.clone() {
+                                    Some((pattern, _)) => {
+                                        self.last_search = Some((pattern.clone(), forward));
+                                        Via::search(editor, &mut self.messages, &pattern, forward, 1);
+                                    },
+                                    None => self.messages.error("E35: No previous regular expression".to_string()),
+                                }
+                                write!(stdout, "{}", termion::cursor::Restore).unwrap();
+                                full_render = true;
+                                self.mode = 0;
+                            },
+                            Key::Char('\n') => {
+                                let text = self.cmd_editor.text();
+                                let mut context = ex::Context::new(editor, &mut self.last_search, &mut self.register, &mut self.register_kind, &mut self.messages);
+                                let result = self.commands.execute(&text[1..], &mut context);
+                                let ex::Context {quit, mode, substitution: pending, ..} = context;
                                 if quit {
-                                    if editor.text_matches() {
-                                        write!(stdout, "{}", termion::cursor::BlinkingBlock).unwrap();
-                                        break
-                                    } else if skip_write {
-                                        break
-                                    }
-                                    // Don't panic, but output an error message
-                                    panic!("Quit without saving")
+                                    write!(stdout, "{}", termion::cursor::BlinkingBlock).unwrap();
+                                    break
+                                }
+                                if let Err(err) = result {
+                                    self.messages.error(err);
+                                }
+                                if self.mode == 5 {
+                                    // Ex mode keeps the commands and their output, and prompts for the next one
+                                    self.ex_lines.push(text);
+                                    self.ex_lines.extend(self.messages.current().iter().map(|message| message.text.clone()));
+                                    self.messages.clear();
+                                    self.cmd_editor.delete_all();
+                                    self.cmd_editor.add_text(":".to_string());
+                                    self.mode = mode.unwrap_or(5);
+                                } else {
+                                    write!(stdout, "{}", termion::cursor::Restore).unwrap();
+                                    self.mode = mode.unwrap_or(0);
                                 }
-                                panic!("Invalid command")
+                                if pending.is_some() {
+                                    substitution = pending;
+                                    self.mode = 0;
+                                }
+                                if self.mode == 0 && editor.col() > 1 && editor.col() - 1 == editor.line_len(editor.row()) {
+                                    editor.left(1);
+                                }
+                                full_render = true;
                             }
                             Key::Char(c) => {
                                 self.cmd_editor.add_text(c.to_string());
                             },
                             Key::Left => self.cmd_editor.left(1),
                             Key::Right => self.cmd_editor.right(1),
-                            Key::Backspace => {
-                                if self.cmd_editor.col() != 1 {
-                                    self.cmd_editor.left(1);
-                                    self.cmd_editor.delete_text(self.cmd_editor.row(), self.cmd_editor.col() + 1).unwrap();
+                            // The `:` prompt can't be deleted in ex mode
+                            Key::Backspace if self.mode == 5 && self.cmd_editor.col() <= 2 => {},
+                            Key::Backspace if self.cmd_editor.col() != 1 => {
+                                self.cmd_editor.left(1);
+                                if let Err(err) = self.cmd_editor.delete_text(self.cmd_editor.row(), self.cmd_editor.col() + 1) {
+                                    self.messages.error(err);
+                                }
+                                if self.cmd_editor.text().is_empty() {
+                                    if let Some((row, col)) = search_start.take() {
+                                        editor.goto(row, col);
+                                        full_render = true;
+                                    }
+                                    write!(stdout, "{}", termion::cursor::Restore).unwrap();
+                                    self.mode = 0;
                                 }
                             },
-                            Key::Delete => {
-                                let at_line_end = self.cmd_editor.col() == self.cmd_editor.num_cols(self.cmd_editor.row());
-                                if !at_line_end {
-                                    self.cmd_editor.delete_text(self.cmd_editor.row(), self.cmd_editor.col() + 1).unwrap();
+                            Key::Delete if self.cmd_editor.col() <= self.cmd_editor.line_len(self.cmd_editor.row()) => {
+                                if let Err(err) = self.cmd_editor.delete_text(self.cmd_editor.row(), self.cmd_editor.col() + 1) {
+                                    self.messages.error(err);
+                                } else {
                                     write!(stdout, "{}{}", termion::clear::CurrentLine, self.cmd_editor.text_line(self.cmd_editor.row())).unwrap();
                                 }
                             },
@@ -265,28 +592,134 @@ impl Via {
                             _ => {}
                         }
                     } else {
-                        panic!("Mode {} not implemented yet", self.mode);
+                        self.messages.error(format!("Mode {} not implemented yet", self.mode));
+                        self.mode = 0;
+                        full_render = true;
                     }
                 },
-                Event::Mouse(me) => {
-                    match me {
-                        MouseEvent::Press(_, x, y) => {
-                            editor.goto(y.try_into().unwrap(), min(x.try_into().unwrap(), editor.line_len(editor.row())));
-                            self.mode = 0;
-                        },
-                        _ => (),
+                Event::Mouse(MouseEvent::Press(_, x, y)) => {
+                    if self.mode == 3 {
+                        editor.commit_group();
                     }
-                }
+                    editor.goto_row(y.into());
+                    let col = editor.display_col_to_col(editor.row(), x.into());
+                    editor.goto_col(min(col, editor.line_len(editor.row())));
+                    self.mode = 0;
+                },
                 _ => {}
             }
-            if full_render {
+            if let (4, Some((row, col))) = (self.mode, search_start) {
+                // Incremental search: move to the first match of what has been typed so far
+                editor.goto(row, col);
+                let text = self.cmd_editor.text();
+                if let Ok(regex) = Regex::new(&text[1..]) {
+                    if let Some(((row, col), _)) = editor.search(&regex, text.starts_with('/'), 1) {
+                        editor.goto(row, col);
+                    }
+                }
+                full_render = true;
+            }
+            let swap_result = editor.sync_swap().err();
+            if swap_result != swap_error {
+                swap_error = swap_result;
+                if let Some(error) = &swap_error {
+                    self.messages.error(format!("Error writing swap file: {}", error));
+                }
+            }
+            if editor.error() != shown_error.as_deref() {
+                shown_error = editor.error().map(str::to_string);
+                if let Some(error) = &shown_error {
+                    self.messages.error(error.clone());
+                }
+            }
+            if editor.row() < visual_first_row || editor.row() >= visual_first_row + text_rows {
+                full_render = true;
+            }
+            if let Some(Ok(())) = undo_result {
+                if editor.col() > 1 && editor.col() - 1 == editor.line_len(editor.row()) {
+                    editor.left(1);
+                }
+                full_render = true;
+            }
+            if self.mode == 5 {
+                // Ex mode shows the commands run and their output instead of the text
+                let first = self.ex_lines.len().saturating_sub(text_rows);
+                for i in 0..text_rows {
+                    write!(stdout, "{}{}", termion::cursor::Goto(1, (i + 1).try_into().unwrap()), termion::clear::CurrentLine).unwrap();
+                    if let Some(line) = self.ex_lines.get(first + i) {
+                        write!(stdout, "{}", line).unwrap();
+                    }
+                }
+            } else if full_render {
                 // write!(stdout, "{}{}", termion::clear::All, termion::cursor::Goto(1, 1)).unwrap();
-                for (i, line) in editor.text_lines(visual_first_row, visual_first_row + min(editor.num_lines(), term_rows)).lines().enumerate() {
-                    write!(stdout, "{}{}", termion::cursor::Goto(1, (i + 1).try_into().unwrap()), line).unwrap();
+                if editor.row() < visual_first_row {
+                    visual_first_row = editor.row();
+                } else if editor.row() >= visual_first_row + text_rows {
+                    visual_first_row = editor.row() - text_rows + 1;
+                }
+                // Highlight the pattern being searched for, or else the last one
+                let text = self.cmd_editor.text();
+                let pattern = match &self.last_search {
+                    _ if self.mode == 4 && search_start.is_some() => Some(&text[1..]),
+                    Some((pattern, _)) => Some(pattern.as_str()),
+                    None => None,
+                };
+                let last_row = min(visual_first_row + text_rows - 1, editor.num_lines());
+                let matches = match pattern.map(Regex::new) {
+                    Some(Ok(regex)) => editor.find_all(&regex, visual_first_row, last_row),
+                    _ => Vec::new(),
+                };
+                // Characters to show as selected: the match being confirmed by `:s///c`, or the visual selection
+                let selection = match &substitution {
+                    Some(substitution) => substitution.current(editor),
+                    None if self.mode == 1 && visual_kind == 'v' => Some(Via::visual_range(editor, visual_anchor, false)),
+                    None => None,
+                };
+                for i in 0..text_rows {
+                    write!(stdout, "{}{}", termion::cursor::Goto(1, (i + 1).try_into().unwrap()), termion::clear::CurrentLine).unwrap();
+                    let row = visual_first_row + i;
+                    if row > editor.num_lines() {
+                        continue;
+                    }
+                    let (first_row, last_row) = (min(visual_anchor.0, editor.row()), max(visual_anchor.0, editor.row()));
+                    let selected = if let Some((start, end)) = selection {
+                        if row < start.0 || row > end.0 || (row == end.0 && end.1 == 1 && row != start.0) {
+                            None
+                        } else {
+                            let first = if row == start.0 {start.1} else {1};
+                            Some((first, if row == end.0 {max(end.1 - 1, first)} else {usize::MAX}))
+                        }
+                    } else if self.mode != 1 {
+                        None
+                    } else if visual_kind == VISUAL_BLOCK {
+                        let (_, cols) = Via::visual_block(editor, visual_anchor);
+                        Some(cols).filter(|_| first_row <= row && row <= last_row)
+                    } else {
+                        Some((1, usize::MAX)).filter(|_| first_row <= row && row <= last_row)
+                    };
+                    let row_matches: Vec<(usize, usize)> = matches.iter().filter(|(start, end)| start.0 <= row && row <= end.0).map(|(start, end)| {
+                        (if start.0 == row {start.1} else {1}, if end.0 == row {end.1 - 1} else {usize::MAX})
+                    }).filter(|(first, last)| first <= last).collect();
+                    Via::render_line(&mut stdout, editor, row, selected, &row_matches);
                 }
                 full_render = false;
             }
-            write!(stdout, "{}", termion::cursor::Goto(editor.col().try_into().unwrap(), (editor.row() - visual_first_row + 1).try_into().unwrap())).unwrap();
+            // The last row shows the command line while it is being typed, or else the messages,
+            // which take as many of the last rows as there are of them
+            write!(stdout, "{}{}", termion::cursor::Goto(1, term_rows_u16), termion::clear::CurrentLine).unwrap();
+            if self.mode == 4 || self.mode == 5 {
+                write!(stdout, "{}", self.cmd_editor.text()).unwrap();
+                write!(stdout, "{}", termion::cursor::Goto(self.cmd_editor.display_col().try_into().unwrap(), term_rows_u16)).unwrap();
+            } else {
+                let messages = self.messages.current();
+                let messages = &messages[messages.len().saturating_sub(term_rows)..];
+                for (i, message) in messages.iter().enumerate() {
+                    let row = term_rows - messages.len() + i + 1;
+                    write!(stdout, "{}{}", termion::cursor::Goto(1, row.try_into().unwrap()), termion::clear::CurrentLine).unwrap();
+                    Via::render_message(&mut stdout, message);
+                }
+                write!(stdout, "{}", termion::cursor::Goto(editor.display_col().try_into().unwrap(), (editor.row() - visual_first_row + 1).try_into().unwrap())).unwrap();
+            }
             if self.mode == 0 {
                 write!(stdout, "{}", termion::cursor::BlinkingBlock).unwrap();
             } else {
@@ -294,12 +727,504 @@ impl Via {
             }
             stdout.flush().unwrap();
         }
+        editor.close_swap();
         write!(stdout, "{}{}{}{}", termion::clear::All, termion::cursor::Goto(1, 1), termion::cursor::Show, termion::cursor::BlinkingBlock).unwrap();
     }
     
+    /// Write `row` of `editor`, highlighting the columns in `selected` and the search
+    /// `matches` (all inclusive). A selected column past the end of the line is shown
+    /// as a highlighted space
+    fn render_line<W: Write>(stdout: &mut W, editor: &Editor, row: usize, selected: Option<(usize, usize)>, matches: &[(usize, usize)]) {
+        if selected.is_none() && matches.is_empty() {
+            write!(stdout, "{}", editor.text_line(row)).unwrap();
+            return
+        }
+        let (first, last) = selected.unwrap_or((0, 0));
+        let line = editor.line_text(row);
+        let mut col = 1;
+        for grapheme in line.graphemes(true) {
+            if first <= col && col <= last {
+                write!(stdout, "{}", termion::style::Invert).unwrap();
+            } else if matches.iter().any(|&(first, last)| first <= col && col <= last) {
+                write!(stdout, "{}{}", termion::color::Bg(termion::color::Yellow), termion::color::Fg(termion::color::Black)).unwrap();
+            }
+            write!(stdout, "{}{}", grapheme, termion::style::Reset).unwrap();
+            col += 1;
+        }
+        if first <= col && col <= last {
+            if first == col {
+                write!(stdout, "{}", termion::style::Invert).unwrap();
+            }
+            write!(stdout, " ").unwrap();
+        }
+        write!(stdout, "{}", termion::style::Reset).unwrap();
+    }
+
+    /// Write `message`, in colors telling how important it is
+    fn render_message<W: Write>(stdout: &mut W, message: &Message) {
+        match message.severity {
+            Severity::Info => write!(stdout, "{}", message.text).unwrap(),
+            Severity::Warning => write!(stdout, "{}{}{}", termion::color::Fg(termion::color::Red), message.text, termion::style::Reset).unwrap(),
+            Severity::Error => write!(stdout, "{}{}{}{}", termion::color::Bg(termion::color::Red), termion::color::Fg(termion::color::White),
+                message.text, termion::style::Reset).unwrap(),
+        }
+    }
+
+    /// Search for the `num`th match of `pattern` from the cursor of `editor` and move
+    /// to it. Adds to `messages` the pattern searched for, whether the search wrapped
+    /// around or why the pattern is invalid
+    fn search(editor: &mut Editor, messages: &mut Messages, pattern: &str, forward: bool, num: usize) {
+        let regex = match Regex::new(pattern) {
+            Ok(regex) => regex,
+            Err(err) => return messages.error(err),
+        };
+        match editor.search(&regex, forward, num) {
+            Some(((row, col), wrapped)) => {
+                editor.goto(row, col);
+                match (wrapped, forward) {
+                    (false, true) => messages.print(format!("/{}", pattern)),
+                    (false, false) => messages.print(format!("?{}", pattern)),
+                    (true, true) => messages.warn("search hit BOTTOM, continuing at TOP".to_string()),
+                    (true, false) => messages.warn("search hit TOP, continuing at BOTTOM".to_string()),
+                }
+            },
+            None => messages.error(format!("E486: Pattern not found: {}", pattern)),
+        }
+    }
+
+    /// Returns the start (inclusive) and end (exclusive) of the visual selection
+    /// between `anchor` and the cursor of `editor`, as (row, col). If `linewise`,
+    /// the selection includes all of the lines
+    fn visual_range(editor: &Editor, anchor: (usize, usize), linewise: bool) -> ((usize, usize), (usize, usize)) {
+        let cursor = (editor.row(), editor.col());
+        let (mut start, mut last) = (min(anchor, cursor), max(anchor, cursor));
+        if linewise {
+            start.1 = 1;
+            last.1 = usize::MAX;
+        }
+        let end = if last.1 <= editor.line_len(last.0) {
+            (last.0, last.1 + 1)
+        } else if last.0 < editor.num_lines() {
+            (last.0 + 1, 1)
+        } else {
+            (last.0, editor.line_len(last.0) + 1)
+        };
+        (start, end)
+    }
+
+    /// Returns the rows (first, last) and columns (first, last) of the visual block
+    /// between `anchor` and the cursor of `editor`, all inclusive
+    fn visual_block(editor: &Editor, anchor: (usize, usize)) -> ((usize, usize), (usize, usize)) {
+        let rows = (min(anchor.0, editor.row()), max(anchor.0, editor.row()));
+        (rows, (min(anchor.1, editor.col()), max(anchor.1, editor.col())))
+    }
+
+    /// Set the marks `<` and `>` to the start and end of the visual selection
+    fn set_visual_marks(editor: &mut Editor, anchor: (usize, usize)) {
+        let cursor = (editor.row(), editor.col());
+        let (start, end) = (min(anchor, cursor), max(anchor, cursor));
+        editor.set_mark('<', start.0, start.1);
+        editor.set_mark('>', end.0, end.1);
+    }
+
+    /// Returns the text of the columns `first_col` to `last_col` (inclusive) of
+    /// every row from `first_row` to `last_row`, with a newline between rows
+    fn block_text(editor: &Editor, first_row: usize, last_row: usize, first_col: usize, last_col: usize) -> String {
+        let rows: Vec<String> = (first_row..=last_row).map(|row| {
+            editor.line_text(row).graphemes(true).skip(first_col - 1).take(last_col + 1 - first_col).collect()
+        }).collect();
+        rows.join("\n")
+    }
+
+    /// Returns `text` with uppercase letters made lowercase and everything else made uppercase
+    fn toggle_case(text: &str) -> String {
+        text.chars().map(|c| {
+            if c.is_lowercase() {c.to_uppercase().collect::<String>()} else {c.to_lowercase().collect()}
+        }).collect()
+    }
+
+    /// Move to `col` of `row`. If the row is too short and `pad`, spaces are added
+    /// to it. Returns whether the cursor made it to `col`
+    fn goto_padded(editor: &mut Editor, row: usize, col: usize, pad: bool) -> bool {
+        editor.goto(row, col);
+        if editor.col() < col && pad {
+            editor.add_text(" ".repeat(col - editor.col()));
+        }
+        editor.col() == col
+    }
+
+    /// Insert the text typed on `first_row` since `col` on every row after it up to
+    /// `last_row`, at the same column. If a row is too short and `pad`, spaces are
+    /// added to it, otherwise it is skipped
+    fn repeat_block_insert(editor: &mut Editor, first_row: usize, last_row: usize, col: usize, pad: bool) {
+        if editor.row() != first_row || editor.col() <= col {
+            return
+        }
+        let cursor_col = editor.col();
+        let text = editor.text_range((first_row, col), (first_row, cursor_col));
+        for row in first_row + 1..=last_row {
+            if Via::goto_padded(editor, row, col, pad) {
+                editor.add_text(text.clone());
+            }
+        }
+        editor.goto(first_row, cursor_col);
+    }
+
+    /// Carry out `operator` (`d`, `x`, `c`, `y`, `~`, `I` or `A`) on the block of the
+    /// rows and columns (first, last) given, as a single undoable action. For `c`, `I`
+    /// and `A`, returns the block insert (first row, last row, column, pad) to start,
+    /// and the action goes on until insert mode ends
+    fn operate_block(editor: &mut Editor, register: &mut String, register_kind: &mut char, operator: char,
+            (first_row, last_row): (usize, usize), (first_col, last_col): (usize, usize)) -> Result<Option<(usize, usize, usize, bool)>, String> {
+        if operator == 'I' || operator == 'A' {
+            let col = if operator == 'I' {first_col} else {last_col + 1};
+            editor.begin_group();
+            Via::goto_padded(editor, first_row, col, operator == 'A');
+            return Ok(Some((first_row, last_row, col, operator == 'A')))
+        }
+        if operator != '~' {
+            *register = Via::block_text(editor, first_row, last_row, first_col, last_col);
+            *register_kind = VISUAL_BLOCK;
+            if operator == 'y' {
+                editor.goto(first_row, first_col);
+                return Ok(None)
+            }
+        }
+        editor.begin_group();
+        // Rows too short to reach the block are left as they are
+        let result = (first_row..=last_row).try_for_each(|row| {
+            let line_len = editor.line_len(row);
+            if first_col > line_len {
+                return Ok(())
+            }
+            let end = (row, min(last_col, line_len) + 1);
+            let text = editor.text_range((row, first_col), end);
+            editor.goto(row, first_col);
+            editor.delete_text(end.0, end.1)?;
+            if operator == '~' {
+                editor.add_text(Via::toggle_case(&text));
+            }
+            Ok(())
+        });
+        editor.goto(first_row, first_col);
+        if operator == 'c' && result.is_ok() {
+            return Ok(Some((first_row, last_row, first_col, false)))
+        }
+        editor.commit_group();
+        result.map(|_| None)
+    }
+
+    /// Put `text` (yanked as `kind`, see `Via::register_kind`) after the cursor
+    /// (or before it if not `after`), as a single undoable action
+    fn put(editor: &mut Editor, text: &str, kind: char, after: bool) {
+        let (row, mut col) = (editor.row(), editor.col());
+        if kind == 'V' {
+            if !after {
+                editor.goto(row, 1);
+                editor.add_text(text.to_string());
+            } else if row < editor.num_lines() {
+                editor.goto(row + 1, 1);
+                editor.add_text(text.to_string());
+            } else {
+                editor.goto_last_col();
+                editor.add_text(format!("\n{}", &text[..text.len() - 1]));
+            }
+            editor.goto(if after {row + 1} else {row}, 1);
+            return
+        }
+        if after && editor.line_len(row) > 0 {
+            col += 1;
+        }
+        if kind != VISUAL_BLOCK {
+            editor.goto(row, col);
+            editor.add_text(text.to_string());
+            editor.left(1);
+            return
+        }
+        editor.begin_group();
+        for (i, line) in text.split('\n').enumerate() {
+            editor.goto(row + i, 1);
+            if editor.row() < row + i {
+                editor.goto_last_col();
+                editor.add_text("\n".to_string());
+            }
+            Via::goto_padded(editor, row + i, col, true);
+            editor.add_text(line.to_string());
+        }
+        editor.commit_group();
+        editor.goto(row, col);
+    }
+
+    /// Indent (or unindent) the rows `first` to `last` (inclusive) by a tab,
+    /// as a single undoable action
+    fn shift_lines(editor: &mut Editor, first: usize, last: usize, indent: bool) {
+        editor.begin_group();
+        for row in first..=last {
+            editor.goto(row, 1);
+            let line = editor.line_text(row);
+            if indent {
+                if !line.is_empty() {
+                    editor.add_text("\t".to_string());
+                }
+            } else {
+                let len = if line.starts_with('\t') {1} else {line.chars().take(8).take_while(|c| *c == ' ').count()};
+                editor.delete_text(row, len + 1).unwrap();
+            }
+        }
+        editor.commit_group();
+        editor.goto(first, 1);
+    }
+
+    /// Returns the commands which can be run from the command line
+    fn commands() -> ex::Registry {
+        let mut commands = ex::Registry::new();
+        commands.add("w[rite]", ex::RANGE | ex::BANG, Via::write);
+        commands.add("wq", ex::RANGE | ex::BANG, |context, args| {
+            Via::write(context, args)?;
+            Via::quit(context, args.bang)
+        });
+        commands.add("x[it]", ex::RANGE | ex::BANG, |context, args| {
+            // Only written if there are changes (or it is written elsewhere)
+            if !context.editor.text_matches() || !args.arg.is_empty() || args.range {
+                Via::write(context, args)?;
+            }
+            Via::quit(context, args.bang)
+        });
+        commands.add("sav[eas]", ex::BANG, |context, args| {
+            if args.arg.is_empty() {
+                return Err("E471: Argument required".to_string())
+            } else if !args.bang && args.arg != context.editor.file_path() && Path::new(args.arg).exists() {
+                return Err(FILE_EXISTS.to_string())
+            }
+            context.editor.update_file_path(args.arg.to_string());
+            Via::write(context, &ex::Args {range: false, arg: "", ..*args})
+        });
+        commands.add("q[uit]", ex::BANG, |context, args| Via::quit(context, args.bang));
+        commands.add("u[ndo]", 0, |context, _| context.editor.undo());
+        commands.add("red[o]", 0, |context, _| context.editor.redo());
+        commands.add("ea[rlier]", 0, |context, args| Via::time_travel(context.editor, args.arg, true));
+        commands.add("lat[er]", 0, |context, args| Via::time_travel(context.editor, args.arg, false));
+        commands.add("comp[act]", 0, |context, _| {
+            context.editor.compact();
+            Ok(())
+        });
+        commands.add("d[elete]", ex::RANGE, |context, args| {
+            let range = Range {start: (args.rows.0, 1), end: (args.rows.1, 1), linewise: true};
+            Via::operate(context.editor, context.register, context.register_kind, 'd', range);
+            Ok(())
+        });
+        commands.add("y[ank]", ex::RANGE, |context, args| {
+            let cursor = (context.editor.row(), context.editor.col());
+            let range = Range {start: (args.rows.0, 1), end: (args.rows.1, 1), linewise: true};
+            Via::operate(context.editor, context.register, context.register_kind, 'y', range);
+            context.editor.goto(cursor.0, cursor.1);
+            Ok(())
+        });
+        commands.add("p[rint]", ex::RANGE, |context, args| {
+            for row in args.rows.0..=args.rows.1 {
+                context.messages.print(context.editor.line_text(row));
+            }
+            context.editor.goto(args.rows.1, 1);
+            Ok(())
+        });
+        commands.add("e[dit]", ex::BANG, |context, args| {
+            if !args.bang && !context.editor.text_matches() {
+                return Err(NOT_WRITTEN.to_string())
+            }
+            let file_path = if args.arg.is_empty() {context.editor.file_path().to_string()} else {args.arg.to_string()};
+            let backup = context.editor.backup();
+            context.editor.close_swap();
+            *context.editor = Editor::new(file_path);
+            context.editor.set_backup(backup);
+            match context.editor.find_swap() {
+                Some(pid) => Err(format!("E325: ATTENTION: Found a swap file by via process {}, editing without one. Recover it with via -r", pid)),
+                None => context.editor.open_swap(),
+            }
+        });
+        commands.add("mes[sages]", 0, |context, args| {
+            match args.arg {
+                "" => context.messages.show_history(),
+                "clear" => context.messages.clear_history(),
+                _ => return Err(format!("E474: Invalid argument: {}", args.arg)),
+            }
+            Ok(())
+        });
+        commands.add("vi[sual]", 0, |context, _| {
+            context.mode = Some(0);
+            Ok(())
+        });
+        substitute::register(&mut commands);
+        commands
+    }
+
+    /// Carry out `:write`: write the rows in `args` (or all of them) to the file, or to the
+    /// path given, after what is in it with `>> path`, or to the input of a shell command
+    /// with `!command`. Writing an unnamed text to a path names it
+    fn write(context: &mut ex::Context, args: &ex::Args) -> Result<(), String> {
+        let editor = &mut *context.editor;
+        if let Some(command) = args.arg.strip_prefix('!') {
+            let (first, last) = if args.range {args.rows} else {(1, editor.last_line())};
+            let text = editor.rows_text(first, last);
+            return Via::pipe(context.messages, command, &text)
+        }
+        let (append, path) = match args.arg.strip_prefix(">>") {
+            Some(path) => (true, path.trim_start()),
+            None => (false, args.arg),
+        };
+        let path = if path.is_empty() {editor.file_path().to_string()} else {path.to_string()};
+        // All of the text is written without loading the rest of the file, so the
+        // last row is only needed for a range
+        let rows = Some(args.rows).filter(|&rows| args.range && rows != (1, editor.last_line()));
+        let whole = rows.is_none();
+        if path.is_empty() {
+            return Err("E32: No file name".to_string())
+        } else if !append && path != editor.file_path() {
+            if !args.bang && Path::new(&path).exists() {
+                return Err(FILE_EXISTS.to_string())
+            } else if editor.file_path().is_empty() && whole {
+                editor.update_file_path(path.clone());
+            }
+        }
+        let len = if append || path != editor.file_path() {
+            editor.write_rows(&path, rows, append)?
+        } else if !whole && !args.bang {
+            return Err("E140: Use ! to write partial buffer".to_string())
+        } else if !whole {
+            editor.write_rows(&path, rows, false)?
+        } else {
+            let len = editor.write_file()?;
+            if let Err(err) = editor.write_undo_file() {
+                context.messages.warn(format!("Unable to write undo file: {}", err));
+            }
+            len
+        };
+        let plural = |n: usize| if n == 1 {""} else {"s"};
+        let lines = match rows {
+            Some((first, last)) => last - first + 1,
+            None => editor.count_lines(),
+        };
+        context.messages.info(format!("\"{}\" {} line{}, {} byte{} {}", path, lines, plural(lines), len, plural(len),
+            if append {"appended"} else {"written"}));
+        Ok(())
+    }
+
+    /// Write `text` to the input of the shell command `command`, showing what it outputs
+    fn pipe(messages: &mut Messages, command: &str, text: &str) -> Result<(), String> {
+        let mut child = process::Command::new("sh").arg("-c").arg(command)
+            .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+            .spawn().map_err(|e| e.to_string())?;
+        // Written from another thread, so the command can't get stuck waiting for its
+        // output to be read while we wait for it to read its input
+        let mut stdin = child.stdin.take().unwrap();
+        let text = text.to_string();
+        let writer = thread::spawn(move || stdin.write_all(text.as_bytes()));
+        let output = child.wait_with_output().map_err(|e| e.to_string())?;
+        // The command doesn't have to read all of its input
+        let _ = writer.join();
+        for output in &[output.stdout, output.stderr] {
+            for line in String::from_utf8_lossy(output).lines() {
+                messages.print(line.to_string());
+            }
+        }
+        match output.status.code() {
+            Some(0) => Ok(()),
+            Some(code) => Err(format!("shell returned {}", code)),
+            None => Err("shell was killed".to_string()),
+        }
+    }
+
+    /// Quit, unless there are changes which haven't been written and not `force`d with `!`
+    fn quit(context: &mut ex::Context, force: bool) -> Result<(), String> {
+        if !force && !context.editor.text_matches() {
+            return Err(NOT_WRITTEN.to_string())
+        }
+        context.quit = true;
+        Ok(())
+    }
+
+    /// Apply `operator` (`d`, `c`, `y`, `~`, `>` or `<`) to `range` of `editor`. Text deleted
+    /// or yanked is put in `register`. Returns whether to start insert mode, for `c`, in which
+    /// case the change is left open so that the text typed is part of the same undoable action
+    fn operate(editor: &mut Editor, register: &mut String, register_kind: &mut char, operator: char, range: Range) -> bool {
+        let (first_row, last_row) = (range.start.0, range.end.0);
+        let (start, end) = if !range.linewise {
+            (range.start, range.end)
+        } else if editor.has_line(last_row + 1) {
+            ((first_row, 1), (last_row + 1, 1))
+        } else {
+            ((first_row, 1), (last_row, editor.line_len(last_row) + 1))
+        };
+        // Deleting the last lines also deletes the newline before them
+        let includes_last_line = range.linewise && end.1 != 1;
+        match operator {
+            'd' | 'c' | 'y' => {
+                *register = editor.text_range(start, end);
+                *register_kind = if range.linewise {'V'} else {'v'};
+                if includes_last_line {
+                    register.push('\n');
+                }
+                if operator == 'c' {
+                    editor.begin_group();
+                    editor.goto(start.0, start.1);
+                    let end = if range.linewise {(last_row, editor.line_len(last_row) + 1)} else {end};
+                    editor.delete_text(end.0, end.1).unwrap();
+                    return true
+                } else if operator == 'd' {
+                    if includes_last_line && first_row > 1 {
+                        editor.goto(first_row - 1, editor.line_len(first_row - 1) + 1);
+                    } else {
+                        editor.goto(start.0, start.1);
+                    }
+                    editor.delete_text(end.0, end.1).unwrap();
+                    if range.linewise {
+                        editor.goto(min(first_row, editor.num_lines()), 1);
+                    } else if editor.col() > 1 && editor.col() - 1 == editor.line_len(editor.row()) {
+                        editor.left(1);
+                    }
+                } else {
+                    editor.goto(range.start.0, range.start.1);
+                }
+            },
+            '~' => {
+                let text = Via::toggle_case(&editor.text_range(start, end));
+                editor.begin_group();
+                editor.goto(start.0, start.1);
+                editor.delete_text(end.0, end.1).unwrap();
+                editor.add_text(text);
+                editor.commit_group();
+                editor.goto(start.0, start.1);
+            },
+            '>' | '<' => Via::shift_lines(editor, first_row, last_row, operator == '>'),
+            _ => {},
+        }
+        false
+    }
+
+    /// Move through the undo tree for `:earlier` and `:later`. `arg` is either a
+    /// number of changes or a duration like `10s`, `5m`, `1h` or `2d`
+    fn time_travel(editor: &mut Editor, arg: &str, earlier: bool) -> Result<(), String> {
+        let (num, unit) = arg.split_at(arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len()));
+        let num = if num.is_empty() {1} else {num.parse::<u64>().map_err(|e| e.to_string())?};
+        let secs = match unit {
+            "" => {
+                let steps = num.try_into().map_err(|_| "E475: Invalid argument")?;
+                return if earlier {editor.earlier(steps)} else {editor.later(steps)};
+            },
+            "s" => Some(num),
+            "m" => num.checked_mul(60),
+            "h" => num.checked_mul(60 * 60),
+            "d" => num.checked_mul(60 * 60 * 24),
+            _ => return Err(format!("Invalid argument: {}", arg)),
+        }.ok_or("E475: Invalid argument")?;
+        if earlier {
+            editor.earlier_time(Duration::from_secs(secs))
+        } else {
+            editor.later_time(Duration::from_secs(secs))
+        }
+    }
+
     /// Process command line options and returns the files to edit and ViaOptions
-    // fn process_args(&mut self, args: &Vec<String>) -> Result<(Vec<String>, ViaOptions), &str> {
-    pub(crate) fn process_args(args: Vec<String>) -> (Vec<String>, ViaOptions) {
+    pub(crate) fn process_args(args: Vec<String>) -> Result<(Vec<String>, ViaOptions), String> {
         let mut flags = Vec::new();
         let mut file_paths = Vec::new();
         // let default_files = vec!["target/debug/via", "via"];
@@ -316,11 +1241,15 @@ impl Via {
         for option in flags {
             if option == "-v" {
                 via_options.verboseness += 1;
+            } else if option == "-b" {
+                via_options.backup = true;
+            } else if option == "-r" {
+                via_options.recover = true;
             } else {
-                panic!("Unknown flag {}", option);
+                return Err(format!("Unknown flag {}", option));
             }
         }
-        (file_paths, via_options)
+        Ok((file_paths, via_options))
     }
 }
 
@@ -328,11 +1257,165 @@ impl Via {
 pub(crate) struct ViaOptions {
     /// Level of verboseness
     verboseness: usize,
+    /// Whether to keep a copy of files as they were before they are written, as `file~`
+    backup: bool,
+    /// Whether to recover the changes in the swap files of the files, with `-r`
+    recover: bool,
 }
 
 impl ViaOptions {
     /// Return default options
     pub(crate) fn new() -> ViaOptions {
-        ViaOptions {verboseness: 1}
+        ViaOptions {verboseness: 1, backup: false, recover: false}
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an editor with `text`, with the cursor at the start
+    fn editor_with(text: &str) -> Editor {
+        let mut editor = Editor::new(String::new());
+        editor.add_text(text.to_string());
+        editor.goto(1, 1);
+        editor
+    }
+
+    #[test]
+    fn visual_linewise() {
+        let text = "ab\ncd\nef";
+        let mut editor = editor_with(text);
+        let (mut register, mut register_kind) = (String::new(), 'v');
+        // `Vjd` from the second column of the first row
+        let range = Range {start: (1, 2), end: (2, 1), linewise: true};
+        assert!(!Via::operate(&mut editor, &mut register, &mut register_kind, 'd', range));
+        assert_eq!(editor.text(), "ef");
+        assert_eq!((register.as_str(), register_kind), ("ab\ncd\n", 'V'));
+        editor.undo().unwrap();
+        assert_eq!(editor.text(), text);
+
+        editor.goto(1, 1);
+        Via::put(&mut editor, &register, register_kind, true);
+        assert_eq!(editor.text(), "ab\nab\ncd\ncd\nef");
+        assert_eq!((editor.row(), editor.col()), (2, 1));
+        editor.undo().unwrap();
+        assert_eq!(editor.text(), text);
+
+        // The last lines, from the second one
+        let range = Range {start: (2, 1), end: (3, 1), linewise: true};
+        Via::operate(&mut editor, &mut register, &mut register_kind, '~', range);
+        assert_eq!(editor.text(), "ab\nCD\nEF");
+        editor.undo().unwrap();
+        assert_eq!(editor.text(), text);
+        Via::operate(&mut editor, &mut register, &mut register_kind, 'd', range);
+        assert_eq!(editor.text(), "ab");
+        assert_eq!(register, "cd\nef\n");
+    }
+
+    #[test]
+    fn block_text() {
+        let mut editor = editor_with("abcd\nef\n\nghij");
+        assert_eq!(Via::block_text(&editor, 1, 4, 2, 3), "bc\nf\n\nhi");
+        assert_eq!(Via::block_text(&editor, 2, 2, 1, 1), "e");
+
+        assert!(Via::goto_padded(&mut editor, 1, 3, false));
+        assert_eq!((editor.row(), editor.col()), (1, 3));
+        assert!(!Via::goto_padded(&mut editor, 3, 3, false));
+        assert_eq!(editor.text(), "abcd\nef\n\nghij");
+        assert!(Via::goto_padded(&mut editor, 3, 3, true));
+        assert_eq!(editor.text(), "abcd\nef\n  \nghij");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn block_delete() {
+        let text = "abcd\nef\n\nghij";
+        let mut editor = editor_with(text);
+        let (mut register, mut register_kind) = (String::new(), 'v');
+        assert_eq!(Via::operate_block(&mut editor, &mut register, &mut register_kind, 'd', (1, 4), (2, 3)), Ok(None));
+        assert_eq!(editor.text(), "ad\ne\n\ngj");
+        assert_eq!((register.as_str(), register_kind), ("bc\nf\n\nhi", VISUAL_BLOCK));
+        assert_eq!((editor.row(), editor.col()), (1, 2));
+        editor.undo().unwrap();
+        assert_eq!(editor.text(), text);
+
+        assert_eq!(Via::operate_block(&mut editor, &mut register, &mut register_kind, '~', (1, 4), (2, 3)), Ok(None));
+        assert_eq!(editor.text(), "aBCd\neF\n\ngHIj");
+        editor.undo().unwrap();
+        assert_eq!(editor.text(), text);
+
+        register.clear();
+        assert_eq!(Via::operate_block(&mut editor, &mut register, &mut register_kind, 'y', (2, 4), (1, 1)), Ok(None));
+        assert_eq!(register, "e\n\ng");
+        assert_eq!(editor.text(), text);
+    }
+
+    #[test]
+    fn block_change() {
+        let text = "abcd\nef\n\nghij";
+        let mut editor = editor_with(text);
+        let (mut register, mut register_kind) = (String::new(), 'v');
+        let insert = Via::operate_block(&mut editor, &mut register, &mut register_kind, 'c', (1, 4), (2, 3)).unwrap();
+        assert_eq!(insert, Some((1, 4, 2, false)));
+        assert_eq!(editor.text(), "ad\ne\n\ngj");
+        editor.add_text("XY".to_string());
+        let (first_row, last_row, col, pad) = insert.unwrap();
+        Via::repeat_block_insert(&mut editor, first_row, last_row, col, pad);
+        editor.commit_group();
+        // The empty row is too short to be changed
+        assert_eq!(editor.text(), "aXYd\neXY\n\ngXYj");
+        assert_eq!((editor.row(), editor.col()), (1, 4));
+        editor.undo().unwrap();
+        assert_eq!(editor.text(), text);
+    }
+
+    #[test]
+    fn block_insert() {
+        let text = "abcd\nef\n\nghij";
+        let mut editor = editor_with(text);
+        let (mut register, mut register_kind) = (String::new(), 'v');
+        let insert = Via::operate_block(&mut editor, &mut register, &mut register_kind, 'I', (1, 4), (2, 3)).unwrap();
+        assert_eq!(insert, Some((1, 4, 2, false)));
+        editor.add_text("X".to_string());
+        let (first_row, last_row, col, pad) = insert.unwrap();
+        Via::repeat_block_insert(&mut editor, first_row, last_row, col, pad);
+        editor.commit_group();
+        assert_eq!(editor.text(), "aXbcd\neXf\n\ngXhij");
+        editor.undo().unwrap();
+        assert_eq!(editor.text(), text);
+
+        // Short rows are padded to append after the block
+        let insert = Via::operate_block(&mut editor, &mut register, &mut register_kind, 'A', (1, 4), (2, 3)).unwrap();
+        assert_eq!(insert, Some((1, 4, 4, true)));
+        editor.add_text("X".to_string());
+        let (first_row, last_row, col, pad) = insert.unwrap();
+        Via::repeat_block_insert(&mut editor, first_row, last_row, col, pad);
+        editor.commit_group();
+        assert_eq!(editor.text(), "abcXd\nef X\n   X\nghiXj");
+        editor.undo().unwrap();
+        assert_eq!(editor.text(), text);
+
+        // Nothing was typed
+        Via::operate_block(&mut editor, &mut register, &mut register_kind, 'I', (1, 4), (2, 3)).unwrap();
+        Via::repeat_block_insert(&mut editor, first_row, last_row, 2, false);
+        editor.commit_group();
+        assert_eq!(editor.text(), text);
+    }
+
+    #[test]
+    fn block_put() {
+        let text = "abc\nd";
+        let mut editor = editor_with(text);
+        Via::put(&mut editor, "X\nY\nZ", VISUAL_BLOCK, true);
+        // Short rows are padded, and rows are added for the rest of the block
+        assert_eq!(editor.text(), "aXbc\ndY\n Z");
+        assert_eq!((editor.row(), editor.col()), (1, 2));
+        editor.undo().unwrap();
+        assert_eq!(editor.text(), text);
+
+        editor.goto(2, 1);
+        Via::put(&mut editor, "XY\nZ", VISUAL_BLOCK, false);
+        assert_eq!(editor.text(), "abc\nXYd\nZ");
+        editor.undo().unwrap();
+        assert_eq!(editor.text(), text);
+    }
+}